@@ -0,0 +1,31 @@
+use anyhow::{anyhow, Result};
+use std::io::ErrorKind;
+use std::process::{Command, Stdio};
+
+/// Runs the configured TTS command with `text` as its last argument and
+/// waits for it to finish. The command is split on whitespace; no shell
+/// quoting is applied.
+pub fn speak(command: &str, text: &str) -> Result<()> {
+    let mut parts = command.split_whitespace();
+    let program = parts
+        .next()
+        .ok_or_else(|| anyhow!("announce_command is empty"))?;
+    let status = Command::new(program)
+        .args(parts)
+        .arg(text)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_err(|err| {
+            if err.kind() == ErrorKind::NotFound {
+                anyhow!("TTS command '{}' not found", program)
+            } else {
+                anyhow!(err)
+            }
+        })?;
+    if !status.success() {
+        return Err(anyhow!("TTS command '{}' exited with {}", program, status));
+    }
+    Ok(())
+}
@@ -1,11 +1,12 @@
 use anyhow::{anyhow, Context, Result};
 use rpassword::read_password;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::{self, OpenOptions};
 use std::io::{self, Write};
 use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 const DEFAULT_API_VERSION: &str = "1.16.1";
 const DEFAULT_CLIENT_NAME: &str = "simplay";
@@ -13,6 +14,34 @@ const DEFAULT_ENDPOINT_SUFFIX: &str = "view";
 const DEFAULT_MAX_SHUFFLE: usize = 0;
 const DEFAULT_VOLUME_STEP: u8 = 5;
 const DEFAULT_END_GRACE_MS: u64 = 500;
+const DEFAULT_MIN_TRACK_SECS: u32 = 0;
+const DEFAULT_LOVE_RATING: u8 = 5;
+const DEFAULT_RANDOM_SONGS_RETRIES: u32 = 2;
+const DEFAULT_MAX_CONSECUTIVE_ERRORS: u32 = 5;
+const DEFAULT_VIDEO: &str = "none";
+const DEFAULT_RESUME_ALBUMS: &str = "prompt";
+const DEFAULT_PREV_RESTART_THRESHOLD_SECS: u32 = 3;
+const DEFAULT_ANNOUNCE_COMMAND: &str = "spd-say --wait";
+const DEFAULT_INSTANT_MIX_SIZE: usize = 50;
+const DEFAULT_INSTANT_MIX_SIMILAR: u32 = 60;
+const DEFAULT_INSTANT_MIX_ARTIST: u32 = 20;
+const DEFAULT_INSTANT_MIX_GENRE: u32 = 20;
+const DEFAULT_PLAYBACK_ENDPOINT: &str = "stream";
+const DEFAULT_SOFT_PAUSE_WINDOW_SECS: u32 = 10;
+const DEFAULT_LOCAL_PATH_TEMPLATE: &str = "{path}";
+const DEFAULT_LOG_MAX_BYTES: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_KEEP_FILES: u32 = 3;
+const DEFAULT_TRANSITION: &str = "none";
+const DEFAULT_TRANSITION_GAP_SECS: u32 = 2;
+/// What a kiosk allows unless `kiosk_commands` says otherwise: see (and
+/// follow) what's playing, start a shuffle, skip, pause and nudge the volume.
+const DEFAULT_KIOSK_COMMANDS: &[&str] = &[
+    "status", "health", "songinfo", "shuffle", "fastforward", "pause", "play", "volumeup",
+    "volumedown", "subscribe",
+];
+const DEFAULT_SUBSCRIBE_HEARTBEAT_SECS: u32 = 15;
+const DEFAULT_MPV_STARTUP_TIMEOUT_MS: u64 = 2000;
+const DEFAULT_SHUFFLE: &str = "library";
 
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -26,9 +55,60 @@ pub struct Config {
     pub max_shuffle: usize,
     pub volume_step: u8,
     pub end_grace_ms: u64,
+    pub min_track_secs: u32,
+    pub shuffle_exclude_genres: Vec<String>,
+    pub shuffle_exclude_artists: Vec<String>,
+    pub shuffle_exclude_albums: Vec<String>,
+    pub love_rating: u8,
+    pub resume_on_start: bool,
+    pub redact_stream_url: bool,
+    pub random_songs_retries: u32,
+    pub music_folder: Option<String>,
+    pub max_consecutive_errors: u32,
+    pub video: String,
+    pub library_cache: bool,
+    pub filter_explicit: bool,
+    pub resume_albums: String,
+    pub prev_restart_threshold_secs: u32,
+    pub extra_params: BTreeMap<String, String>,
+    pub listenbrainz_token: Option<String>,
+    pub initial_volume: Option<u8>,
+    pub announce: bool,
+    pub announce_command: String,
+    pub auto_dedupe: bool,
+    pub mpv_config_dir: Option<String>,
+    pub max_queue_len: usize,
+    pub usage_stats: bool,
+    pub instant_mix_size: usize,
+    pub instant_mix_similar: u32,
+    pub instant_mix_artist: u32,
+    pub instant_mix_genre: u32,
+    pub device_name: Option<String>,
+    pub volume_max: u8,
+    pub volume_min: u8,
+    pub advance_on_stop: bool,
+    pub autostart: bool,
+    pub playback_endpoint: String,
+    pub soft_pause_window_secs: u32,
+    pub observer_socket: bool,
+    pub local_library_root: Option<String>,
+    pub local_path_template: String,
+    pub log_file: Option<String>,
+    pub log_max_bytes: u64,
+    pub log_keep_files: u32,
+    pub transition: String,
+    pub transition_gap_secs: u32,
+    pub reconnect_on_wake: bool,
+    pub kiosk_mode: bool,
+    pub kiosk_commands: Vec<String>,
+    pub auto_like_after_plays: u32,
+    pub subscribe_heartbeat_secs: u32,
+    pub mpv_startup_timeout_ms: u64,
+    pub scrobble_on_skip: bool,
+    pub default_shuffle: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct ConfigFile {
     server_url: Option<String>,
     username: Option<String>,
@@ -40,37 +120,92 @@ struct ConfigFile {
     max_shuffle: Option<usize>,
     volume_step: Option<u8>,
     end_grace_ms: Option<u64>,
+    min_track_secs: Option<u32>,
+    shuffle_exclude_genres: Option<Vec<String>>,
+    shuffle_exclude_artists: Option<Vec<String>>,
+    shuffle_exclude_albums: Option<Vec<String>>,
+    love_rating: Option<u8>,
+    resume_on_start: Option<bool>,
+    redact_stream_url: Option<bool>,
+    random_songs_retries: Option<u32>,
+    music_folder: Option<String>,
+    max_consecutive_errors: Option<u32>,
+    video: Option<String>,
+    library_cache: Option<bool>,
+    filter_explicit: Option<bool>,
+    resume_albums: Option<String>,
+    prev_restart_threshold_secs: Option<u32>,
+    extra_params: Option<BTreeMap<String, String>>,
+    listenbrainz_token: Option<String>,
+    initial_volume: Option<u8>,
+    announce: Option<bool>,
+    announce_command: Option<String>,
+    auto_dedupe: Option<bool>,
+    mpv_config_dir: Option<String>,
+    max_queue_len: Option<usize>,
+    usage_stats: Option<bool>,
+    instant_mix_size: Option<usize>,
+    instant_mix_similar: Option<u32>,
+    instant_mix_artist: Option<u32>,
+    instant_mix_genre: Option<u32>,
+    device_name: Option<String>,
+    volume_max: Option<u8>,
+    volume_min: Option<u8>,
+    advance_on_stop: Option<bool>,
+    autostart: Option<bool>,
+    playback_endpoint: Option<String>,
+    soft_pause_window_secs: Option<u32>,
+    observer_socket: Option<bool>,
+    local_library_root: Option<String>,
+    local_path_template: Option<String>,
+    log_file: Option<String>,
+    log_max_bytes: Option<u64>,
+    log_keep_files: Option<u32>,
+    transition: Option<String>,
+    transition_gap_secs: Option<u32>,
+    reconnect_on_wake: Option<bool>,
+    kiosk_mode: Option<bool>,
+    kiosk_commands: Option<Vec<String>>,
+    auto_like_after_plays: Option<u32>,
+    subscribe_heartbeat_secs: Option<u32>,
+    mpv_startup_timeout_ms: Option<u64>,
+    scrobble_on_skip: Option<bool>,
+    default_shuffle: Option<String>,
 }
 
 impl Config {
+    /// Loads the config with `SIMPLAY_*` environment variables applied over
+    /// the file, prompting only for required values neither provides.
+    /// Prompted values are saved; values from the environment never are.
     pub fn load_or_prompt_required() -> Result<Self> {
         let mut file = load_config_file()?.unwrap_or_default();
+        let effective = apply_env(file.clone())?;
         let mut updated = false;
 
-        if file.server_url.as_deref().unwrap_or("").is_empty() {
+        if effective.server_url.as_deref().unwrap_or("").is_empty() {
             file.server_url = Some(prompt_required("Navidrome server URL")?);
             updated = true;
         }
 
-        if file.username.as_deref().unwrap_or("").is_empty() {
+        if effective.username.as_deref().unwrap_or("").is_empty() {
             file.username = Some(prompt_required("Username")?);
             updated = true;
         }
 
-        if file.password.as_deref().unwrap_or("").is_empty() {
+        if effective.password.as_deref().unwrap_or("").is_empty() {
             file.password = Some(prompt_password("Password", None)?);
             updated = true;
         }
 
-        let config = Config::from_file(file);
         if updated {
-            config.save()?;
+            Config::from_file(file.clone()).save()?;
         }
-        Ok(config)
+        Ok(Config::from_file(apply_env(file)?))
     }
 
     pub fn configure() -> Result<Self> {
         let file = load_config_file()?.unwrap_or_default();
+        let defaults = Config::from_file(file.clone());
 
         let server_url = prompt_with_default(
             "Navidrome server URL",
@@ -111,6 +246,10 @@ impl Config {
             "End-of-track grace ms",
             file.end_grace_ms.unwrap_or(DEFAULT_END_GRACE_MS),
         )?;
+        let min_track_secs = prompt_u32(
+            "Skip shuffle tracks shorter than secs (0 = off)",
+            file.min_track_secs.unwrap_or(DEFAULT_MIN_TRACK_SECS),
+        )?;
 
         let config = Config {
             server_url: normalize_url(&server_url),
@@ -123,6 +262,8 @@ impl Config {
             max_shuffle,
             volume_step,
             end_grace_ms,
+            min_track_secs,
+            ..defaults
         };
         config.save()?;
         Ok(config)
@@ -134,18 +275,7 @@ impl Config {
             fs::create_dir_all(parent)?;
         }
 
-        let file = ConfigFile {
-            server_url: Some(self.server_url.clone()),
-            username: Some(self.username.clone()),
-            password: Some(self.password.clone()),
-            api_version: Some(self.api_version.clone()),
-            client_name: Some(self.client_name.clone()),
-            endpoint_suffix: Some(self.endpoint_suffix.clone()),
-            tls_verify: Some(self.tls_verify),
-            max_shuffle: Some(self.max_shuffle),
-            volume_step: Some(self.volume_step),
-            end_grace_ms: Some(self.end_grace_ms),
-        };
+        let file = self.to_file();
 
         let encoded = toml::to_string_pretty(&file)?;
         let mut handle = OpenOptions::new()
@@ -160,7 +290,35 @@ impl Config {
         Ok(())
     }
 
+    /// Loads the config without prompting, for read-only inspection.
+    pub fn load_existing() -> Result<(Self, PathBuf)> {
+        let path = config_path()?;
+        let file = match load_config_file()? {
+            Some(file) => file,
+            None if has_env_config() => ConfigFile::default(),
+            None => return Err(anyhow!("No config at {}, run simplay --configure", path.display())),
+        };
+        Ok((Config::from_file(apply_env(file)?), path))
+    }
+
+    /// The effective config as TOML with the password redacted.
+    pub fn redacted(&self) -> Result<String> {
+        let mut file = self.to_file();
+        if file.password.as_deref().map(|p| !p.is_empty()).unwrap_or(false) {
+            file.password = Some("****".to_string());
+        }
+        if file.listenbrainz_token.is_some() {
+            file.listenbrainz_token = Some("****".to_string());
+        }
+        Ok(toml::to_string_pretty(&file)?)
+    }
+
     pub fn socket_path() -> Result<PathBuf> {
+        if let Ok(path) = env::var("SIMPLAY_SOCKET") {
+            if !path.trim().is_empty() {
+                return Config::custom_socket_path(PathBuf::from(path.trim()));
+            }
+        }
         let base = match runtime_dir() {
             Some(dir) => dir,
             None => config_dir()?,
@@ -170,6 +328,29 @@ impl Config {
         Ok(dir.join("simplay.sock"))
     }
 
+    pub fn custom_socket_path(path: PathBuf) -> Result<PathBuf> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => return Ok(path),
+        };
+        if !parent.is_dir() {
+            return Err(anyhow!(
+                "Socket directory {} does not exist",
+                parent.display()
+            ));
+        }
+        Ok(path)
+    }
+
+    /// The read-only observer socket, next to the control socket.
+    pub fn observer_socket_path(socket_path: &Path) -> PathBuf {
+        let stem = socket_path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "simplay".to_string());
+        socket_path.with_file_name(format!("{}-observer.sock", stem))
+    }
+
     pub fn mpv_socket_path() -> Result<PathBuf> {
         let base = match runtime_dir() {
             Some(dir) => dir,
@@ -180,6 +361,78 @@ impl Config {
         Ok(dir.join("simplay-mpv.sock"))
     }
 
+    pub fn session_path() -> Result<PathBuf> {
+        let dir = config_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("session.json"))
+    }
+
+    /// The `c` parameter sent to the server: `client_name`, plus
+    /// `@device_name` when one is set.
+    pub fn client_id(&self) -> String {
+        match self.device_name.as_deref() {
+            Some(device) => format!("{}@{}", self.client_name, device.trim()),
+            None => self.client_name.clone(),
+        }
+    }
+
+    /// Limits `volume` to `volume_min..=volume_max`; every volume change
+    /// the daemon makes goes through this.
+    pub fn clamp_volume(&self, volume: i32) -> i32 {
+        volume.clamp(self.volume_min as i32, self.volume_max as i32)
+    }
+
+    pub fn device_volumes_path() -> Result<PathBuf> {
+        let dir = config_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("device_volumes.json"))
+    }
+
+    pub fn completions_path() -> Result<PathBuf> {
+        let dir = config_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("completions.json"))
+    }
+
+    pub fn usage_stats_path() -> Result<PathBuf> {
+        let dir = config_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("usage_stats.json"))
+    }
+
+    pub fn album_positions_path() -> Result<PathBuf> {
+        let dir = config_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("album_positions.json"))
+    }
+
+    /// Whether positions within albums started by `playalbum` are kept.
+    pub fn resume_albums_enabled(&self) -> bool {
+        !self.resume_albums.trim().eq_ignore_ascii_case("off")
+    }
+
+    /// Whether `playalbum` continues from the saved position without asking.
+    /// Whether songs are played from the `download` endpoint (the original
+    /// file) instead of `stream`.
+    pub fn download_playback(&self) -> bool {
+        self.playback_endpoint.trim().eq_ignore_ascii_case("download")
+    }
+
+    pub fn resume_albums_auto(&self) -> bool {
+        self.resume_albums.trim().eq_ignore_ascii_case("auto")
+    }
+
+    /// Whether mpv may open a window for streams that contain video.
+    pub fn video_enabled(&self) -> bool {
+        self.video.trim().eq_ignore_ascii_case("auto")
+    }
+
+    pub fn library_cache_path() -> Result<PathBuf> {
+        let dir = cache_dir()?.join("simplay");
+        fs::create_dir_all(&dir)?;
+        Ok(dir.join("library.json"))
+    }
+
     pub fn max_shuffle(&self) -> usize {
         self.max_shuffle
     }
@@ -191,9 +444,199 @@ impl Config {
     pub fn end_grace_ms(&self) -> u64 {
         self.end_grace_ms
     }
+
+    pub fn min_track_secs(&self) -> u32 {
+        self.min_track_secs
+    }
+
+    pub fn love_rating(&self) -> u8 {
+        self.love_rating
+    }
+
+    pub fn resume_on_start(&self) -> bool {
+        self.resume_on_start
+    }
+
+    pub fn redact_stream_url(&self) -> bool {
+        self.redact_stream_url
+    }
+
+    pub fn max_consecutive_errors(&self) -> u32 {
+        self.max_consecutive_errors
+    }
+
+    pub fn library_cache(&self) -> bool {
+        self.library_cache
+    }
+
+    pub fn filter_explicit(&self) -> bool {
+        self.filter_explicit
+    }
+
+    pub fn prev_restart_threshold_secs(&self) -> u32 {
+        self.prev_restart_threshold_secs
+    }
+
+    pub fn announce(&self) -> bool {
+        self.announce
+    }
+
+    pub fn auto_dedupe(&self) -> bool {
+        self.auto_dedupe
+    }
+
+    pub fn max_queue_len(&self) -> usize {
+        self.max_queue_len
+    }
+
+    pub fn usage_stats(&self) -> bool {
+        self.usage_stats
+    }
+
+    pub fn instant_mix_size(&self) -> usize {
+        self.instant_mix_size
+    }
+
+    pub fn instant_mix_similar(&self) -> u32 {
+        self.instant_mix_similar
+    }
+
+    pub fn instant_mix_artist(&self) -> u32 {
+        self.instant_mix_artist
+    }
+
+    pub fn instant_mix_genre(&self) -> u32 {
+        self.instant_mix_genre
+    }
+
+    pub fn volume_max(&self) -> u8 {
+        self.volume_max
+    }
+
+    pub fn volume_min(&self) -> u8 {
+        self.volume_min
+    }
+
+    pub fn advance_on_stop(&self) -> bool {
+        self.advance_on_stop
+    }
+
+    pub fn autostart(&self) -> bool {
+        self.autostart
+    }
+
+    pub fn soft_pause_window_secs(&self) -> u32 {
+        self.soft_pause_window_secs
+    }
+
+    pub fn observer_socket(&self) -> bool {
+        self.observer_socket
+    }
+
+    pub fn log_max_bytes(&self) -> u64 {
+        self.log_max_bytes
+    }
+
+    pub fn log_keep_files(&self) -> u32 {
+        self.log_keep_files
+    }
+
+    pub fn transition_gap_secs(&self) -> u32 {
+        self.transition_gap_secs
+    }
+
+    pub fn reconnect_on_wake(&self) -> bool {
+        self.reconnect_on_wake
+    }
+
+    pub fn kiosk_mode(&self) -> bool {
+        self.kiosk_mode
+    }
+
+    pub fn auto_like_after_plays(&self) -> u32 {
+        self.auto_like_after_plays
+    }
+
+    pub fn subscribe_heartbeat_secs(&self) -> u32 {
+        self.subscribe_heartbeat_secs
+    }
+
+    pub fn mpv_startup_timeout_ms(&self) -> u64 {
+        self.mpv_startup_timeout_ms
+    }
+
+    pub fn scrobble_on_skip(&self) -> bool {
+        self.scrobble_on_skip
+    }
 }
 
 impl Config {
+    fn to_file(&self) -> ConfigFile {
+        ConfigFile {
+            server_url: Some(self.server_url.clone()),
+            username: Some(self.username.clone()),
+            password: Some(self.password.clone()),
+            api_version: Some(self.api_version.clone()),
+            client_name: Some(self.client_name.clone()),
+            endpoint_suffix: Some(self.endpoint_suffix.clone()),
+            tls_verify: Some(self.tls_verify),
+            max_shuffle: Some(self.max_shuffle),
+            volume_step: Some(self.volume_step),
+            end_grace_ms: Some(self.end_grace_ms),
+            min_track_secs: Some(self.min_track_secs),
+            shuffle_exclude_genres: Some(self.shuffle_exclude_genres.clone()),
+            shuffle_exclude_artists: Some(self.shuffle_exclude_artists.clone()),
+            shuffle_exclude_albums: Some(self.shuffle_exclude_albums.clone()),
+            love_rating: Some(self.love_rating),
+            resume_on_start: Some(self.resume_on_start),
+            redact_stream_url: Some(self.redact_stream_url),
+            random_songs_retries: Some(self.random_songs_retries),
+            music_folder: self.music_folder.clone(),
+            max_consecutive_errors: Some(self.max_consecutive_errors),
+            video: Some(self.video.clone()),
+            library_cache: Some(self.library_cache),
+            filter_explicit: Some(self.filter_explicit),
+            resume_albums: Some(self.resume_albums.clone()),
+            prev_restart_threshold_secs: Some(self.prev_restart_threshold_secs),
+            extra_params: Some(self.extra_params.clone()),
+            listenbrainz_token: self.listenbrainz_token.clone(),
+            initial_volume: self.initial_volume,
+            announce: Some(self.announce),
+            announce_command: Some(self.announce_command.clone()),
+            auto_dedupe: Some(self.auto_dedupe),
+            mpv_config_dir: self.mpv_config_dir.clone(),
+            max_queue_len: Some(self.max_queue_len),
+            usage_stats: Some(self.usage_stats),
+            instant_mix_size: Some(self.instant_mix_size),
+            instant_mix_similar: Some(self.instant_mix_similar),
+            instant_mix_artist: Some(self.instant_mix_artist),
+            instant_mix_genre: Some(self.instant_mix_genre),
+            device_name: self.device_name.clone(),
+            volume_max: Some(self.volume_max),
+            volume_min: Some(self.volume_min),
+            advance_on_stop: Some(self.advance_on_stop),
+            autostart: Some(self.autostart),
+            playback_endpoint: Some(self.playback_endpoint.clone()),
+            soft_pause_window_secs: Some(self.soft_pause_window_secs),
+            observer_socket: Some(self.observer_socket),
+            local_library_root: self.local_library_root.clone(),
+            local_path_template: Some(self.local_path_template.clone()),
+            log_file: self.log_file.clone(),
+            log_max_bytes: Some(self.log_max_bytes),
+            log_keep_files: Some(self.log_keep_files),
+            transition: Some(self.transition.clone()),
+            transition_gap_secs: Some(self.transition_gap_secs),
+            reconnect_on_wake: Some(self.reconnect_on_wake),
+            kiosk_mode: Some(self.kiosk_mode),
+            kiosk_commands: Some(self.kiosk_commands.clone()),
+            auto_like_after_plays: Some(self.auto_like_after_plays),
+            subscribe_heartbeat_secs: Some(self.subscribe_heartbeat_secs),
+            mpv_startup_timeout_ms: Some(self.mpv_startup_timeout_ms),
+            scrobble_on_skip: Some(self.scrobble_on_skip),
+            default_shuffle: Some(self.default_shuffle.clone()),
+        }
+    }
+
     fn from_file(file: ConfigFile) -> Self {
         let server_url = normalize_url(file.server_url.unwrap_or_default().as_str());
         let username = file.username.unwrap_or_default();
@@ -211,6 +654,80 @@ impl Config {
         let max_shuffle = file.max_shuffle.unwrap_or(DEFAULT_MAX_SHUFFLE);
         let volume_step = file.volume_step.unwrap_or(DEFAULT_VOLUME_STEP);
         let end_grace_ms = file.end_grace_ms.unwrap_or(DEFAULT_END_GRACE_MS);
+        let min_track_secs = file.min_track_secs.unwrap_or(DEFAULT_MIN_TRACK_SECS);
+        let shuffle_exclude_genres = file.shuffle_exclude_genres.unwrap_or_default();
+        let shuffle_exclude_artists = file.shuffle_exclude_artists.unwrap_or_default();
+        let shuffle_exclude_albums = file.shuffle_exclude_albums.unwrap_or_default();
+        let love_rating = file.love_rating.unwrap_or(DEFAULT_LOVE_RATING);
+        let resume_on_start = file.resume_on_start.unwrap_or(false);
+        let redact_stream_url = file.redact_stream_url.unwrap_or(true);
+        let random_songs_retries = file.random_songs_retries.unwrap_or(DEFAULT_RANDOM_SONGS_RETRIES);
+        let music_folder = file.music_folder.filter(|id| !id.trim().is_empty());
+        let max_consecutive_errors = file.max_consecutive_errors.unwrap_or(DEFAULT_MAX_CONSECUTIVE_ERRORS);
+        let video = file
+            .video
+            .unwrap_or_else(|| DEFAULT_VIDEO.to_string());
+        let library_cache = file.library_cache.unwrap_or(false);
+        let filter_explicit = file.filter_explicit.unwrap_or(false);
+        let resume_albums = file
+            .resume_albums
+            .unwrap_or_else(|| DEFAULT_RESUME_ALBUMS.to_string());
+        let prev_restart_threshold_secs = file.prev_restart_threshold_secs.unwrap_or(DEFAULT_PREV_RESTART_THRESHOLD_SECS);
+        let extra_params = file.extra_params.unwrap_or_default();
+        let listenbrainz_token = file.listenbrainz_token.filter(|token| !token.trim().is_empty());
+        let announce = file.announce.unwrap_or(false);
+        let announce_command = file
+            .announce_command
+            .unwrap_or_else(|| DEFAULT_ANNOUNCE_COMMAND.to_string());
+        let auto_dedupe = file.auto_dedupe.unwrap_or(false);
+        let mpv_config_dir = file.mpv_config_dir.filter(|dir| !dir.trim().is_empty());
+        let max_queue_len = file.max_queue_len.unwrap_or_default();
+        let usage_stats = file.usage_stats.unwrap_or_default();
+        let instant_mix_size = file.instant_mix_size.unwrap_or(DEFAULT_INSTANT_MIX_SIZE);
+        let instant_mix_similar = file.instant_mix_similar.unwrap_or(DEFAULT_INSTANT_MIX_SIMILAR);
+        let instant_mix_artist = file.instant_mix_artist.unwrap_or(DEFAULT_INSTANT_MIX_ARTIST);
+        let instant_mix_genre = file.instant_mix_genre.unwrap_or(DEFAULT_INSTANT_MIX_GENRE);
+        let device_name = file.device_name.filter(|name| !name.trim().is_empty());
+        let volume_max = file.volume_max.unwrap_or(100).min(100);
+        let volume_min = file.volume_min.unwrap_or(0).min(volume_max);
+        let initial_volume = file
+            .initial_volume
+            .map(|volume| volume.clamp(volume_min, volume_max));
+        let advance_on_stop = file.advance_on_stop.unwrap_or_default();
+        let autostart = file.autostart.unwrap_or_default();
+        let playback_endpoint = file
+            .playback_endpoint
+            .unwrap_or_else(|| DEFAULT_PLAYBACK_ENDPOINT.to_string());
+        let soft_pause_window_secs = file.soft_pause_window_secs.unwrap_or(DEFAULT_SOFT_PAUSE_WINDOW_SECS);
+        let observer_socket = file.observer_socket.unwrap_or_default();
+        let local_library_root = file.local_library_root.filter(|root| !root.trim().is_empty());
+        let local_path_template = file
+            .local_path_template
+            .filter(|template| !template.trim().is_empty())
+            .unwrap_or_else(|| DEFAULT_LOCAL_PATH_TEMPLATE.to_string());
+        let log_file = file.log_file.filter(|path| !path.trim().is_empty());
+        let log_max_bytes = file.log_max_bytes.unwrap_or(DEFAULT_LOG_MAX_BYTES);
+        let log_keep_files = file.log_keep_files.unwrap_or(DEFAULT_LOG_KEEP_FILES);
+        let transition = file
+            .transition
+            .map(|mode| mode.trim().to_lowercase())
+            .filter(|mode| !mode.is_empty())
+            .unwrap_or_else(|| DEFAULT_TRANSITION.to_string());
+        let transition_gap_secs = file.transition_gap_secs.unwrap_or(DEFAULT_TRANSITION_GAP_SECS);
+        let reconnect_on_wake = file.reconnect_on_wake.unwrap_or(true);
+        let kiosk_mode = file.kiosk_mode.unwrap_or_default();
+        let kiosk_commands = file
+            .kiosk_commands
+            .unwrap_or_else(|| DEFAULT_KIOSK_COMMANDS.iter().map(|cmd| cmd.to_string()).collect());
+        let auto_like_after_plays = file.auto_like_after_plays.unwrap_or_default();
+        let subscribe_heartbeat_secs = file.subscribe_heartbeat_secs.unwrap_or(DEFAULT_SUBSCRIBE_HEARTBEAT_SECS);
+        let mpv_startup_timeout_ms = file.mpv_startup_timeout_ms.unwrap_or(DEFAULT_MPV_STARTUP_TIMEOUT_MS);
+        let scrobble_on_skip = file.scrobble_on_skip.unwrap_or(false);
+        let default_shuffle = file
+            .default_shuffle
+            .map(|target| target.trim().to_string())
+            .filter(|target| !target.is_empty())
+            .unwrap_or_else(|| DEFAULT_SHUFFLE.to_string());
 
         Self {
             server_url,
@@ -223,6 +740,57 @@ impl Config {
             max_shuffle,
             volume_step,
             end_grace_ms,
+            min_track_secs,
+            shuffle_exclude_genres,
+            shuffle_exclude_artists,
+            shuffle_exclude_albums,
+            love_rating,
+            resume_on_start,
+            redact_stream_url,
+            random_songs_retries,
+            music_folder,
+            max_consecutive_errors,
+            video,
+            library_cache,
+            filter_explicit,
+            resume_albums,
+            prev_restart_threshold_secs,
+            extra_params,
+            listenbrainz_token,
+            initial_volume,
+            announce,
+            announce_command,
+            auto_dedupe,
+            mpv_config_dir,
+            max_queue_len,
+            usage_stats,
+            instant_mix_size,
+            instant_mix_similar,
+            instant_mix_artist,
+            instant_mix_genre,
+            device_name,
+            volume_max,
+            volume_min,
+            advance_on_stop,
+            autostart,
+            playback_endpoint,
+            soft_pause_window_secs,
+            observer_socket,
+            local_library_root,
+            local_path_template,
+            log_file,
+            log_max_bytes,
+            log_keep_files,
+            transition,
+            transition_gap_secs,
+            reconnect_on_wake,
+            kiosk_mode,
+            kiosk_commands,
+            auto_like_after_plays,
+            subscribe_heartbeat_secs,
+            mpv_startup_timeout_ms,
+            scrobble_on_skip,
+            default_shuffle,
         }
     }
 }
@@ -238,6 +806,50 @@ fn load_config_file() -> Result<Option<ConfigFile>> {
     Ok(Some(file))
 }
 
+const ENV_PREFIX: &str = "SIMPLAY_";
+/// `SIMPLAY_*` variables that are not config fields.
+const NON_CONFIG_ENV: &[&str] = &["SIMPLAY_SOCKET", "SIMPLAY_MPV"];
+
+fn config_env_vars() -> impl Iterator<Item = (String, String)> {
+    env::vars().filter(|(name, _)| name.starts_with(ENV_PREFIX) && !NON_CONFIG_ENV.contains(&name.as_str()))
+}
+
+fn has_env_config() -> bool {
+    config_env_vars().next().is_some()
+}
+
+/// Overrides file values with `SIMPLAY_<FIELD>` environment variables, e.g.
+/// `SIMPLAY_SERVER_URL` or `SIMPLAY_MAX_SHUFFLE=500`. Values are read as TOML
+/// (numbers, booleans, arrays, inline tables) and otherwise as a string.
+/// Variables that match no field are ignored.
+fn apply_env(file: ConfigFile) -> Result<ConfigFile> {
+    let mut table = match toml::Value::try_from(&file)? {
+        toml::Value::Table(table) => table,
+        _ => return Ok(file),
+    };
+    for (name, value) in config_env_vars() {
+        let key = name[ENV_PREFIX.len()..].to_lowercase();
+        let parsed = toml::from_str::<toml::Table>(&format!("value = {}", value))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"));
+        let fits = |candidate: &toml::Value| {
+            let mut table = table.clone();
+            table.insert(key.clone(), candidate.clone());
+            toml::Value::Table(table).try_into::<ConfigFile>().is_ok()
+        };
+        // `SIMPLAY_PASSWORD=1234` is a string, not a number.
+        let value = match parsed {
+            Some(parsed) if fits(&parsed) => parsed,
+            _ => toml::Value::String(value),
+        };
+        if !fits(&value) {
+            return Err(anyhow!("Invalid value in {}", name));
+        }
+        table.insert(key, value);
+    }
+    Ok(toml::Value::Table(table).try_into()?)
+}
+
 fn config_dir() -> Result<PathBuf> {
     if let Ok(dir) = env::var("XDG_CONFIG_HOME") {
         return Ok(PathBuf::from(dir));
@@ -246,6 +858,14 @@ fn config_dir() -> Result<PathBuf> {
     Ok(PathBuf::from(home).join(".config"))
 }
 
+fn cache_dir() -> Result<PathBuf> {
+    if let Ok(dir) = env::var("XDG_CACHE_HOME") {
+        return Ok(PathBuf::from(dir));
+    }
+    let home = env::var("HOME").map_err(|_| anyhow!("HOME not set"))?;
+    Ok(PathBuf::from(home).join(".cache"))
+}
+
 fn runtime_dir() -> Option<PathBuf> {
     env::var("XDG_RUNTIME_DIR").ok().map(PathBuf::from)
 }
@@ -345,6 +965,18 @@ fn prompt_u8(label: &str, default: u8) -> Result<u8> {
         .map_err(|_| anyhow!("Invalid number"))
 }
 
+fn prompt_u32(label: &str, default: u32) -> Result<u32> {
+    let prompt = format!("{} [{}]: ", label, default);
+    let input = prompt_line(&prompt)?;
+    if input.trim().is_empty() {
+        return Ok(default);
+    }
+    input
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| anyhow!("Invalid number"))
+}
+
 fn prompt_u64(label: &str, default: u64) -> Result<u64> {
     let prompt = format!("{} [{}]: ", label, default);
     let input = prompt_line(&prompt)?;
@@ -361,6 +993,64 @@ fn prompt_line(prompt: &str) -> Result<String> {
     print!("{}", prompt);
     io::stdout().flush()?;
     let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
+    // No terminal (e.g. an autostarted daemon): fail instead of re-prompting forever.
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Err(anyhow!("No input for {}; run simplay --configure", prompt.trim_end_matches(": ")));
+    }
     Ok(input)
 }
+
+#[cfg(test)]
+impl Config {
+    /// A config read from TOML text, for tests.
+    pub(crate) fn from_toml(text: &str) -> Self {
+        Self::from_file(toml::from_str(text).expect("valid test config"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn volume_clamps_to_the_configured_range() {
+        let config = Config::from_toml("volume_min = 20\nvolume_max = 80");
+        assert_eq!(config.clamp_volume(-5), 20);
+        assert_eq!(config.clamp_volume(19), 20);
+        assert_eq!(config.clamp_volume(20), 20);
+        assert_eq!(config.clamp_volume(50), 50);
+        assert_eq!(config.clamp_volume(80), 80);
+        assert_eq!(config.clamp_volume(81), 80);
+        assert_eq!(config.clamp_volume(150), 80);
+    }
+
+    #[test]
+    fn volume_range_defaults_to_the_full_scale() {
+        let config = Config::from_toml("");
+        assert_eq!((config.volume_min(), config.volume_max()), (0, 100));
+        assert_eq!(config.clamp_volume(-1), 0);
+        assert_eq!(config.clamp_volume(101), 100);
+    }
+
+    #[test]
+    fn volume_limits_are_coerced_into_order() {
+        let config = Config::from_toml("volume_max = 150");
+        assert_eq!(config.volume_max(), 100);
+        // A floor above the ceiling is lowered to it.
+        let config = Config::from_toml("volume_min = 90\nvolume_max = 60");
+        assert_eq!((config.volume_min(), config.volume_max()), (60, 60));
+        assert_eq!(config.clamp_volume(0), 60);
+        assert_eq!(config.clamp_volume(100), 60);
+    }
+
+    #[test]
+    fn initial_volume_is_clamped_into_the_range() {
+        let config = Config::from_toml("volume_min = 10\nvolume_max = 70\ninitial_volume = 90");
+        assert_eq!(config.initial_volume, Some(70));
+        let config = Config::from_toml("volume_min = 10\nvolume_max = 70\ninitial_volume = 5");
+        assert_eq!(config.initial_volume, Some(10));
+        let config = Config::from_toml("volume_min = 10\nvolume_max = 70\ninitial_volume = 40");
+        assert_eq!(config.initial_volume, Some(40));
+        assert_eq!(Config::from_toml("").initial_volume, None);
+    }
+}
@@ -1,16 +1,97 @@
+use crate::announce;
 use crate::config::Config;
+use crate::library;
+use crate::listenbrainz::ListenBrainz;
+use crate::logfile;
 use crate::player::{MpvController, MpvEvent};
-use crate::protocol::{Response, SongInfo, Status};
-use crate::subsonic::{NavidromeClient, Song};
+use crate::protocol::{
+    AlbumDetails, Diagnosis, Health, Resolved, ResolvedMatch, Response, SongDetails, SongInfo, Status,
+    TrackInfo,
+};
+use crate::session::{self, AlbumPosition, Session};
+use crate::subsonic::{self, NavidromeClient, Song, SubsonicError};
+use crate::systemd;
 use anyhow::{anyhow, Context, Result};
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::os::unix::fs::PermissionsExt;
 use std::os::unix::net::{UnixListener, UnixStream};
-use std::sync::{mpsc, Arc, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, TryLockError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const PAUSED_POLL_MS: u64 = 1000;
+const DEFAULT_RANDOM_ALBUMS: usize = 10;
+/// Music volume, as a fraction, while a track is announced.
+const ANNOUNCE_DUCK: f64 = 0.3;
+/// How long `softpause` fades the volume out or back in.
+const SOFT_PAUSE_FADE_MS: u64 = 1500;
+const SOFT_PAUSE_FADE_STEPS: u64 = 15;
+/// Oldest plays are dropped from the in-memory history past this many.
+const MAX_HISTORY: usize = 10_000;
+/// Distinct songs `failures` remembers; the oldest are dropped past this.
+const MAX_FAILURES: usize = 500;
+/// How much of the next track `prefetch` downloads.
+const PREFETCH_BYTES: u64 = 256 * 1024;
+/// Albums and songs each that `search` returns.
+const SEARCH_RESULTS: usize = 10;
+/// How often the suspend watcher compares the wall clock with the monotonic
+/// one, and how far they may drift apart before it counts as a sleep.
+const SUSPEND_TICK_SECS: u64 = 5;
+const SUSPEND_GAP_SECS: u64 = 30;
+/// How often subscribers' status is checked for changes, and how long a
+/// write to one may block before it counts as gone.
+const SUBSCRIBE_POLL_MS: u64 = 500;
+const SUBSCRIBER_WRITE_TIMEOUT_SECS: u64 = 2;
+/// How long `diagnose` waits between its two position samples.
+const DIAGNOSE_SAMPLE_MS: u64 = 500;
+/// The latest point a skipped track counts as played for `scrobble_on_skip`.
+const SCROBBLE_POINT_MAX_SECS: f64 = 240.0;
+/// A chapter boundary closer than this to the position counts as already
+/// reached, so a relative seek from a boundary carries on past it.
+const CHAPTER_EDGE_SECS: f64 = 1.0;
+/// Transition modes `transition` accepts.
+const TRANSITIONS: &[&str] = &["none", "gap", "gapless"];
+/// How many playlist entries `shuffleplaylist` waits for before it starts.
+const PLAYLIST_BATCH: usize = 50;
+/// `getAlbumList2` caps `size` at 500.
+const MAX_RANDOM_ALBUMS: usize = 500;
+/// Every command `handle_command` understands, for suggestions on typos.
+const COMMANDS: &[&str] = &[
+    "shuffle", "shufflekeepalbum", "instantmix", "refreshcache", "shuffleartist",
+    "shufflealbum", "shuffleplaylist", "playlistcontents", "playalbum",
+    "shufflerandomalbums", "playartist", "playyear", "resolve", "similarartists",
+    "albuminfo", "queuealbumnext", "playurl", "fastforward", "rewind", "restartqueue",
+    "bookmark", "gotobookmark", "dedupe", "groupbyartist", "albumgoto", "gototrack",
+    "randomtrack", "resumealbum", "repeatcount", "exporthistory", "pauseafter", "pause",
+    "play", "startover", "likesong", "unlikesong", "love", "rate", "shuffleliked",
+    "normalize", "announce", "volumeup", "volumedown", "addsongtoplaylist",
+    "deleteplaylist", "status", "songinfo", "trackinfo", "streamurl", "listfolders",
+    "usagestats", "setfolder", "grace", "gracestats", "rescan", "scanstatus", "health",
+    "search", "playresult", "prefetch", "shareplaylist", "audiodevices", "audiodevice",
+    "resumeaftererror", "softpause", "nextdisc", "prevdisc", "localplayback", "undo",
+    "transition", "playtrack", "diagnose", "sortbyrating", "subscribe",
+    "requeuecurrent", "scrobbleonskip", "finishalbum", "failures", "clearfailures",
+    "seek",
+];
+/// The commands observer connections may send: they report on playback and
+/// the library without changing either. `streamurl` is left out since the
+/// URL carries credentials.
+const OBSERVER_COMMANDS: &[&str] = &[
+    "status", "health", "songinfo", "trackinfo", "search", "playlistcontents",
+    "listfolders", "albuminfo", "similarartists", "resolve", "gracestats", "scanstatus",
+    "usagestats", "audiodevices", "diagnose", "subscribe", "failures",
+];
 
 struct State {
     queue: Vec<Song>,
@@ -21,10 +102,162 @@ struct State {
     shuffle: bool,
     suppress_next_end: bool,
     end_grace_ms: u64,
+    core_idle: bool,
+    paused_for_cache: bool,
+    started: Instant,
+    track_serial: u64,
+    next_group: u32,
+    stream_url: Option<String>,
+    external: bool,
+    normalize: bool,
+    consecutive_errors: u32,
+    max_consecutive_errors: u32,
+    /// Album started with `playalbum` whose position is being remembered.
+    album_play: Option<String>,
+    /// Saved (queue index, position) offered by `playalbum` in prompt mode.
+    resume_offer: Option<(usize, f64)>,
+    /// Serial of the track after which playback pauses; any other track
+    /// change makes it stale.
+    pause_after_current: Option<u64>,
+    /// Extra passes over the queue left in repeat-count mode.
+    repeats_left: Option<u32>,
+    /// `repeat` as it was before `repeatcount`, restored once the count ends.
+    repeat_before_count: bool,
+    history: VecDeque<HistoryEntry>,
+    listenbrainz: Option<ListenBrainz>,
+    /// Volume for the first queue this daemon plays; taken once applied.
+    initial_volume: Option<u8>,
+    announce: bool,
+    /// Scrobble manual skips that got past the scrobble point.
+    scrobble_on_skip: bool,
+    announce_command: String,
+    announcing: bool,
+    auto_dedupe: bool,
+    /// Bumped whenever the queue is replaced, so background loaders can tell
+    /// their queue is gone.
+    queue_serial: u64,
+    /// Longest the queue may grow through appends before played tracks are
+    /// dropped from the front; 0 = unbounded.
+    max_queue_len: usize,
+    /// Per-command use counts; only kept when `usage_stats` is on.
+    usage_counts: BTreeMap<String, u64>,
+    /// Hands count snapshots to the writer thread.
+    usage_tx: Option<mpsc::Sender<BTreeMap<String, u64>>>,
+    position_writer: Option<PositionWriter>,
+    grace: GraceStats,
+    /// Results of the last `search`, numbered from 1 for `playresult`.
+    last_search: Vec<SearchHit>,
+    /// Whether an `end-file` with reason `stop` moves on to the next track.
+    advance_on_stop: bool,
+    /// Local mirror of the library, tried before streaming.
+    local_root: Option<PathBuf>,
+    local_path_template: String,
+    local_playback: bool,
+    /// The current track was loaded from `local_root`.
+    local_file: bool,
+    /// The queue before the last command that replaced it, for `undo`.
+    undo: Option<QueueSnapshot>,
+    /// One of `TRANSITIONS`.
+    transition: String,
+    transition_gap_secs: u32,
+    /// Full plays after which a song is starred; 0 = never.
+    auto_like_after_plays: u32,
+    /// Connections that sent `subscribe` and get a line per status change.
+    subscribers: Vec<UnixStream>,
+    /// Songs that failed to load this session, oldest first.
+    failures: Vec<Failure>,
+    /// The next track, already appended to mpv's playlist by `gapless`.
+    preloaded: Option<Preload>,
+}
+
+/// Saves album position changes on a background thread, so track changes
+/// don't wait on the positions file.
+struct PositionWriter {
+    tx: mpsc::Sender<(String, Option<AlbumPosition>)>,
+    thread: thread::JoinHandle<()>,
+}
+
+/// One level of `undo`: the queue and where it was, but not the position
+/// within the track.
+#[derive(Debug)]
+struct QueueSnapshot {
+    queue: Vec<Song>,
+    index: usize,
+    repeat: bool,
+    shuffle: bool,
+}
+
+/// A track `preload_next` appended to mpv's playlist. mpv starts it by
+/// itself when the current one ends, so `play_next` only takes it over.
+#[derive(Debug)]
+struct Preload {
+    id: String,
+    url: String,
+    local: bool,
+}
+
+/// A song mpv ended with an `error`, and how often it did.
+#[derive(Debug)]
+struct Failure {
+    id: String,
+    title: String,
+    artist: String,
+    reason: String,
+    count: u32,
+}
+
+#[derive(Debug, Clone)]
+enum SearchHit {
+    Artist(subsonic::SearchEntry),
+    Album(subsonic::SearchEntry),
+    Song(Box<Song>),
+}
+
+/// How track ends were detected this session, for tuning `end_grace_ms`.
+#[derive(Debug, Default)]
+struct GraceStats {
+    /// Ends reported by mpv's `end-file` event.
+    events: u64,
+    /// Ends caught by the fallback timer instead.
+    fallbacks: u64,
+    /// `end-file` events that arrived after the fallback had already moved on.
+    late_events: u64,
+    /// The last track change came from the fallback timer.
+    fallback_fired: bool,
+    hint_logged: bool,
+}
+
+impl GraceStats {
+    fn suggestion(&self, grace_ms: u64) -> Option<String> {
+        if self.late_events >= 3 && self.late_events * 4 >= self.fallbacks {
+            return Some(format!(
+                "{} of {} fallbacks fired before mpv reported the end; end_grace_ms = {} looks too short",
+                self.late_events, self.fallbacks, grace_ms
+            ));
+        }
+        if self.fallbacks >= 5 && self.events == 0 && self.late_events == 0 {
+            return Some(format!(
+                "mpv has not reported any track end, so every track waits the full {} ms; a lower end_grace_ms shortens the gap",
+                grace_ms
+            ));
+        }
+        None
+    }
+}
+
+/// One track start, kept for `exporthistory`.
+#[derive(Debug, Clone, Serialize)]
+struct HistoryEntry {
+    id: String,
+    title: String,
+    artist: String,
+    album: String,
+    /// Unix seconds.
+    played_at: u64,
 }
 
 impl State {
-    fn new(end_grace_ms: u64) -> Self {
+    fn new(config: &Config) -> Self {
         Self {
             queue: Vec::new(),
             index: 0,
@@ -33,7 +266,73 @@ impl State {
             repeat: false,
             shuffle: false,
             suppress_next_end: false,
-            end_grace_ms,
+            end_grace_ms: config.end_grace_ms(),
+            core_idle: false,
+            paused_for_cache: false,
+            started: Instant::now(),
+            track_serial: 0,
+            next_group: 1,
+            stream_url: None,
+            external: false,
+            normalize: false,
+            consecutive_errors: 0,
+            max_consecutive_errors: config.max_consecutive_errors(),
+            album_play: None,
+            resume_offer: None,
+            pause_after_current: None,
+            repeats_left: None,
+            repeat_before_count: false,
+            history: VecDeque::new(),
+            listenbrainz: config.listenbrainz_token.as_deref().and_then(|token| {
+                ListenBrainz::new(token)
+                    .map_err(|err| eprintln!("simplay: ListenBrainz disabled: {}", err))
+                    .ok()
+            }),
+            initial_volume: config.initial_volume,
+            announce: config.announce(),
+            scrobble_on_skip: config.scrobble_on_skip(),
+            announce_command: config.announce_command.clone(),
+            announcing: false,
+            auto_dedupe: config.auto_dedupe(),
+            queue_serial: 0,
+            max_queue_len: config.max_queue_len(),
+            usage_counts: BTreeMap::new(),
+            usage_tx: None,
+            position_writer: None,
+            grace: GraceStats::default(),
+            last_search: Vec::new(),
+            advance_on_stop: config.advance_on_stop(),
+            local_root: config.local_library_root.as_ref().map(PathBuf::from),
+            local_path_template: config.local_path_template.clone(),
+            local_playback: config.local_library_root.is_some(),
+            local_file: false,
+            undo: None,
+            failures: Vec::new(),
+            preloaded: None,
+            transition: if TRANSITIONS.contains(&config.transition.as_str()) {
+                config.transition.clone()
+            } else {
+                eprintln!("simplay: unknown transition '{}', using none", config.transition);
+                "none".to_string()
+            },
+            transition_gap_secs: config.transition_gap_secs(),
+            auto_like_after_plays: config.auto_like_after_plays(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    /// The preloaded track, if mpv moving on to it by itself is how `next_id`
+    /// starts. A track ending by itself with any other preload means mpv
+    /// started a track the queue no longer has next; the load replacing it
+    /// ends it, and that end must not advance again.
+    fn take_preload(&mut self, next_id: &str, manual: bool, hold: bool) -> Option<Preload> {
+        match self.preloaded.take() {
+            Some(preload) if !manual && !hold && preload.id == next_id => Some(preload),
+            Some(_) if !manual => {
+                self.suppress_next_end = true;
+                None
+            }
+            _ => None,
         }
     }
 
@@ -46,14 +345,145 @@ impl State {
                 album: song.album.clone(),
             }),
             paused: self.paused,
+            buffering: self.is_buffering(),
+            source: if self.external {
+                Some("external URL".to_string())
+            } else if self.local_file {
+                Some("local file".to_string())
+            } else {
+                None
+            },
+            normalize: self.normalize,
+            folder: None,
             queue_len: self.queue.len(),
             index: self.index,
+            pause_after: self.pause_after_current == Some(self.track_serial),
+            repeats_left: self.repeats_left,
+            position: None,
+            ends_in_secs: None,
+            // A queue edit since the preload leaves a stale entry behind.
+            gapless: match (&self.preloaded, self.queue.get(self.index + 1)) {
+                (Some(preload), Some(next)) => preload.id == next.id,
+                _ => false,
+            },
+            chapter: None,
+            chapter_title: None,
+            consecutive_errors: self.consecutive_errors,
+            halted_by_errors: self.breaker_tripped(),
+            transition: self.transition.clone(),
+        }
+    }
+
+    /// Remembers the queue for `undo` before it is replaced. An empty queue
+    /// or a played URL isn't worth going back to.
+    fn snapshot_queue(&mut self) {
+        if self.queue.is_empty() || self.external {
+            return;
+        }
+        self.undo = Some(QueueSnapshot {
+            queue: self.queue.clone(),
+            index: self.index,
+            repeat: self.repeat,
+            shuffle: self.shuffle,
+        });
+    }
+
+    /// Replaces the queue with `url` alone, for `play_external`.
+    fn start_external(&mut self, url: &str) {
+        let song = Song::external(url);
+        self.suppress_next_end = self.current.is_some();
+        self.snapshot_queue();
+        self.queue = vec![song.clone()];
+        self.queue_serial += 1;
+        self.index = 0;
+        self.current = Some(song);
+        self.paused = false;
+        self.repeat = false;
+        self.repeats_left = None;
+        self.shuffle = false;
+        self.external = true;
+        self.stream_url = Some(url.to_string());
+        self.preloaded = None;
+        self.track_serial += 1;
+    }
+
+    /// Moves `index` to the next track, wrapping when the queue repeats or a
+    /// repeat count has passes left. Returns `false` at the end of the queue.
+    fn advance(&mut self) -> bool {
+        if self.index + 1 < self.queue.len() {
+            self.index += 1;
+            return true;
+        }
+        let counted = match self.repeats_left {
+            Some(left) if left > 0 => {
+                self.repeats_left = Some(left - 1);
+                true
+            }
+            _ => false,
+        };
+        if !self.repeat && !counted {
+            self.end_repeat_count();
+            return false;
+        }
+        if self.shuffle {
+            shuffle_queue(&mut self.queue);
+        }
+        self.index = 0;
+        true
+    }
+
+    /// Plays the queue `count` times in all, counting the pass in progress;
+    /// 0 cancels a count.
+    fn start_repeat_count(&mut self, count: u32) {
+        if count == 0 {
+            self.end_repeat_count();
+            return;
+        }
+        if self.repeats_left.is_none() {
+            self.repeat_before_count = self.repeat;
+        }
+        self.repeat = false;
+        self.repeats_left = Some(count - 1);
+    }
+
+    /// Saves, or with `None` forgets, an album's position through the
+    /// position writer, or right away when there is none.
+    fn change_album_position(&self, album_id: String, change: Option<AlbumPosition>) {
+        let change = match &self.position_writer {
+            Some(writer) => match writer.tx.send((album_id, change)) {
+                Ok(()) => return,
+                Err(mpsc::SendError(change)) => change,
+            },
+            None => (album_id, change),
+        };
+        if let Err(err) = session::update_album_positions(HashMap::from([change])) {
+            eprintln!("simplay: failed to save album position: {}", err);
+        }
+    }
+
+    /// Leaves repeat-count mode, putting back the `repeat` it replaced.
+    fn end_repeat_count(&mut self) {
+        if self.repeats_left.take().is_some() {
+            self.repeat = self.repeat_before_count;
+        }
+    }
+
+    fn breaker_tripped(&self) -> bool {
+        self.max_consecutive_errors > 0 && self.consecutive_errors >= self.max_consecutive_errors
+    }
+
+    fn is_buffering(&self) -> bool {
+        if self.current.is_none() || self.paused {
+            return false;
         }
+        self.paused_for_cache || self.core_idle
     }
 }
 
-pub fn run(config: Config) -> Result<()> {
-    let socket_path = Config::socket_path()?;
+pub fn run(config: Config, socket_path: PathBuf) -> Result<()> {
+    if let Some(path) = config.log_file.as_deref() {
+        logfile::redirect_stderr(Path::new(path), config.log_max_bytes(), config.log_keep_files())?;
+    }
     if socket_path.exists() {
         fs::remove_file(&socket_path).ok();
     }
@@ -61,9 +491,20 @@ pub fn run(config: Config) -> Result<()> {
     let listener = UnixListener::bind(&socket_path)
         .with_context(|| format!("Failed to bind socket {}", socket_path.display()))?;
     fs::set_permissions(&socket_path, fs::Permissions::from_mode(0o600))?;
+    let observer = if config.observer_socket() {
+        let path = Config::observer_socket_path(&socket_path);
+        fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind observer socket {}", path.display()))?;
+        // Read-only, so other local users may watch.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o666))?;
+        Some(listener)
+    } else {
+        None
+    };
 
     let mpv_socket = Config::mpv_socket_path()?;
-    let mpv = match MpvController::spawn(&mpv_socket) {
+    let mpv = match MpvController::spawn(&mpv_socket, &config) {
         Ok(mpv) => Arc::new(mpv),
         Err(err) => {
             fs::remove_file(&socket_path).ok();
@@ -72,22 +513,136 @@ pub fn run(config: Config) -> Result<()> {
     };
 
     let client = NavidromeClient::new(&config)?;
-    let state = Arc::new(Mutex::new(State::new(config.end_grace_ms())));
+    let state = Arc::new(Mutex::new(State::new(&config)));
+    let gapless = state.lock().map(|st| st.transition == "gapless").unwrap_or(false);
+    if gapless {
+        if let Err(err) = mpv.set_gapless(true) {
+            eprintln!("simplay: could not enable gapless playback: {}", err);
+        }
+    }
+
+    install_signal_handler(
+        state.clone(),
+        mpv.clone(),
+        socket_path.clone(),
+        mpv_socket.clone(),
+    )?;
 
     let (event_tx, event_rx) = mpsc::channel();
     mpv.start_event_loop(event_tx)?;
 
     start_event_handler(state.clone(), client.clone(), mpv.clone(), event_rx);
 
+    if config.usage_stats() {
+        start_usage_writer(&state);
+    }
+    start_position_writer(&state);
+
+    if config.kiosk_mode() {
+        for cmd in config.kiosk_commands.iter().filter(|cmd| !COMMANDS.contains(&cmd.as_str())) {
+            eprintln!("simplay: kiosk_commands lists unknown command '{}'", cmd);
+        }
+    }
+
+    start_broadcaster(state.clone(), config.subscribe_heartbeat_secs());
+
+    if config.reconnect_on_wake() {
+        start_suspend_watch(state.clone(), client.clone(), mpv.clone());
+    }
+
+    if config.resume_on_start() {
+        match session::load() {
+            Ok(Some(saved)) => {
+                if let Err(err) = resume_session(&state, &client, &mpv, saved) {
+                    eprintln!("simplay: could not resume last session: {}", err);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => eprintln!("simplay: could not load last session: {}", err),
+        }
+    }
+    let accepted = Arc::new(AtomicU64::new(0));
+    {
+        let state = state.clone();
+        let accepted = accepted.clone();
+        systemd::notify_ready(move |budget| daemon_alive(&state, &socket_path, &accepted, budget));
+    }
+
+    if let Some(observer) = observer {
+        let state = state.clone();
+        let client = client.clone();
+        let mpv = mpv.clone();
+        let config = config.clone();
+        thread::spawn(move || serve(observer, true, None, state, client, mpv, config));
+    }
+    serve(listener, false, Some(accepted), state, client, mpv, config);
+
+    Ok(())
+}
+
+/// Watchdog liveness check: the state lock has to come free and the accept
+/// loop has to pick up a probe connection, both within `budget`.
+fn daemon_alive(
+    state: &Mutex<State>,
+    socket_path: &Path,
+    accepted: &AtomicU64,
+    budget: Duration,
+) -> bool {
+    let deadline = Instant::now() + budget;
+    if !lock_frees_within(state, budget) {
+        return false;
+    }
+    let before = accepted.load(Ordering::Relaxed);
+    if UnixStream::connect(socket_path).is_err() {
+        return false;
+    }
+    while accepted.load(Ordering::Relaxed) == before {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    true
+}
+
+/// Whether `lock` can be taken before `budget` runs out. A poisoned lock
+/// never counts as free.
+fn lock_frees_within<T>(lock: &Mutex<T>, budget: Duration) -> bool {
+    let deadline = Instant::now() + budget;
+    loop {
+        match lock.try_lock() {
+            Ok(_) => return true,
+            Err(TryLockError::Poisoned(_)) => return false,
+            Err(TryLockError::WouldBlock) if Instant::now() >= deadline => return false,
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(10)),
+        }
+    }
+}
+
+/// Accepts connections on `listener`, one thread each. Observer connections
+/// may only send `OBSERVER_COMMANDS`. Each accepted connection bumps
+/// `accepted`, so the watchdog can tell the loop is still turning.
+fn serve(
+    listener: UnixListener,
+    observer: bool,
+    accepted: Option<Arc<AtomicU64>>,
+    state: Arc<Mutex<State>>,
+    client: NavidromeClient,
+    mpv: Arc<MpvController>,
+    config: Config,
+) {
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
+                if let Some(accepted) = &accepted {
+                    accepted.fetch_add(1, Ordering::Relaxed);
+                }
                 let state = state.clone();
                 let client = client.clone();
                 let mpv = mpv.clone();
                 let config = config.clone();
                 thread::spawn(move || {
-                    if let Err(err) = handle_connection(stream, state, client, mpv, config) {
+                    if let Err(err) = handle_connection(stream, observer, state, client, mpv, config) {
                         eprintln!("simplay: error handling client: {}", err);
                     }
                 });
@@ -95,10 +650,286 @@ pub fn run(config: Config) -> Result<()> {
             Err(err) => eprintln!("simplay: socket accept error: {}", err),
         }
     }
+}
+
+/// Loads the saved usage counts and starts the thread that writes them back,
+/// so counting a command never waits on the disk.
+fn start_usage_writer(state: &Arc<Mutex<State>>) {
+    let counts = session::load_usage_stats().unwrap_or_else(|err| {
+        eprintln!("simplay: could not load usage stats: {}", err);
+        BTreeMap::new()
+    });
+    let (tx, rx) = mpsc::channel::<BTreeMap<String, u64>>();
+    if let Ok(mut st) = state.lock() {
+        st.usage_counts = counts;
+        st.usage_tx = Some(tx);
+    }
+    thread::spawn(move || {
+        while let Ok(mut counts) = rx.recv() {
+            // Only the newest snapshot matters.
+            while let Ok(newer) = rx.try_recv() {
+                counts = newer;
+            }
+            if let Err(err) = session::save_usage_stats(&counts) {
+                eprintln!("simplay: failed to save usage stats: {}", err);
+            }
+        }
+    });
+}
+
+/// Writes the status to every subscriber when it changes, and a heartbeat
+/// line when nothing was sent for `heartbeat_secs` (0 = none), so dead
+/// clients are noticed. A subscriber whose write fails is dropped.
+fn start_broadcaster(state: Arc<Mutex<State>>, heartbeat_secs: u32) {
+    thread::spawn(move || {
+        let mut last_status = String::new();
+        let mut last_write = Instant::now();
+        loop {
+            thread::sleep(Duration::from_millis(SUBSCRIBE_POLL_MS));
+            let (status, mut subscribers) = match state.lock() {
+                Ok(mut st) => (st.status(), std::mem::take(&mut st.subscribers)),
+                Err(_) => return,
+            };
+            let key = serde_json::to_string(&status).unwrap_or_default();
+            let changed = key != last_status;
+            last_status = key;
+            let heartbeat = heartbeat_secs > 0 && last_write.elapsed().as_secs() >= heartbeat_secs as u64;
+            let line = if changed {
+                Some(status_response(status))
+            } else if heartbeat {
+                Some(Response::ok("heartbeat"))
+            } else {
+                None
+            };
+            if let (Some(line), false) = (line, subscribers.is_empty()) {
+                let mut text = serde_json::to_string(&line).unwrap_or_default();
+                text.push('\n');
+                subscribers.retain_mut(|stream| stream.write_all(text.as_bytes()).is_ok());
+                last_write = Instant::now();
+            }
+            // Keep anyone who subscribed meanwhile too.
+            if let Ok(mut st) = state.lock() {
+                subscribers.append(&mut st.subscribers);
+                st.subscribers = subscribers;
+            }
+        }
+    });
+}
+
+/// Notices the machine waking from sleep: the monotonic clock stops while
+/// suspended but the wall clock doesn't. On wake the server connections are
+/// rebuilt and the current stream reloaded at its position, since both have
+/// usually gone stale.
+fn start_suspend_watch(state: Arc<Mutex<State>>, client: NavidromeClient, mpv: Arc<MpvController>) {
+    thread::spawn(move || {
+        let mut last = (Instant::now(), SystemTime::now());
+        loop {
+            thread::sleep(Duration::from_secs(SUSPEND_TICK_SECS));
+            let now = (Instant::now(), SystemTime::now());
+            let awake = now.0.duration_since(last.0);
+            let wall = now.1.duration_since(last.1).unwrap_or(awake);
+            last = now;
+            let asleep = wall.saturating_sub(awake);
+            if asleep.as_secs() < SUSPEND_GAP_SECS {
+                continue;
+            }
+            eprintln!("simplay: woke after about {}s asleep; reconnecting", asleep.as_secs());
+            if let Err(err) = client.reconnect() {
+                eprintln!("simplay: reconnect failed: {}", err);
+            }
+            if let Err(err) = reload_stream(&state, &client, &mpv) {
+                eprintln!("simplay: could not reload the stream: {}", err);
+            }
+        }
+    });
+}
+
+/// Loads the current song again from a fresh stream URL at the same
+/// position, keeping it paused if it was. Local files and URLs are left be,
+/// and so is a track mpv no longer has loaded: after the queue ended, a stop
+/// from outside, or the error breaker halting playback.
+fn reload_stream(state: &Arc<Mutex<State>>, client: &NavidromeClient, mpv: &MpvController) -> Result<()> {
+    let (song, paused, loaded_url) = match state.lock() {
+        Ok(st) if !st.external && !st.local_file && !st.breaker_tripped() => {
+            match (st.current.clone(), st.stream_url.clone()) {
+                (Some(song), Some(url)) => (song, st.paused, url),
+                _ => return Ok(()),
+            }
+        }
+        _ => return Ok(()),
+    };
+    if mpv_idle(mpv) {
+        return Ok(());
+    }
+    let path = mpv
+        .get_property("path")?
+        .and_then(|v| v.as_str().map(|s| s.to_string()));
+    if path.as_deref() != Some(loaded_url.as_str()) {
+        return Ok(());
+    }
+    let position = mpv.get_time_pos()?.unwrap_or(0.0);
+    let url = client.stream_url(&song.id)?;
+    if let Ok(mut st) = state.lock() {
+        st.stream_url = Some(url.clone());
+        // Replacing a loaded file emits an end-file we must not advance on.
+        st.suppress_next_end = true;
+        // ... and drops anything appended after it.
+        st.preloaded = None;
+    }
+    if paused {
+        mpv.pause(true)?;
+    }
+    mpv.load(&url)?;
+    if position > 0.0 && !seek_when_loaded(state, mpv, position)? {
+        return Err(anyhow!("Could not load {}", song.title));
+    }
+    preload_next(state, client, mpv);
+    Ok(())
+}
+
+fn record_usage(state: &Arc<Mutex<State>>, cmd: &str) {
+    if let Ok(mut st) = state.lock() {
+        if st.usage_tx.is_none() {
+            return;
+        }
+        *st.usage_counts.entry(cmd.to_string()).or_insert(0) += 1;
+        let snapshot = st.usage_counts.clone();
+        if let Some(tx) = st.usage_tx.as_ref() {
+            let _ = tx.send(snapshot);
+        }
+    }
+}
+
+fn install_signal_handler(
+    state: Arc<Mutex<State>>,
+    mpv: Arc<MpvController>,
+    socket_path: PathBuf,
+    mpv_socket: PathBuf,
+) -> Result<()> {
+    let mut signals = Signals::new([SIGTERM, SIGINT]).context("Failed to install signal handler")?;
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            shutdown(&state, &mpv, &socket_path, &mpv_socket);
+            logfile::finish();
+            std::process::exit(0);
+        }
+    });
+    Ok(())
+}
+
+fn shutdown(state: &Arc<Mutex<State>>, mpv: &MpvController, socket_path: &Path, mpv_socket: &Path) {
+    save_session(state, mpv);
+    finish_position_writes(state);
+    let _ = mpv.stop();
+    mpv.quit();
+    fs::remove_file(socket_path).ok();
+    fs::remove_file(Config::observer_socket_path(socket_path)).ok();
+    fs::remove_file(mpv_socket).ok();
+}
+
+fn save_session(state: &Arc<Mutex<State>>, mpv: &MpvController) {
+    let position = mpv.get_time_pos().ok().flatten().unwrap_or(0.0);
+    remember_album_position(state, position);
+    let saved = match state.lock() {
+        Ok(st) if st.current.is_some() && !st.external => Session {
+            queue: st.queue.clone(),
+            index: st.index,
+            position,
+            repeat: st.repeat,
+            shuffle: st.shuffle,
+        },
+        _ => return,
+    };
+    if let Err(err) = session::save(&saved) {
+        eprintln!("simplay: failed to save session: {}", err);
+    }
+}
+
+/// Restores a saved queue and loads its current track paused at the saved
+/// position.
+fn resume_session(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    saved: Session,
+) -> Result<()> {
+    if saved.queue.is_empty() {
+        return Ok(());
+    }
+    let index = saved.index.min(saved.queue.len() - 1);
+    let song = saved.queue[index].clone();
+    let serial = {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        st.queue = saved.queue;
+        st.queue_serial += 1;
+        st.index = index;
+        st.current = Some(song.clone());
+        st.paused = true;
+        st.repeat = saved.repeat;
+        st.repeats_left = None;
+        st.shuffle = saved.shuffle;
+        st.suppress_next_end = false;
+        st.album_play = None;
+        st.resume_offer = None;
+        st.preloaded = None;
+        st.track_serial += 1;
+        st.track_serial
+    };
 
+    let (url, local) = song_source(state, client, &song)?;
+    if let Ok(mut st) = state.lock() {
+        st.stream_url = Some(url.clone());
+        st.local_file = local;
+    }
+    apply_initial_volume(state, mpv);
+    mpv.pause(true)?;
+    mpv.load(&url)?;
+    if !seek_when_loaded(state, mpv, saved.position)? {
+        return Err(anyhow!("Saved song {} is unavailable", song.title));
+    }
+    if let Some(duration) = song.duration {
+        schedule_end_fallback(
+            state.clone(),
+            client.clone(),
+            mpv.clone(),
+            song.id.clone(),
+            serial,
+            duration,
+            saved.position,
+        );
+    }
+    preload_next(state, client, mpv);
     Ok(())
 }
 
+/// Waits for mpv to finish opening the loaded file, then seeks to
+/// `position`. Returns `false` if the file never became playable.
+fn seek_when_loaded(state: &Arc<Mutex<State>>, mpv: &MpvController, position: f64) -> Result<bool> {
+    let url = state.lock().ok().and_then(|st| st.stream_url.clone());
+    for _ in 0..100 {
+        let path = mpv
+            .get_property("path")
+            .ok()
+            .flatten()
+            .and_then(|v| v.as_str().map(|s| s.to_string()));
+        let time_pos = mpv.get_time_pos().ok().flatten();
+        if file_ready(path.as_deref(), url.as_deref(), time_pos) {
+            if position > 0.0 {
+                mpv.seek_absolute(position)?;
+            }
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(false)
+}
+
+/// Whether mpv has opened `expected` far enough to seek in it. Right after
+/// `loadfile`, `time-pos` can still be the previous file's.
+fn file_ready(path: Option<&str>, expected: Option<&str>, time_pos: Option<f64>) -> bool {
+    time_pos.is_some() && (expected.is_none() || path == expected)
+}
+
 fn start_event_handler(
     state: Arc<Mutex<State>>,
     client: NavidromeClient,
@@ -108,40 +939,75 @@ fn start_event_handler(
     thread::spawn(move || {
         while let Ok(event) = event_rx.recv() {
             match event {
-                MpvEvent::EndFile { reason } => {
+                MpvEvent::EndFile { reason, file_error } => {
                     let reason = reason.unwrap_or_default();
-                    let suppress = {
+                    let (suppress, advance_on_stop) = {
                         if let Ok(mut st) = state.lock() {
+                            let advance_on_stop = st.advance_on_stop;
+                            let after_fallback = std::mem::take(&mut st.grace.fallback_fired);
                             if st.suppress_next_end {
                                 st.suppress_next_end = false;
-                                true
+                                if after_fallback && reason == "eof" {
+                                    st.grace.late_events += 1;
+                                    log_grace_hint(&mut st);
+                                }
+                                (true, advance_on_stop)
                             } else {
-                                false
+                                if reason == "eof" {
+                                    st.grace.events += 1;
+                                }
+                                (false, advance_on_stop)
                             }
                         } else {
-                            false
+                            (false, false)
+                        }
+                    };
+                    let advance = || {
+                        if let Err(err) = play_next(&state, &client, &mpv, false, None) {
+                            eprintln!("simplay: next track failed: {}", err);
                         }
                     };
-                    if suppress {
-                        continue;
-                    }
-                    let should_advance =
-                        reason.is_empty() || matches!(reason.as_str(), "eof" | "stop" | "error");
-                    if reason == "eof" {
-                        let ended = { state.lock().ok().and_then(|s| s.current.clone()) };
-                        if let Some(song) = ended {
-                            let client = client.clone();
-                            let song_id = song.id.clone();
-                            thread::spawn(move || {
-                                if let Err(err) = client.scrobble_submission(&song_id) {
-                                    eprintln!("simplay: scrobble failed: {}", err);
+                    match end_file_action(&reason, suppress, advance_on_stop) {
+                        EndAction::Ignore => {}
+                        EndAction::Finished => {
+                            if let Some(song) = current_song(&state) {
+                                count_completion(&state, &client, &song);
+                                scrobble_song(&state, &client, song);
+                            }
+                            advance();
+                        }
+                        EndAction::Failed => {
+                            record_failure(&state, file_error);
+                            if !trip_error_breaker(&state, &mpv) {
+                                advance();
+                            }
+                        }
+                        EndAction::Advance => advance(),
+                        EndAction::Halt => {
+                            if mpv_idle(&mpv) {
+                                // Stopped from outside: keep the end fallback from
+                                // advancing anyway once the track's time is up.
+                                if let Ok(mut st) = state.lock() {
+                                    st.track_serial += 1;
+                                    st.paused = true;
                                 }
-                            });
+                            }
                         }
                     }
-                    if should_advance {
-                        if let Err(err) = play_next(&state, &client, &mpv, false, None) {
-                            eprintln!("simplay: next track failed: {}", err);
+                }
+                MpvEvent::PropertyChange { name, data } => {
+                    if let Ok(mut st) = state.lock() {
+                        let flag = data.as_bool().unwrap_or(false);
+                        match name.as_str() {
+                            "core-idle" => {
+                                st.core_idle = flag;
+                                // Audio is flowing again, so the last load worked.
+                                if !flag && !st.paused {
+                                    st.consecutive_errors = 0;
+                                }
+                            }
+                            "paused-for-cache" => st.paused_for_cache = flag,
+                            _ => {}
                         }
                     }
                 }
@@ -150,8 +1016,142 @@ fn start_event_handler(
     });
 }
 
+/// Writes `history` as CSV or JSON, chosen by the file extension.
+fn export_history(path: &Path, history: &VecDeque<HistoryEntry>) -> Result<()> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+    let contents = match extension.as_deref() {
+        Some("json") => serde_json::to_string_pretty(history)?,
+        Some("csv") => {
+            let mut out = String::from("played_at,id,title,artist,album\n");
+            for entry in history {
+                out.push_str(&format!(
+                    "{},{},{},{},{}\n",
+                    entry.played_at,
+                    csv_field(&entry.id),
+                    csv_field(&entry.title),
+                    csv_field(&entry.artist),
+                    csv_field(&entry.album)
+                ));
+            }
+            out
+        }
+        _ => return Err(anyhow!("Export path must end in .csv or .json")),
+    };
+    fs::write(path, contents).with_context(|| format!("Failed writing {}", path.display()))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// A failed command's response; Subsonic errors carry their code and a hint
+/// on how to fix them.
+fn error_response(err: impl Into<anyhow::Error>) -> Response {
+    let err = err.into();
+    match err.downcast_ref::<SubsonicError>() {
+        Some(api) => {
+            let message = match api.hint() {
+                Some(hint) => format!("{}: {}", err, hint),
+                None => err.to_string(),
+            };
+            Response::err(message).with_code(api.code())
+        }
+        None => Response::err(err.to_string()),
+    }
+}
+
+/// Notes that the current song failed to load, for `failures`. A song that
+/// fails again moves to the end with its count raised.
+fn record_failure(state: &Arc<Mutex<State>>, file_error: Option<String>) {
+    let mut st = match state.lock() {
+        Ok(st) => st,
+        Err(_) => return,
+    };
+    let song = match st.current.clone() {
+        Some(song) => song,
+        None => return,
+    };
+    let reason = file_error.unwrap_or_else(|| "unknown error".to_string());
+    let count = match st.failures.iter().position(|failure| failure.id == song.id) {
+        Some(i) => st.failures.remove(i).count + 1,
+        None => 1,
+    };
+    st.failures.push(Failure {
+        id: song.id,
+        title: song.title,
+        artist: song.artist,
+        reason,
+        count,
+    });
+    if st.failures.len() > MAX_FAILURES {
+        st.failures.remove(0);
+    }
+}
+
+/// What the daemon does about an mpv end-file event.
+#[derive(Debug, PartialEq)]
+enum EndAction {
+    /// Nothing: our own load or skip replaced the track, or mpv quit.
+    Ignore,
+    /// The track played to the end: count and scrobble it, then advance.
+    Finished,
+    /// The track failed to load: note it and advance unless the error
+    /// breaker trips.
+    Failed,
+    /// Advance without counting the track as played.
+    Advance,
+    /// Stay put: playback was stopped from outside.
+    Halt,
+}
+
+/// Decides how to handle an end-file with `reason`. `suppressed` is set when
+/// a command already moved on (`suppress_next_end`). A `stop` comes from a
+/// command (ours or another mpv client), not the track running out, so it
+/// halts unless `advance_on_stop` is set.
+fn end_file_action(reason: &str, suppressed: bool, advance_on_stop: bool) -> EndAction {
+    if suppressed {
+        return EndAction::Ignore;
+    }
+    match reason {
+        "eof" => EndAction::Finished,
+        "error" => EndAction::Failed,
+        // Old mpv versions send no reason.
+        "" => EndAction::Advance,
+        "stop" if advance_on_stop => EndAction::Advance,
+        "stop" => EndAction::Halt,
+        _ => EndAction::Ignore,
+    }
+}
+
+/// Counts a failed track and, once `max_consecutive_errors` is reached,
+/// pauses instead of advancing. Returns `true` if playback was halted.
+fn trip_error_breaker(state: &Arc<Mutex<State>>, mpv: &MpvController) -> bool {
+    let count = match state.lock() {
+        Ok(mut st) => {
+            st.consecutive_errors += 1;
+            if st.max_consecutive_errors == 0 || st.consecutive_errors < st.max_consecutive_errors {
+                return false;
+            }
+            st.paused = true;
+            st.consecutive_errors
+        }
+        Err(_) => return false,
+    };
+    let _ = mpv.pause(true);
+    eprintln!("simplay: playback halted after {} errors", count);
+    true
+}
+
 fn handle_connection(
     stream: UnixStream,
+    observer: bool,
     state: Arc<Mutex<State>>,
     client: NavidromeClient,
     mpv: Arc<MpvController>,
@@ -159,109 +1159,576 @@ fn handle_connection(
 ) -> Result<()> {
     let mut reader = BufReader::new(stream.try_clone()?);
     let mut line = String::new();
-    reader.read_line(&mut line)?;
+    if reader.read_line(&mut line)? == 0 {
+        // A watchdog probe, or a client that gave up before asking anything.
+        return Ok(());
+    }
 
     let req: crate::protocol::Request = serde_json::from_str(&line)?;
-    let response = handle_command(req, &state, &client, &mpv, &config);
+    let subscribe = req.cmd == "subscribe";
+    let response = if observer && !OBSERVER_COMMANDS.contains(&req.cmd.as_str()) {
+        Response::err(format!("'{}' is not allowed on the observer socket", req.cmd))
+    } else {
+        handle_command(req, observer, &state, &client, &mpv, &config)
+    };
 
+    let subscriber = (subscribe && response.ok).then(|| stream.try_clone()).transpose()?;
     let mut writer = BufWriter::new(stream);
     serde_json::to_writer(&mut writer, &response)?;
     writer.write_all(b"\n")?;
     writer.flush()?;
+    if let Some(subscriber) = subscriber {
+        subscriber.set_write_timeout(Some(Duration::from_secs(SUBSCRIBER_WRITE_TIMEOUT_SECS)))?;
+        if let Ok(mut st) = state.lock() {
+            st.subscribers.push(subscriber);
+        }
+    }
     Ok(())
 }
 
+/// Runs one command. `observer` is set for commands from the observer
+/// socket, which must leave the owner's state alone: they are not counted in
+/// usage stats and `search` does not replace the results `playresult` uses.
 fn handle_command(
     req: crate::protocol::Request,
+    observer: bool,
     state: &Arc<Mutex<State>>,
     client: &NavidromeClient,
     mpv: &Arc<MpvController>,
     config: &Config,
 ) -> Response {
+    if config.kiosk_mode() && !config.kiosk_commands.contains(&req.cmd) {
+        return Response::err(format!("'{}' is disabled in kiosk mode", req.cmd));
+    }
+    if !observer {
+        record_usage(state, &req.cmd);
+    }
+    let req = resolve_default_shuffle(req, config);
     match req.cmd.as_str() {
-        "shuffle" => match shuffle_library(client, config) {
-            Ok(mut songs) => {
+        "shuffle" if walks_library(client, config) => {
+            match shuffle_library_streaming(&req, state, client, mpv, config) {
+                Ok(start) => Response::ok(start.note("Shuffling library".to_string())),
+                Err(err) => error_response(err),
+            }
+        }
+        "shuffle" => match library_shuffle(&req, client, config) {
+            Ok((songs, filtered)) => {
                 if songs.is_empty() {
                     return Response::err("No songs found");
                 }
-                if config.max_shuffle() > 0 && songs.len() > config.max_shuffle() {
-                    songs.shuffle(&mut rand::thread_rng());
-                    songs.truncate(config.max_shuffle());
-                }
-                songs.shuffle(&mut rand::thread_rng());
                 if let Err(err) = set_queue_and_play(state, client, mpv, songs, true, true) {
-                    return Response::err(err.to_string());
+                    return error_response(err);
+                }
+                Response::ok(note_filtered("Shuffling library".to_string(), filtered))
+            }
+            Err(err) => error_response(err),
+        },
+        "shufflekeepalbum" => {
+            let (album_id, mut queue) = match current_album_remainder(state) {
+                Some(found) => found,
+                None => return Response::err("No album playing"),
+            };
+            match library_shuffle(&req, client, config) {
+                Ok((mut songs, filtered)) => {
+                    songs.retain(|song| song.album_id.as_deref() != Some(album_id.as_str()));
+                    let kept = queue.len() - 1;
+                    queue.append(&mut songs);
+                    match state.lock() {
+                        Ok(mut st) => {
+                            st.snapshot_queue();
+                            st.queue = queue;
+                            st.queue_serial += 1;
+                            st.index = 0;
+                            st.repeat = true;
+                            st.repeats_left = None;
+                            st.shuffle = true;
+                        }
+                        Err(_) => return Response::err("State lock poisoned"),
+                    }
+                    Response::ok(note_filtered(
+                        format!("Finishing album ({} tracks left), then shuffling library", kept),
+                        filtered,
+                    ))
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "instantmix" => match instant_mix(state, client, config) {
+            Ok((seed, mix)) => {
+                let added = mix.len();
+                match state.lock() {
+                    Ok(mut st) => {
+                        st.snapshot_queue();
+                        st.queue = std::iter::once(seed.clone()).chain(mix).collect();
+                        st.queue_serial += 1;
+                        st.index = 0;
+                        st.repeat = false;
+                        st.repeats_left = None;
+                        st.shuffle = false;
+                        st.album_play = None;
+                        st.resume_offer = None;
+                    }
+                    Err(_) => return Response::err("State lock poisoned"),
                 }
-                Response::ok("Shuffling library")
+                Response::ok(format!("Instant mix from {}: {} songs queued", seed.title, added))
             }
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
+        },
+        "refreshcache" => match library::rebuild(client) {
+            Ok(songs) => Response::ok(format!("Library cache rebuilt ({} songs)", songs.len())),
+            Err(err) => error_response(err),
         },
         "shuffleartist" => {
-            let name = match req.arg {
+            let name = match req.arg.clone() {
                 Some(arg) if !arg.trim().is_empty() => arg,
                 _ => return Response::err("Artist name required"),
             };
             match shuffle_artist(client, &name) {
                 Ok(mut songs) => {
+                    let filtered = drop_explicit(&mut songs, config);
+                    filter_min_rating(&mut songs, &req);
                     if songs.is_empty() {
                         return Response::err("No songs found for artist");
                     }
-                    songs.shuffle(&mut rand::thread_rng());
+                    songs.shuffle(&mut shuffle_rng(&req));
                     if let Err(err) = set_queue_and_play(state, client, mpv, songs, true, true) {
-                        return Response::err(err.to_string());
+                        return error_response(err);
                     }
-                    Response::ok("Shuffling artist")
+                    Response::ok(note_filtered("Shuffling artist".to_string(), filtered))
                 }
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             }
         }
         "shufflealbum" => {
-            let name = match req.arg {
+            let name = match req.arg.clone() {
                 Some(arg) if !arg.trim().is_empty() => arg,
                 _ => return Response::err("Album name required"),
             };
             match client.find_album(&name) {
                 Ok(Some(album)) => match client.album_songs(&album.id) {
                     Ok(mut songs) => {
+                        let filtered = drop_explicit(&mut songs, config);
+                        filter_min_rating(&mut songs, &req);
                         if songs.is_empty() {
                             return Response::err("No songs found for album");
                         }
-                        songs.shuffle(&mut rand::thread_rng());
+                        songs.shuffle(&mut shuffle_rng(&req));
                         if let Err(err) = set_queue_and_play(state, client, mpv, songs, true, true) {
-                            return Response::err(err.to_string());
+                            return error_response(err);
                         }
-                        Response::ok(format!("Shuffling album {}", album.name))
+                        Response::ok(note_filtered(format!("Shuffling album {}", album.name), filtered))
                     }
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
                 Ok(None) => Response::err("Album not found"),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             }
         }
         "shuffleplaylist" => {
-            let name = match req.arg {
+            let name = match req.arg.clone() {
                 Some(arg) if !arg.trim().is_empty() => arg,
                 _ => return Response::err("Playlist name required"),
             };
             match client.find_playlist(&name) {
-                Ok(Some(list)) => match client.playlist_songs(&list.id) {
+                Ok(Some(list)) => {
+                    match shuffle_playlist_streaming(&req, state, client, mpv, config, &list.id) {
+                        Ok(Some(start)) => {
+                            Response::ok(start.note(format!("Shuffling playlist {}", list.name)))
+                        }
+                        Ok(None) => Response::err("No songs found for playlist"),
+                        Err(err) => error_response(err),
+                    }
+                }
+                Ok(None) => Response::err("Playlist not found"),
+                Err(err) => error_response(err),
+            }
+        }
+        "shareplaylist" => {
+            let name = match req.arg.clone() {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Playlist name or id required"),
+            };
+            let list = match client.list_playlists() {
+                Ok(lists) => lists
+                    .iter()
+                    .find(|list| list.id == name)
+                    .cloned()
+                    .or_else(|| subsonic::best_match(&name, &lists)),
+                Err(err) => return error_response(err),
+            };
+            let list = match list {
+                Some(list) => list,
+                None => return Response::err("Playlist not found"),
+            };
+            match client.create_share(&list.id) {
+                Ok(url) => Response::ok(url),
+                Err(SubsonicError::Unsupported(_)) => {
+                    Response::err("The server does not support shares (or sharing is disabled)")
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "playlistcontents" => {
+            let name = match req.arg.clone() {
+                Some(arg) if !arg.trim().is_empty() => arg,
+                _ => return Response::err("Playlist name required"),
+            };
+            let list = match client.find_playlist(&name) {
+                Ok(Some(list)) => list,
+                Ok(None) => return Response::err("Playlist not found"),
+                Err(err) => return error_response(err),
+            };
+            match client.playlist_songs(&list.id) {
+                Ok(songs) => {
+                    let total = songs.len();
+                    let shown = req.limit.unwrap_or(total).min(total);
+                    let songs: Vec<SongInfo> = songs
+                        .into_iter()
+                        .take(shown)
+                        .map(|song| SongInfo {
+                            id: song.id,
+                            title: song.title,
+                            artist: song.artist,
+                            album: song.album,
+                        })
+                        .collect();
+                    let message = if shown < total {
+                        format!("Playlist {} ({} songs, showing {})", list.name, total, shown)
+                    } else {
+                        format!("Playlist {} ({} songs)", list.name, total)
+                    };
+                    Response::ok(message).with_data(songs)
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "playtrack" => {
+            let (album_name, title) = match req.arg.as_deref().and_then(|arg| arg.split_once("::")) {
+                Some((album, title)) if !album.trim().is_empty() && !title.trim().is_empty() => {
+                    (album.trim().to_string(), title.trim().to_string())
+                }
+                _ => return Response::err("Usage: playtrack <album>::<title>"),
+            };
+            let album = match client.find_album(&album_name) {
+                Ok(Some(album)) => album,
+                Ok(None) => return Response::err("Album not found"),
+                Err(err) => return error_response(err),
+            };
+            let mut songs = match client.album_songs(&album.id) {
+                Ok(songs) if songs.is_empty() => return Response::err("No songs found for album"),
+                Ok(songs) => songs,
+                Err(err) => return error_response(err),
+            };
+            sort_album_songs(&mut songs);
+            let titles: Vec<subsonic::Item> = songs
+                .iter()
+                .map(|song| subsonic::Item {
+                    id: song.id.clone(),
+                    name: song.title.clone(),
+                })
+                .collect();
+            let index = match subsonic::best_match(&title, &titles)
+                .and_then(|item| songs.iter().position(|song| song.id == item.id))
+            {
+                Some(index) => index,
+                None => return Response::err(format!("No track matching '{}' on {}", title, album.name)),
+            };
+            let song_title = songs[index].title.clone();
+            let remember = config.resume_albums_enabled().then(|| album.id.clone());
+            match play_album(state, client, mpv, songs, remember, index, 0.0) {
+                Ok(_) => Response::ok(format!(
+                    "Playing {} (track {} of {}), then the rest of the album",
+                    song_title,
+                    index + 1,
+                    album.name
+                )),
+                Err(err) => error_response(err),
+            }
+        }
+        "playalbum" => {
+            let name = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg,
+                _ => return Response::err("Album name required"),
+            };
+            match client.find_album(&name) {
+                Ok(Some(album)) => match client.album_songs(&album.id) {
                     Ok(mut songs) => {
                         if songs.is_empty() {
-                            return Response::err("No songs found for playlist");
+                            return Response::err("No songs found for album");
                         }
-                        songs.shuffle(&mut rand::thread_rng());
-                        if let Err(err) = set_queue_and_play(state, client, mpv, songs, true, true) {
-                            return Response::err(err.to_string());
+                        sort_album_songs(&mut songs);
+                        let saved = saved_album_position(config, &album.id, &songs);
+                        let (index, position) = match saved {
+                            Some(spot) if config.resume_albums_auto() => spot,
+                            _ => (0, 0.0),
+                        };
+                        let remember = config.resume_albums_enabled().then(|| album.id.clone());
+                        if let Err(err) = play_album(state, client, mpv, songs, remember, index, position) {
+                            return error_response(err);
+                        }
+                        match saved {
+                            Some((index, position)) if config.resume_albums_auto() => Response::ok(format!(
+                                "Resuming album {} at track {} ({})",
+                                album.name,
+                                index + 1,
+                                format_secs(position)
+                            )),
+                            Some((index, position)) => {
+                                if let Ok(mut st) = state.lock() {
+                                    st.resume_offer = Some((index, position));
+                                }
+                                Response::ok(format!(
+                                    "Playing album {} (left off at track {}, {}; use --resume-album to continue)",
+                                    album.name,
+                                    index + 1,
+                                    format_secs(position)
+                                ))
+                            }
+                            None => Response::ok(format!("Playing album {}", album.name)),
                         }
-                        Response::ok(format!("Shuffling playlist {}", list.name))
                     }
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
-                Ok(None) => Response::err("Playlist not found"),
-                Err(err) => Response::err(err.to_string()),
+                Ok(None) => Response::err("Album not found"),
+                Err(err) => error_response(err),
             }
         }
-        "playalbum" => {
+        "shufflerandomalbums" => {
+            let count = match req.arg.as_deref().map(str::trim) {
+                None | Some("") => DEFAULT_RANDOM_ALBUMS,
+                Some(arg) => match arg.parse::<usize>() {
+                    Ok(count) if (1..=MAX_RANDOM_ALBUMS).contains(&count) => count,
+                    _ => {
+                        return Response::err(format!(
+                            "Album count must be 1-{}",
+                            MAX_RANDOM_ALBUMS
+                        ))
+                    }
+                },
+            };
+            let album_ids: Vec<String> = match client.random_albums(count) {
+                Ok(albums) => albums.into_iter().map(|album| album.id).collect(),
+                Err(err) => return error_response(err),
+            };
+            let albums = album_ids.len();
+            match play_album_walk(state, client, mpv, config, album_ids, config.filter_explicit()) {
+                Ok(Some(start)) => Response::ok(start.note(format!("Playing {} random albums", albums))),
+                Ok(None) => Response::err("No albums found"),
+                Err(err) => error_response(err),
+            }
+        }
+        "playartist" => {
+            let name = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg,
+                _ => return Response::err("Artist name required"),
+            };
+            let (artist, album_ids) = match artist_discography(client, &name) {
+                Ok(found) => found,
+                Err(err) => return error_response(err),
+            };
+            match play_album_walk(state, client, mpv, config, album_ids, false) {
+                Ok(Some(_)) => Response::ok(format!("Playing discography of {}", artist)),
+                Ok(None) => Response::err("No songs found for artist"),
+                Err(err) => error_response(err),
+            }
+        }
+        "playyear" => {
+            let year = match req.arg.as_deref().map(str::trim).map(str::parse::<u32>) {
+                Some(Ok(year)) => year,
+                Some(Err(_)) => return Response::err("Year must be a number"),
+                None => return Response::err("Year required"),
+            };
+            let album_ids = match year_albums(client, year) {
+                Ok(album_ids) => album_ids,
+                Err(err) => return error_response(err),
+            };
+            match play_album_walk(state, client, mpv, config, album_ids, false) {
+                Ok(Some(_)) => Response::ok(format!("Playing albums from {}", year)),
+                Ok(None) => Response::err(format!("No albums found for {}", year)),
+                Err(err) => error_response(err),
+            }
+        }
+        "resolve" => {
+            let arg = req.arg.unwrap_or_default();
+            let (kind, query) = match arg.trim().split_once(char::is_whitespace) {
+                Some((kind, query)) if !query.trim().is_empty() => {
+                    (kind.to_lowercase(), query.trim().to_string())
+                }
+                _ => return Response::err("Usage: resolve <album|artist|playlist> <name>"),
+            };
+            let items = match kind.as_str() {
+                "album" => client.search_albums(&query),
+                "artist" => client.search_artists(&query),
+                "playlist" => client.list_playlists(),
+                _ => return Response::err("Type must be album, artist or playlist"),
+            };
+            let items = match items {
+                Ok(items) => items,
+                Err(err) => return error_response(err),
+            };
+            let matches: Vec<ResolvedMatch> = subsonic::ranked_matches(&query, &items)
+                .into_iter()
+                .map(|(item, score)| ResolvedMatch {
+                    id: item.id,
+                    name: item.name,
+                    score,
+                })
+                .collect();
+            let message = match matches.first() {
+                Some(best) => format!("{} ({})", best.name, best.id),
+                None => return Response::err(format!("No {} matches {}", kind, query)),
+            };
+            Response::ok(message).with_data(Resolved { kind, query, matches })
+        }
+        "search" => {
+            let query = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Search query required"),
+            };
+            let results = match client.search(&query, SEARCH_RESULTS) {
+                Ok(results) => results,
+                Err(err) => return error_response(err),
+            };
+            let hits: Vec<SearchHit> = results
+                .artists
+                .into_iter()
+                .map(SearchHit::Artist)
+                .chain(results.albums.into_iter().map(SearchHit::Album))
+                .chain(results.songs.into_iter().map(|song| SearchHit::Song(Box::new(song))))
+                .collect();
+            let count = |n: Option<u32>, what: &str| match n {
+                Some(n) => format!(" ({} {}{})", n, what, if n == 1 { "" } else { "s" }),
+                None => String::new(),
+            };
+            let lines: Vec<String> = hits
+                .iter()
+                .enumerate()
+                .map(|(i, hit)| match hit {
+                    SearchHit::Artist(artist) => {
+                        format!("{:>3}. [artist] {}{}", i + 1, artist.name, count(artist.album_count, "album"))
+                    }
+                    SearchHit::Album(album) => format!(
+                        "{:>3}. [album] {} - {}{}",
+                        i + 1,
+                        album.artist.as_deref().unwrap_or("Unknown Artist"),
+                        album.name,
+                        count(album.song_count, "track")
+                    ),
+                    SearchHit::Song(song) => {
+                        format!("{:>3}. {} - {} ({})", i + 1, song.artist, song.title, song.album)
+                    }
+                })
+                .collect();
+            if !observer {
+                if let Ok(mut st) = state.lock() {
+                    st.last_search = hits;
+                }
+            }
+            if lines.is_empty() {
+                return Response::err(format!("Nothing matches {}", query));
+            }
+            if observer {
+                return Response::ok(format!("{} results for {}", lines.len(), query)).with_data(lines);
+            }
+            Response::ok(format!("{} results for {}; play one with --play-result <n>", lines.len(), query))
+                .with_data(lines)
+        }
+        "playresult" => {
+            let number = match req.arg.as_deref().map(str::trim).map(str::parse::<usize>) {
+                Some(Ok(number)) if number > 0 => number,
+                Some(_) => return Response::err("Result number must be 1 or more"),
+                None => return Response::err("Result number required"),
+            };
+            let hit = match state.lock() {
+                Ok(st) if st.last_search.is_empty() => return Response::err("No search results; run --search first"),
+                Ok(st) => st.last_search.get(number - 1).cloned(),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            match hit {
+                Some(SearchHit::Song(song)) => {
+                    let message = format!("Playing {} - {}", song.artist, song.title);
+                    match set_queue_and_play(state, client, mpv, vec![*song], false, false) {
+                        Ok(_) => Response::ok(message),
+                        Err(err) => error_response(err),
+                    }
+                }
+                Some(SearchHit::Artist(artist)) => {
+                    let album_ids = match artist_album_ids(client, &artist.id) {
+                        Ok(album_ids) => album_ids,
+                        Err(err) => return error_response(err),
+                    };
+                    match play_album_walk(state, client, mpv, config, album_ids, false) {
+                        Ok(Some(_)) => Response::ok(format!("Playing discography of {}", artist.name)),
+                        Ok(None) => Response::err("No songs found for artist"),
+                        Err(err) => error_response(err),
+                    }
+                }
+                Some(SearchHit::Album(album)) => {
+                    let mut songs = match client.album_songs(&album.id) {
+                        Ok(songs) if !songs.is_empty() => songs,
+                        Ok(_) => return Response::err("No songs found for album"),
+                        Err(err) => return error_response(err),
+                    };
+                    sort_album_songs(&mut songs);
+                    match play_album(state, client, mpv, songs, None, 0, 0.0) {
+                        Ok(_) => Response::ok(format!("Playing album {}", album.name)),
+                        Err(err) => error_response(err),
+                    }
+                }
+                None => Response::err(format!("The last search has no result {}", number)),
+            }
+        }
+        "similarartists" => {
+            let query = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg,
+                _ => match current_song(state) {
+                    Some(song) => song.artist,
+                    None => return Response::err("No song playing"),
+                },
+            };
+            match client.find_artist(&query) {
+                Ok(Some(artist)) => match client.similar_artists(&artist.id) {
+                    Ok(similar) => {
+                        if similar.is_empty() {
+                            return Response::ok(format!("No similar artists for {}", artist.name));
+                        }
+                        let names: Vec<String> = similar.into_iter().map(|item| item.name).collect();
+                        Response::ok(format!("Artists similar to {}", artist.name)).with_data(names)
+                    }
+                    Err(err) => error_response(err),
+                },
+                Ok(None) => Response::err("Artist not found"),
+                Err(err) => error_response(err),
+            }
+        }
+        "albuminfo" => {
+            let album_id = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => match client.find_album(&arg) {
+                    Ok(Some(album)) => album.id,
+                    Ok(None) => return Response::err("Album not found"),
+                    Err(err) => return error_response(err),
+                },
+                _ => match current_song(state) {
+                    Some(song) => match song.album_id {
+                        Some(id) => id,
+                        None => return Response::err("Current song has no album"),
+                    },
+                    None => return Response::err("No song playing"),
+                },
+            };
+            match album_details(client, &album_id) {
+                Ok(details) => {
+                    let message = if details.notes.is_none() && details.links.is_empty() {
+                        format!("No album info available for {}", details.name)
+                    } else {
+                        format!("Album info for {}", details.name)
+                    };
+                    Response::ok(message).with_data(details)
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "queuealbumnext" => {
             let name = match req.arg {
                 Some(arg) if !arg.trim().is_empty() => arg,
                 _ => return Response::err("Album name required"),
@@ -272,35 +1739,467 @@ fn handle_command(
                         if songs.is_empty() {
                             return Response::err("No songs found for album");
                         }
-                        songs.sort_by_key(|song| (song.disc.unwrap_or(0), song.track.unwrap_or(0)));
-                        if let Err(err) = set_queue_and_play(state, client, mpv, songs, false, false) {
-                            return Response::err(err.to_string());
+                        sort_album_songs(&mut songs);
+                        match queue_group_next(state, client, mpv, songs) {
+                            Ok(_) => Response::ok(format!("Queued album {} next", album.name)),
+                            Err(err) => error_response(err),
                         }
-                        Response::ok(format!("Playing album {}", album.name))
                     }
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
                 Ok(None) => Response::err("Album not found"),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
+            }
+        }
+        "finishalbum" => {
+            let (song, serial) = match state.lock() {
+                Ok(st) if !st.external => match st.current.clone() {
+                    Some(song) => (song, st.queue_serial),
+                    None => return Response::err("No song playing"),
+                },
+                Ok(_) => return Response::err("No song playing"),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            match album_remainder(client, &song) {
+                Ok(rest) if rest.is_empty() => {
+                    Response::ok(format!("{} is the last track of {}", song.title, song.album))
+                }
+                Ok(rest) => {
+                    let count = rest.len();
+                    if append_if_current(state, serial, rest, None) {
+                        Response::ok(format!(
+                            "Queued the remaining {} tracks of {}",
+                            count, song.album
+                        ))
+                    } else {
+                        Response::err("The queue changed while loading the album")
+                    }
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "playurl" => {
+            let url = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("URL required"),
+            };
+            match play_external(state, mpv, &url) {
+                Ok(_) => Response::ok(format!("Playing external URL {}", url)),
+                Err(err) => error_response(err),
             }
         }
         "fastforward" => match play_next(state, client, mpv, true, None) {
             Ok(_) => Response::ok("Next track"),
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
+        },
+        "rewind" => {
+            // Past the threshold, "previous" restarts the current track.
+            let threshold = config.prev_restart_threshold_secs();
+            if threshold > 0 && current_song(state).is_some() {
+                if let Ok(Some(pos)) = mpv.get_time_pos() {
+                    if pos > threshold as f64 {
+                        return match mpv.seek_absolute(0.0) {
+                            Ok(_) => Response::ok("Restarted track"),
+                            Err(err) => error_response(err),
+                        };
+                    }
+                }
+            }
+            match play_previous(state, client, mpv, true) {
+                Ok(_) => Response::ok("Previous track"),
+                Err(err) => error_response(err),
+            }
+        }
+        "restartqueue" => match restart_queue(state, client, mpv) {
+            Ok(_) => Response::ok("Restarted queue"),
+            Err(err) => error_response(err),
+        },
+        "bookmark" => {
+            let name = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Bookmark name required"),
+            };
+            let song = match current_song(state) {
+                Some(song) => song,
+                None => return Response::err("No song playing"),
+            };
+            let position = mpv.get_time_pos().ok().flatten().unwrap_or(0.0);
+            match client.create_bookmark(&song.id, (position * 1000.0) as u64, &name) {
+                Ok(_) => Response::ok(format!("Bookmarked {} at {}s", name, position as u64)),
+                Err(err) => error_response(err),
+            }
+        }
+        "gotobookmark" => {
+            let name = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Bookmark name required"),
+            };
+            let bookmarks = match client.get_bookmarks() {
+                Ok(bookmarks) => bookmarks,
+                Err(err) => return error_response(err),
+            };
+            let bookmark = match bookmarks
+                .into_iter()
+                .find(|b| b.comment.trim().eq_ignore_ascii_case(&name))
+            {
+                Some(bookmark) => bookmark,
+                None => return Response::err(format!("No bookmark named {}", name)),
+            };
+            let position = bookmark.position_ms as f64 / 1000.0;
+            let title = bookmark.song.title.clone();
+            if let Err(err) = set_queue_and_play(state, client, mpv, vec![bookmark.song], false, false) {
+                return error_response(err);
+            }
+            match seek_when_loaded(state, mpv, position) {
+                Ok(true) => Response::ok(format!("Jumped to {} ({} at {}s)", name, title, position as u64)),
+                Ok(false) => Response::err(format!("Could not load {}", title)),
+                Err(err) => error_response(err),
+            }
+        }
+        "dedupe" => match state.lock() {
+            Ok(mut st) => {
+                let removed = dedupe_queue(&mut st);
+                Response::ok(format!("Removed {} duplicate songs", removed))
+            }
+            Err(_) => Response::err("State lock poisoned"),
         },
-        "rewind" => match play_previous(state, client, mpv, true) {
-            Ok(_) => Response::ok("Previous track"),
-            Err(err) => Response::err(err.to_string()),
+        "groupbyartist" => match state.lock() {
+            Ok(mut st) => {
+                if st.queue.is_empty() {
+                    return Response::err("Queue is empty");
+                }
+                let index = st.index;
+                let artists = group_by_artist(&mut st.queue, index);
+                Response::ok(format!("Grouped upcoming songs into {} artists", artists))
+            }
+            Err(_) => Response::err("State lock poisoned"),
         },
+        "requeuecurrent" => {
+            let target = match req.arg.as_deref().map(str::trim) {
+                Some("end") => None,
+                Some(arg) => match arg.parse::<usize>() {
+                    Ok(position) if position > 0 => Some(position - 1),
+                    _ => return Response::err("Position must be a number from 1, or end"),
+                },
+                None => return Response::err("Position required (a number, or end)"),
+            };
+            match state.lock() {
+                Ok(mut st) => {
+                    if st.external || st.current.is_none() || st.queue.is_empty() {
+                        return Response::err("No song playing");
+                    }
+                    let index = st.index;
+                    let index = requeue_current(&mut st.queue, index, target);
+                    st.index = index;
+                    Response::ok(format!(
+                        "Moved the current song to queue position {} of {}",
+                        index + 1,
+                        st.queue.len()
+                    ))
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "sortbyrating" => {
+            let (sorted, unrated) = match state.lock() {
+                Ok(mut st) => {
+                    if st.queue.is_empty() {
+                        return Response::err("Queue is empty");
+                    }
+                    let index = st.index;
+                    // Background loaders would append unsorted songs after
+                    // the sort; this stops them.
+                    st.queue_serial += 1;
+                    sort_by_rating(&mut st.queue, index)
+                }
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            // The preloaded track may no longer be next.
+            drop_preload(state, mpv);
+            preload_next(state, client, mpv);
+            match unrated {
+                0 => Response::ok(format!("Sorted {} upcoming songs by rating", sorted)),
+                _ => Response::ok(format!(
+                    "Sorted {} upcoming songs by rating; {} have no rating and go last",
+                    sorted, unrated
+                )),
+            }
+        }
+        "albumgoto" => {
+            let name = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg,
+                _ => return Response::err("Album name required"),
+            };
+            let album = match client.find_album(&name) {
+                Ok(Some(album)) => album,
+                Ok(None) => return Response::err("No album found"),
+                Err(err) => return error_response(err),
+            };
+            let queued = match state.lock() {
+                Ok(st) => st
+                    .queue
+                    .iter()
+                    .position(|song| song.album_id.as_deref() == Some(album.id.as_str())),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            if let Some(index) = queued {
+                return match jump_to(state, client, mpv, index, 0.0) {
+                    Ok(_) => Response::ok(format!(
+                        "Jumped to {} at queue position {}; the rest of the queue is unchanged",
+                        album.name,
+                        index + 1
+                    )),
+                    Err(err) => error_response(err),
+                };
+            }
+            let mut songs = match client.album_songs(&album.id) {
+                Ok(songs) if !songs.is_empty() => songs,
+                Ok(_) => return Response::err("No songs found for album"),
+                Err(err) => return error_response(err),
+            };
+            sort_album_songs(&mut songs);
+            match play_album(state, client, mpv, songs, None, 0, 0.0) {
+                Ok(_) => Response::ok(format!("{} is not in the queue; playing it instead", album.name)),
+                Err(err) => error_response(err),
+            }
+        }
+        "gototrack" => {
+            let number = match req.arg.as_deref().map(str::trim).map(str::parse::<u32>) {
+                Some(Ok(number)) => number,
+                Some(Err(_)) => return Response::err("Track number must be a number"),
+                None => return Response::err("Track number required"),
+            };
+            let found = match state.lock() {
+                Ok(st) => album_track_index(&st, number),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let index = match found {
+                Ok(index) => index,
+                Err(err) => return error_response(err),
+            };
+            match jump_to(state, client, mpv, index, 0.0) {
+                Ok(_) => match current_song(state) {
+                    Some(song) => Response::ok(format!("Playing track {}: {}", number, song.title)),
+                    None => Response::ok(format!("Playing track {}", number)),
+                },
+                Err(err) => error_response(err),
+            }
+        }
+        "undo" => {
+            let index = match state.lock() {
+                Ok(mut st) => match st.undo.take() {
+                    Some(snapshot) => {
+                        st.queue = snapshot.queue;
+                        st.queue_serial += 1;
+                        st.repeat = snapshot.repeat;
+                        st.repeats_left = None;
+                        st.shuffle = snapshot.shuffle;
+                        st.album_play = None;
+                        st.resume_offer = None;
+                        snapshot.index
+                    }
+                    None => return Response::err("Nothing to undo"),
+                },
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            match jump_to(state, client, mpv, index, 0.0) {
+                Ok(_) => match current_song(state) {
+                    Some(song) => Response::ok(format!("Restored the previous queue at {}", song.title)),
+                    None => Response::ok("Restored the previous queue"),
+                },
+                Err(err) => error_response(err),
+            }
+        }
+        "transition" => {
+            let mode = req.arg.as_deref().map(str::trim).unwrap_or("").to_lowercase();
+            if mode == "crossfade" {
+                // mpv plays one file at a time, so two overlapping tracks
+                // would need a second player mixed with the first.
+                return Response::err(
+                    "crossfade needs two tracks playing at once, which one mpv can't do; use gapless, gap or none",
+                );
+            }
+            if !mode.is_empty() && !TRANSITIONS.contains(&mode.as_str()) {
+                return Response::err(format!(
+                    "Unknown transition '{}'; use {}",
+                    mode,
+                    TRANSITIONS.join(", ")
+                ));
+            }
+            let (previous, leaves_gapless) = match state.lock() {
+                Ok(st) if mode.is_empty() => {
+                    return Response::ok(format!("Transition: {}", st.transition));
+                }
+                Ok(mut st) => {
+                    let previous = std::mem::replace(&mut st.transition, mode.clone());
+                    (previous, mode != "gapless")
+                }
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            if (previous == "gapless") != (mode == "gapless") {
+                if let Err(err) = mpv.set_gapless(mode == "gapless") {
+                    return Response::err(format!("Transition: {}, but mpv refused: {}", mode, err));
+                }
+            }
+            if leaves_gapless {
+                drop_preload(state, mpv);
+            }
+            preload_next(state, client, mpv);
+            Response::ok(format!("Transition: {} (was {})", mode, previous))
+        }
+        "nextdisc" | "prevdisc" => {
+            let found = match state.lock() {
+                Ok(st) => album_disc_index(&st, req.cmd == "nextdisc"),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let index = match found {
+                Ok(index) => index,
+                Err(err) => return error_response(err),
+            };
+            match jump_to(state, client, mpv, index, 0.0) {
+                Ok(_) => match current_song(state) {
+                    Some(song) => Response::ok(format!(
+                        "Playing disc {}: {}",
+                        song.disc.unwrap_or(1),
+                        song.title
+                    )),
+                    None => Response::ok("Playing disc"),
+                },
+                Err(err) => error_response(err),
+            }
+        }
+        "randomtrack" => {
+            let picked = match state.lock() {
+                Ok(st) => {
+                    let others: Vec<usize> =
+                        (0..st.queue.len()).filter(|&i| st.current.is_none() || i != st.index).collect();
+                    others.choose(&mut rand::thread_rng()).copied()
+                }
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let index = match picked {
+                Some(index) => index,
+                None => return Response::err("No other track in the queue"),
+            };
+            match jump_to(state, client, mpv, index, 0.0) {
+                Ok(_) => match current_song(state) {
+                    Some(song) => Response::ok(format!("Playing {} - {}", song.artist, song.title)),
+                    None => Response::ok("Playing random track"),
+                },
+                Err(err) => error_response(err),
+            }
+        }
+        "resumealbum" => {
+            let (offer, queue) = match state.lock() {
+                Ok(mut st) => (st.resume_offer.take(), st.queue.clone()),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let (index, position) = match offer {
+                Some(offer) => offer,
+                None => return Response::err("No saved album position to resume"),
+            };
+            let song = match queue.get(index) {
+                Some(song) => song.clone(),
+                None => return Response::err("Queue changed; saved position no longer applies"),
+            };
+            if let Err(err) = jump_to(state, client, mpv, index, position) {
+                return error_response(err);
+            }
+            Response::ok(format!("Resumed {} at {}", song.title, format_secs(position)))
+        }
+        "repeatcount" => {
+            let count = match req.arg.as_deref().map(str::trim).map(str::parse::<u32>) {
+                Some(Ok(count)) => count,
+                Some(Err(_)) => return Response::err("Repeat count must be a number"),
+                None => return Response::err("Repeat count required"),
+            };
+            match state.lock() {
+                Ok(mut st) => {
+                    st.start_repeat_count(count);
+                    match count {
+                        0 => Response::ok("Repeat count cleared"),
+                        _ => Response::ok(format!("Playing the queue {} times", count)),
+                    }
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "exporthistory" => {
+            let path = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => PathBuf::from(arg.trim()),
+                _ => return Response::err("Export path required"),
+            };
+            let history = match state.lock() {
+                Ok(st) => st.history.clone(),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            match export_history(&path, &history) {
+                Ok(_) => Response::ok(format!(
+                    "Exported {} plays to {}",
+                    history.len(),
+                    path.display()
+                )),
+                Err(err) => error_response(err),
+            }
+        }
+        "pauseafter" => {
+            let holding = match state.lock() {
+                Ok(mut st) => {
+                    if st.current.is_none() {
+                        return Response::err("No song playing");
+                    }
+                    if st.pause_after_current == Some(st.track_serial) {
+                        st.pause_after_current = None;
+                        false
+                    } else {
+                        st.pause_after_current = Some(st.track_serial);
+                        true
+                    }
+                }
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            if holding {
+                // mpv would start a preloaded track by itself, past the hold.
+                drop_preload(state, mpv);
+                Response::ok("Will pause after this track")
+            } else {
+                preload_next(state, client, mpv);
+                Response::ok("Will keep playing after this track")
+            }
+        }
         "pause" => match mpv.pause(true) {
             Ok(_) => {
                 if let Ok(mut st) = state.lock() {
                     st.paused = true;
                 }
+                let position = mpv.get_time_pos().ok().flatten().unwrap_or(0.0);
+                remember_album_position(state, position);
                 Response::ok("Paused")
             }
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
         },
+        "resumeaftererror" => {
+            let song = match state.lock() {
+                Ok(mut st) if st.breaker_tripped() => {
+                    st.consecutive_errors = 0;
+                    st.paused = false;
+                    // mpv is idle after the failed load, so no end event is coming.
+                    st.suppress_next_end = false;
+                    st.current.clone()
+                }
+                Ok(_) => return Response::err("Playback was not halted by errors"),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let song = match song {
+                Some(song) => song,
+                None => return Response::ok("Error count reset"),
+            };
+            match play_song(state, client, mpv, &song) {
+                Ok(_) => Response::ok(format!("Retrying {} - {}", song.artist, song.title)),
+                Err(err) => error_response(err),
+            }
+        }
+        "softpause" => soft_pause(state, mpv, config),
         "play" => match mpv.pause(false) {
             Ok(_) => {
                 if let Ok(mut st) = state.lock() {
@@ -308,26 +2207,73 @@ fn handle_command(
                 }
                 Response::ok("Playing")
             }
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
         },
+        "seek" => {
+            let arg = match req.arg.as_deref().map(str::trim) {
+                Some(arg) if !arg.is_empty() => arg.to_string(),
+                _ => return Response::err("Seek offset required (e.g. +30, -10 or 90)"),
+            };
+            let secs = match arg.parse::<f64>() {
+                Ok(secs) if secs.is_finite() => secs,
+                _ => return Response::err("Seek offset must be seconds, e.g. +30, -10 or 90"),
+            };
+            if current_song(state).is_none() {
+                return Response::err("No song playing");
+            }
+            let target = if arg.starts_with('+') || arg.starts_with('-') {
+                match mpv.get_time_pos() {
+                    Ok(Some(pos)) => chapter_bounded_seek(pos, secs, &chapter_starts(mpv)),
+                    Ok(None) => return Response::err("No song playing"),
+                    Err(err) => return error_response(err),
+                }
+            } else {
+                secs.max(0.0)
+            };
+            match mpv.seek_absolute(target) {
+                Ok(_) => Response::ok(format!("Seeked to {}", format_secs(target))),
+                Err(err) => error_response(err),
+            }
+        }
         "startover" => match mpv.seek_absolute(0.0) {
             Ok(_) => Response::ok("Restarted"),
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
         },
         "likesong" => match current_song(state) {
             Some(song) => match client.star_song(&song.id) {
                 Ok(_) => Response::ok("Hearted song"),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             },
             None => Response::err("No song playing"),
         },
         "unlikesong" => match current_song(state) {
             Some(song) => match client.unstar_song(&song.id) {
                 Ok(_) => Response::ok("Unhearted song"),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             },
             None => Response::err("No song playing"),
         },
+        "love" => match current_song(state) {
+            Some(song) => {
+                let rating = config.love_rating().clamp(1, 5);
+                let starred = client.star_song(&song.id);
+                let rated = client.set_rating(&song.id, rating);
+                match (starred, rated) {
+                    (Ok(_), Ok(_)) => Response::ok(format!("Hearted and rated song {}", rating)),
+                    (Err(err), Ok(_)) => {
+                        Response::err(format!("Rated song {} but heart failed: {}", rating, err))
+                    }
+                    (Ok(_), Err(err)) => {
+                        Response::err(format!("Hearted song but rating failed: {}", err))
+                    }
+                    (Err(star_err), Err(rate_err)) => Response::err(format!(
+                        "Heart failed: {}; rating failed: {}",
+                        star_err, rate_err
+                    )),
+                }
+            }
+            None => Response::err("No song playing"),
+        },
         "rate" => {
             let rating = match req.arg {
                 Some(arg) => match arg.trim().parse::<u8>() {
@@ -339,32 +2285,134 @@ fn handle_command(
             match current_song(state) {
                 Some(song) => match client.set_rating(&song.id, rating) {
                     Ok(_) => Response::ok(format!("Rated song {}", rating)),
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
                 None => Response::err("No song playing"),
             }
         }
         "shuffleliked" => match client.starred_songs() {
             Ok(mut songs) => {
+                apply_shuffle_excludes(&mut songs, config);
+                let filtered = drop_explicit(&mut songs, config);
+                skip_short_tracks(&mut songs, min_track_secs(&req, config));
+                filter_min_rating(&mut songs, &req);
                 if songs.is_empty() {
                     return Response::err("No liked songs found");
                 }
+                let mut rng = shuffle_rng(&req);
                 if config.max_shuffle() > 0 && songs.len() > config.max_shuffle() {
-                    songs.shuffle(&mut rand::thread_rng());
+                    songs.shuffle(&mut rng);
                     songs.truncate(config.max_shuffle());
                 }
-                songs.shuffle(&mut rand::thread_rng());
+                songs.shuffle(&mut rng);
                 if let Err(err) = set_queue_and_play(state, client, mpv, songs, true, true) {
-                    return Response::err(err.to_string());
+                    return error_response(err);
                 }
-                Response::ok("Shuffling liked songs")
+                Response::ok(note_filtered("Shuffling liked songs".to_string(), filtered))
             }
-            Err(err) => Response::err(err.to_string()),
+            Err(err) => error_response(err),
         },
-        "volumeup" => adjust_volume(mpv, config.volume_step() as i32),
-        "volumedown" => adjust_volume(mpv, -(config.volume_step() as i32)),
-        "addsongtoplaylist" => {
-            let playlist_name = match req.arg {
+        "normalize" => {
+            let enabled = match req.arg.as_deref().map(str::trim) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Response::err("Normalize expects on or off"),
+            };
+            match mpv.set_dynamic_normalization(enabled) {
+                Ok(_) => {
+                    if let Ok(mut st) = state.lock() {
+                        st.normalize = enabled;
+                    }
+                    Response::ok(if enabled {
+                        "Normalization on"
+                    } else {
+                        "Normalization off"
+                    })
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "localplayback" => {
+            let enabled = match req.arg.as_deref().map(str::trim) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Response::err("Local playback expects on or off"),
+            };
+            match state.lock() {
+                Ok(_) if enabled && config.local_library_root.is_none() => {
+                    Response::err("Set local_library_root to play local files")
+                }
+                Ok(mut st) => {
+                    st.local_playback = enabled;
+                    Response::ok(if enabled {
+                        "Local playback on (from the next track)"
+                    } else {
+                        "Local playback off (from the next track)"
+                    })
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "scrobbleonskip" => {
+            let enabled = match req.arg.as_deref().map(str::trim) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Response::err("Scrobble on skip expects on or off"),
+            };
+            match state.lock() {
+                Ok(mut st) => {
+                    st.scrobble_on_skip = enabled;
+                    Response::ok(if enabled {
+                        "Skips past the scrobble point are scrobbled"
+                    } else {
+                        "Skips are not scrobbled"
+                    })
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "announce" => {
+            let enabled = match req.arg.as_deref().map(str::trim) {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Response::err("Announce expects on or off"),
+            };
+            match state.lock() {
+                Ok(mut st) => {
+                    st.announce = enabled;
+                    Response::ok(if enabled { "Announcements on" } else { "Announcements off" })
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "audiodevices" => match mpv.audio_devices() {
+            Ok(devices) => {
+                let active = mpv.audio_device().ok().flatten();
+                let lines: Vec<String> = devices
+                    .into_iter()
+                    .map(|(name, description)| {
+                        let marker = if active.as_deref() == Some(name.as_str()) { "*" } else { " " };
+                        format!("{} {}: {}", marker, name, description)
+                    })
+                    .collect();
+                Response::ok("Audio devices").with_data(lines)
+            }
+            Err(err) => error_response(err),
+        },
+        "audiodevice" => {
+            let query = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Audio device name required"),
+            };
+            match switch_audio_device(state, mpv, config, &query) {
+                Ok(message) => Response::ok(message),
+                Err(err) => error_response(err),
+            }
+        }
+        "volumeup" => adjust_volume(mpv, config, config.volume_step() as i32),
+        "volumedown" => adjust_volume(mpv, config, -(config.volume_step() as i32)),
+        "addsongtoplaylist" => {
+            let playlist_name = match req.arg {
                 Some(arg) if !arg.trim().is_empty() => arg,
                 _ => return Response::err("Playlist name required"),
             };
@@ -375,13 +2423,13 @@ fn handle_command(
             match client.find_playlist(&playlist_name) {
                 Ok(Some(playlist)) => match client.add_song_to_playlist(&playlist.id, &song.id) {
                     Ok(_) => Response::ok(format!("Added to playlist {}", playlist.name)),
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
                 Ok(None) => match client.create_playlist_with_song(&playlist_name, &song.id) {
                     Ok(_) => Response::ok(format!("Created playlist {}", playlist_name)),
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             }
         }
         "deleteplaylist" => {
@@ -392,34 +2440,537 @@ fn handle_command(
             match client.find_playlist(&playlist_name) {
                 Ok(Some(playlist)) => match client.delete_playlist(&playlist.id) {
                     Ok(_) => Response::ok(format!("Deleted playlist {}", playlist.name)),
-                    Err(err) => Response::err(err.to_string()),
+                    Err(err) => error_response(err),
                 },
                 Ok(None) => Response::err("Playlist not found"),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             }
         }
         "status" => {
-            let status = state.lock().map(|s| s.status()).unwrap_or(Status {
+            let mut status = state.lock().map(|s| s.status()).unwrap_or(Status {
                 song: None,
                 paused: false,
+                buffering: false,
+                source: None,
+                normalize: false,
+                folder: None,
                 queue_len: 0,
                 index: 0,
+                pause_after: false,
+                repeats_left: None,
+                position: None,
+                ends_in_secs: None,
+                gapless: false,
+                chapter: None,
+                chapter_title: None,
+                consecutive_errors: 0,
+                halted_by_errors: false,
+                transition: "none".to_string(),
             });
-            Response {
-                ok: true,
-                message: "ok".to_string(),
-                status: Some(status),
+            status.folder = client.music_folder().map(|folder| folder.name);
+            if status.song.is_some() {
+                status.position = mpv.get_time_pos().ok().flatten();
+                let duration = current_song(state).and_then(|song| song.duration);
+                if let (false, Some(duration), Some(position)) = (status.paused, duration, status.position) {
+                    status.ends_in_secs = Some((duration as f64 - position).max(0.0).round() as u64);
+                }
+                status.gapless = status.gapless && next_preloaded(mpv);
+                if let Some((chapter, title)) = current_chapter(mpv) {
+                    status.chapter = Some(chapter);
+                    status.chapter_title = title;
+                }
+            }
+            status_response(status)
+        }
+        "subscribe" => match state.lock() {
+            Ok(st) => {
+                let mut response = status_response(st.status());
+                response.message = "subscribed".to_string();
+                response
+            }
+            Err(_) => Response::err("State lock poisoned"),
+        },
+        "songinfo" => match current_song(state) {
+            Some(song) => match client.get_song(&song.id) {
+                Ok(detail) => {
+                    Response::ok(format!("{} - {}", detail.artist, detail.title))
+                        .with_data(song_details(&detail))
+                }
+                Err(err) => error_response(err),
+            },
+            None => Response::err("No song playing"),
+        },
+        "diagnose" => match diagnose(mpv) {
+            Ok(diagnosis) => Response::ok(format!("Playback: {}", diagnosis.verdict)).with_data(diagnosis),
+            Err(err) => error_response(err),
+        },
+        "trackinfo" => match track_info(mpv) {
+            Ok(Some(info)) => {
+                let format = match (info.file_format.as_deref(), info.codec_name.as_deref()) {
+                    (Some(container), Some(codec)) => format!("{} in {}", codec, container),
+                    (None, Some(codec)) => codec.to_string(),
+                    (Some(container), None) => container.to_string(),
+                    (None, None) => "unknown format".to_string(),
+                };
+                Response::ok(format!("Track info: {}", format)).with_data(info)
+            }
+            Ok(None) => Response::err("No track loaded"),
+            Err(err) => error_response(err),
+        },
+        "streamurl" => {
+            let url = state.lock().ok().and_then(|s| s.stream_url.clone());
+            match url {
+                Some(url) if config.redact_stream_url() => Response::ok(subsonic::redact_auth(&url)),
+                Some(url) => Response::ok(url),
+                None => Response::err("No stream loaded"),
+            }
+        }
+        "listfolders" => match client.music_folders() {
+            Ok(folders) => {
+                let active = client.music_folder().map(|folder| folder.id);
+                let lines: Vec<String> = folders
+                    .into_iter()
+                    .map(|folder| {
+                        let marker = if active.as_deref() == Some(folder.id.as_str()) {
+                            "*"
+                        } else {
+                            " "
+                        };
+                        format!("{} {}: {}", marker, folder.id, folder.name)
+                    })
+                    .collect();
+                Response::ok("Music folders").with_data(lines)
+            }
+            Err(err) => error_response(err),
+        },
+        "failures" => {
+            let (lines, halted) = match state.lock() {
+                Ok(st) => {
+                    let lines: Vec<String> = st
+                        .failures
+                        .iter()
+                        .map(|failure| {
+                            format!(
+                                "{:>4}x  {} - {} ({}): {}",
+                                failure.count,
+                                failure.artist,
+                                failure.title,
+                                failure.id,
+                                failure.reason
+                            )
+                        })
+                        .collect();
+                    (lines, st.breaker_tripped().then_some(st.consecutive_errors))
+                }
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let mut message = match lines.len() {
+                0 => "No tracks failed to load this session".to_string(),
+                1 => "1 track failed to load this session".to_string(),
+                n => format!("{} tracks failed to load this session", n),
+            };
+            if let Some(count) = halted {
+                message.push_str(&format!("; playback halted after {} in a row", count));
+            }
+            Response::ok(message).with_data(lines)
+        }
+        "clearfailures" => match state.lock() {
+            Ok(mut st) => {
+                let count = st.failures.len();
+                st.failures.clear();
+                Response::ok(format!("Cleared {} failed tracks", count))
+            }
+            Err(_) => Response::err("State lock poisoned"),
+        },
+        "usagestats" => {
+            let counts = match state.lock() {
+                Ok(st) if st.usage_tx.is_some() => st.usage_counts.clone(),
+                Ok(_) => return Response::err("Usage stats are off; set usage_stats = true in config"),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let mut counts: Vec<(String, u64)> = counts.into_iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            let lines: Vec<String> = counts
+                .into_iter()
+                .map(|(cmd, count)| format!("{:>6}  {}", count, cmd))
+                .collect();
+            Response::ok("Command usage").with_data(lines)
+        }
+        "setfolder" => {
+            let query = match req.arg {
+                Some(arg) if !arg.trim().is_empty() => arg.trim().to_string(),
+                _ => return Response::err("Folder name or id required"),
+            };
+            if query.eq_ignore_ascii_case("all") {
+                client.set_music_folder(None);
+                return Response::ok("Using all music folders");
+            }
+            match client.music_folders() {
+                Ok(folders) => {
+                    let folder = folders
+                        .iter()
+                        .find(|folder| folder.id == query)
+                        .cloned()
+                        .or_else(|| subsonic::best_match(&query, &folders));
+                    match folder {
+                        Some(folder) => {
+                            let message = format!("Using music folder {}", folder.name);
+                            client.set_music_folder(Some(folder));
+                            Response::ok(message)
+                        }
+                        None => Response::err("Music folder not found"),
+                    }
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "grace" => {
+            let ms = match req.arg.as_deref().map(str::trim).map(str::parse::<u64>) {
+                Some(Ok(ms)) => ms,
+                Some(Err(_)) => return Response::err("Grace must be a number of milliseconds"),
+                None => return Response::err("Grace in milliseconds required"),
+            };
+            match state.lock() {
+                Ok(mut st) => {
+                    st.end_grace_ms = ms;
+                    Response::ok(format!("End grace set to {} ms until the daemon restarts", ms))
+                }
+                Err(_) => Response::err("State lock poisoned"),
+            }
+        }
+        "gracestats" => match state.lock() {
+            Ok(st) => {
+                let mut message = format!(
+                    "End grace {} ms: {} ends reported by mpv, {} caught by the fallback, {} reported after the fallback",
+                    st.end_grace_ms, st.grace.events, st.grace.fallbacks, st.grace.late_events
+                );
+                if let Some(hint) = st.grace.suggestion(st.end_grace_ms) {
+                    message = format!("{}\n{}", message, hint);
+                }
+                Response::ok(message)
             }
+            Err(_) => Response::err("State lock poisoned"),
+        },
+        "prefetch" => {
+            let next = match state.lock() {
+                Ok(st) => peek_next(&st),
+                Err(_) => return Response::err("State lock poisoned"),
+            };
+            let song = match next {
+                Some(song) => song,
+                None => return Response::ok("Nothing to prefetch: no known next track"),
+            };
+            let started = Instant::now();
+            match client.warm_stream(&song.id, PREFETCH_BYTES) {
+                Ok(bytes) => {
+                    let elapsed = started.elapsed().as_millis();
+                    eprintln!("simplay: prefetched {} ({} bytes in {} ms)", song.title, bytes, elapsed);
+                    Response::ok(format!("Prefetched {} - {} in {} ms", song.artist, song.title, elapsed))
+                }
+                Err(err) => error_response(err),
+            }
+        }
+        "rescan" => match client.start_scan() {
+            Ok(status) => Response::ok(describe_scan("Library scan started", &status)),
+            Err(SubsonicError::Unsupported(_)) => Response::err("The server does not support startScan"),
+            Err(err) => error_response(err),
+        },
+        "scanstatus" => match client.scan_status() {
+            Ok(status) if status.scanning => Response::ok(describe_scan("Scanning", &status)),
+            Ok(status) => Response::ok(describe_scan("Not scanning", &status)),
+            Err(SubsonicError::Unsupported(_)) => Response::err("The server does not support getScanStatus"),
+            Err(err) => error_response(err),
+        },
+        "health" => {
+            let uptime_secs = state
+                .lock()
+                .map(|s| s.started.elapsed().as_secs())
+                .unwrap_or(0);
+            let mpv_alive = mpv.is_alive();
+            let health = Health {
+                uptime_secs,
+                mpv_alive,
+                mpv_pid: mpv.pid(),
+                client: config.client_id(),
+                volume_min: config.volume_min(),
+                volume_max: config.volume_max(),
+                mpv_version: mpv.version().map(|version| version.to_string()),
+            };
+            let message = if mpv_alive { "healthy" } else { "mpv is not running" };
+            Response::ok(message).with_data(health)
+        }
+        _ => match closest_command(&req.cmd) {
+            Some(known) => Response::err(format!("Unknown command {}; did you mean {}?", req.cmd, known)),
+            None => Response::err("Unknown command"),
+        },
+    }
+}
+
+/// The known command nearest to `cmd` by edit distance, if it is close
+/// enough to be a plausible typo.
+fn closest_command(cmd: &str) -> Option<&'static str> {
+    let cmd = cmd.trim().to_lowercase();
+    let max_distance = (cmd.chars().count() / 3).max(1);
+    COMMANDS
+        .iter()
+        .map(|known| (*known, edit_distance(&cmd, known)))
+        .filter(|(_, distance)| *distance <= max_distance)
+        .min_by_key(|(_, distance)| *distance)
+        .map(|(known, _)| known)
+}
+
+/// Levenshtein distance between `a` and `b`, by characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitute = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitute.min(previous + 1).min(row[j] + 1);
         }
-        _ => Response::err("Unknown command"),
     }
+    row[b.len()]
 }
 
 fn shuffle_library(client: &NavidromeClient, config: &Config) -> Result<Vec<Song>> {
     if config.max_shuffle() == 0 {
-        client.all_songs()
+        // The cache holds the whole library, so it can't serve a single folder.
+        if config.library_cache() && client.music_folder().is_none() {
+            library::songs(client)
+        } else {
+            Ok(client.all_songs()?)
+        }
+    } else {
+        Ok(client.get_random_songs(config.max_shuffle())?)
+    }
+}
+
+/// Library songs for a shuffle queue, filtered and capped per config and
+/// already shuffled, plus the number of explicit songs dropped.
+fn library_shuffle(
+    req: &crate::protocol::Request,
+    client: &NavidromeClient,
+    config: &Config,
+) -> Result<(Vec<Song>, usize)> {
+    let mut songs = shuffle_library(client, config)?;
+    let filtered = apply_shuffle_filters(&mut songs, req, config);
+    let mut rng = shuffle_rng(req);
+    if config.max_shuffle() > 0 && songs.len() > config.max_shuffle() {
+        songs.shuffle(&mut rng);
+        songs.truncate(config.max_shuffle());
+    }
+    songs.shuffle(&mut rng);
+    Ok((songs, filtered))
+}
+
+/// A bare `shuffle` becomes the `default_shuffle` target: liked songs, or
+/// the playlist of that name. `library` leaves it as the library shuffle.
+fn resolve_default_shuffle(
+    mut req: crate::protocol::Request,
+    config: &Config,
+) -> crate::protocol::Request {
+    if req.cmd != "shuffle" || req.arg.is_some() {
+        return req;
+    }
+    let target = config.default_shuffle.as_str();
+    if target.eq_ignore_ascii_case("liked") {
+        req.cmd = "shuffleliked".to_string();
+    } else if !target.eq_ignore_ascii_case("library") {
+        req.cmd = "shuffleplaylist".to_string();
+        req.arg = Some(target.to_string());
+    }
+    req
+}
+
+/// The RNG for a shuffle: seeded from the request when it has a seed, so
+/// the same songs and seed give the same order, otherwise random.
+fn shuffle_rng(req: &crate::protocol::Request) -> StdRng {
+    match req.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+/// The config and per-request filters every library shuffle applies.
+/// Returns the number of explicit songs dropped.
+fn apply_shuffle_filters(songs: &mut Vec<Song>, req: &crate::protocol::Request, config: &Config) -> usize {
+    apply_shuffle_excludes(songs, config);
+    let filtered = drop_explicit(songs, config);
+    skip_short_tracks(songs, min_track_secs(req, config));
+    filter_min_rating(songs, req);
+    filtered
+}
+
+/// The current song plus the queued tracks right after it from the same
+/// album, or `None` when the current song has no album.
+fn current_album_remainder(state: &Arc<Mutex<State>>) -> Option<(String, Vec<Song>)> {
+    let st = state.lock().ok()?;
+    if st.external {
+        return None;
+    }
+    let current = st.current.clone()?;
+    let album_id = current.album_id.clone()?;
+    let mut songs = vec![current];
+    songs.extend(
+        st.queue
+            .iter()
+            .skip(st.index + 1)
+            .take_while(|song| song.album_id.as_deref() == Some(album_id.as_str()))
+            .cloned(),
+    );
+    Some((album_id, songs))
+}
+
+/// Builds a mix around the current song from similar songs, the same artist
+/// and the same genre, weighted by the `instant_mix_*` settings. Returns the
+/// seed and the shuffled mix, which never repeats a song.
+fn instant_mix(state: &Arc<Mutex<State>>, client: &NavidromeClient, config: &Config) -> Result<(Song, Vec<Song>)> {
+    let seed = match state.lock() {
+        Ok(st) if !st.external => st.current.clone(),
+        Ok(_) => None,
+        Err(_) => return Err(anyhow!("State lock poisoned")),
+    }
+    .ok_or_else(|| anyhow!("No song playing"))?;
+    let weights = [
+        config.instant_mix_similar(),
+        config.instant_mix_artist(),
+        config.instant_mix_genre(),
+    ];
+    let total: u32 = weights.iter().sum();
+    if total == 0 || config.instant_mix_size() == 0 {
+        return Err(anyhow!("instant_mix_size and at least one instant_mix weight must be above 0"));
+    }
+    let [similar, artist, genre] = weights.map(|w| config.instant_mix_size() * w as usize / total as usize);
+    let mut rng = rand::thread_rng();
+    let mut sources: Vec<Vec<Song>> = Vec::new();
+
+    if similar > 0 {
+        match client.similar_songs(&seed.id, similar) {
+            Ok(songs) => sources.push(songs),
+            Err(err) => eprintln!("simplay: instant mix: similar songs unavailable: {}", err),
+        }
+    }
+    if let Some(artist_id) = seed.artist_id.as_deref().filter(|_| artist > 0) {
+        match client.artist_albums(artist_id) {
+            Ok(mut albums) => {
+                albums.shuffle(&mut rng);
+                let mut songs = Vec::new();
+                for album in albums {
+                    if songs.len() >= artist * 2 {
+                        break;
+                    }
+                    match client.album_songs(&album.id) {
+                        Ok(album_songs) => songs.extend(album_songs),
+                        Err(err) => eprintln!("simplay: instant mix: album {} unavailable: {}", album.name, err),
+                    }
+                }
+                songs.shuffle(&mut rng);
+                songs.truncate(artist);
+                sources.push(songs);
+            }
+            Err(err) => eprintln!("simplay: instant mix: artist albums unavailable: {}", err),
+        }
+    }
+    if let Some(name) = seed.genre.as_deref().filter(|_| genre > 0) {
+        match client.songs_by_genre(name, genre * 4) {
+            Ok(mut songs) => {
+                songs.shuffle(&mut rng);
+                songs.truncate(genre);
+                sources.push(songs);
+            }
+            Err(err) => eprintln!("simplay: instant mix: genre songs unavailable: {}", err),
+        }
+    }
+
+    let mut seen = HashSet::from([seed.id.clone()]);
+    let mut mix: Vec<Song> = sources
+        .into_iter()
+        .flatten()
+        .filter(|song| seen.insert(song.id.clone()))
+        .collect();
+    drop_explicit(&mut mix, config);
+    if mix.is_empty() {
+        return Err(anyhow!("Nothing found to mix with {}", seed.title));
+    }
+    mix.shuffle(&mut rng);
+    Ok((seed, mix))
+}
+
+fn min_track_secs(req: &crate::protocol::Request, config: &Config) -> u32 {
+    req.min_track_secs.unwrap_or(config.min_track_secs())
+}
+
+/// Applies the request's `--min-rating`; unrated songs only stay with
+/// `--include-unrated`.
+fn filter_min_rating(songs: &mut Vec<Song>, req: &crate::protocol::Request) {
+    let min = match req.min_rating {
+        Some(min) => min,
+        None => return,
+    };
+    songs.retain(|song| match song.user_rating {
+        Some(rating) => rating >= min,
+        None => req.include_unrated,
+    });
+}
+
+fn skip_short_tracks(songs: &mut Vec<Song>, min_secs: u32) {
+    if min_secs == 0 {
+        return;
+    }
+    // Songs with unknown duration are kept.
+    songs.retain(|song| song.duration.map(|d| d >= min_secs).unwrap_or(true));
+}
+
+fn apply_shuffle_excludes(songs: &mut Vec<Song>, config: &Config) {
+    let genres: Vec<String> = config
+        .shuffle_exclude_genres
+        .iter()
+        .map(|g| g.trim().to_lowercase())
+        .collect();
+    let artists: Vec<String> = config
+        .shuffle_exclude_artists
+        .iter()
+        .map(|a| a.trim().to_lowercase())
+        .collect();
+    let albums: Vec<String> = config
+        .shuffle_exclude_albums
+        .iter()
+        .map(|a| a.trim().to_lowercase())
+        .collect();
+    if genres.is_empty() && artists.is_empty() && albums.is_empty() {
+        return;
+    }
+    songs.retain(|song| {
+        let genre_excluded = song
+            .genre
+            .as_deref()
+            .map(|g| genres.contains(&g.trim().to_lowercase()))
+            .unwrap_or(false);
+        let artist_excluded = artists.contains(&song.artist.trim().to_lowercase());
+        let album_excluded = albums.contains(&song.album.trim().to_lowercase());
+        !genre_excluded && !artist_excluded && !album_excluded
+    });
+}
+
+/// Drops songs the server flags explicit when `filter_explicit` is set and
+/// returns how many were removed. Songs without the flag are kept.
+fn drop_explicit(songs: &mut Vec<Song>, config: &Config) -> usize {
+    if !config.filter_explicit() {
+        return 0;
+    }
+    let before = songs.len();
+    songs.retain(|song| song.explicit != Some(true));
+    before - songs.len()
+}
+
+fn note_filtered(message: String, filtered: usize) -> String {
+    if filtered == 0 {
+        message
     } else {
-        client.get_random_songs(config.max_shuffle())
+        format!("{} ({} explicit tracks filtered)", message, filtered)
     }
 }
 
@@ -427,15 +2978,384 @@ fn shuffle_artist(client: &NavidromeClient, query: &str) -> Result<Vec<Song>> {
     let artist = client
         .find_artist(query)?
         .ok_or_else(|| anyhow!("Artist not found"))?;
-    let album_ids = client.artist_album_ids(&artist.id)?;
+    let albums = client.artist_albums(&artist.id)?;
     let mut songs = Vec::new();
-    for album_id in album_ids {
-        let mut album_songs = client.album_songs(&album_id)?;
+    for album in albums {
+        let mut album_songs = client.album_songs(&album.id)?;
         songs.append(&mut album_songs);
     }
     Ok(songs)
 }
 
+/// An artist's name and album ids, oldest first (undated last).
+fn artist_discography(client: &NavidromeClient, query: &str) -> Result<(String, Vec<String>)> {
+    let artist = client
+        .find_artist(query)?
+        .ok_or_else(|| anyhow!("Artist not found"))?;
+    Ok((artist.name, artist_album_ids(client, &artist.id)?))
+}
+
+/// The artist's album ids, oldest first; undated albums go last.
+fn artist_album_ids(client: &NavidromeClient, artist_id: &str) -> Result<Vec<String>> {
+    let mut albums = client.artist_albums(artist_id)?;
+    albums.sort_by(|a, b| {
+        (a.year.is_none(), a.year, a.name.to_lowercase())
+            .cmp(&(b.year.is_none(), b.year, b.name.to_lowercase()))
+    });
+    Ok(albums.into_iter().map(|album| album.id).collect())
+}
+
+fn song_details(song: &Song) -> SongDetails {
+    SongDetails {
+        id: song.id.clone(),
+        title: song.title.clone(),
+        artist: song.artist.clone(),
+        album: song.album.clone(),
+        genre: song.genre.clone(),
+        year: song.year,
+        track: song.track,
+        disc: song.disc,
+        duration: song.duration,
+        starred: song.starred,
+        rating: song.user_rating,
+        play_count: song.play_count,
+        bit_rate: song.bit_rate,
+        format: song.suffix.clone(),
+        content_type: song.content_type.clone(),
+        path: song.path.clone(),
+        cover_art: song.cover_art.clone(),
+        track_gain: song.track_gain,
+        album_gain: song.album_gain,
+    }
+}
+
+fn album_details(client: &NavidromeClient, album_id: &str) -> Result<AlbumDetails> {
+    let album = client
+        .get_album(album_id)?
+        .ok_or_else(|| anyhow!("Album not found"))?;
+    // Servers without external metadata agents may reject getAlbumInfo2.
+    let info = client.album_info(album_id).unwrap_or_default();
+    let mut links = Vec::new();
+    if let Some(url) = info.lastfm_url {
+        links.push(url);
+    }
+    if let Some(mbid) = info.musicbrainz_id {
+        links.push(format!("https://musicbrainz.org/release/{}", mbid));
+    }
+    Ok(AlbumDetails {
+        name: album.name,
+        artist: album.artist,
+        year: album.year,
+        notes: info.notes,
+        links,
+    })
+}
+
+/// Album ids from `year`, by artist then name.
+fn year_albums(client: &NavidromeClient, year: u32) -> Result<Vec<String>> {
+    let mut albums = client.albums_by_year(year, year)?;
+    albums.sort_by(|a, b| {
+        (a.artist.to_lowercase(), a.name.to_lowercase())
+            .cmp(&(b.artist.to_lowercase(), b.name.to_lowercase()))
+    });
+    Ok(albums.into_iter().map(|album| album.id).collect())
+}
+
+/// Plays albums in order, starting as soon as the first one with songs is
+/// fetched; the rest are fetched and appended in the background unless the
+/// queue is replaced first. Returns `None` if no album had any songs.
+fn play_album_walk(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    config: &Config,
+    album_ids: Vec<String>,
+    filter_explicit: bool,
+) -> Result<Option<StreamStart>> {
+    let mut album_ids = album_ids.into_iter();
+    let mut first = Vec::new();
+    let mut filtered = 0;
+    for album_id in album_ids.by_ref() {
+        let (songs, dropped) = walked_album_songs(client, config, &album_id, filter_explicit)?;
+        first = songs;
+        filtered += dropped;
+        if !first.is_empty() {
+            break;
+        }
+    }
+    if first.is_empty() {
+        return Ok(None);
+    }
+    set_queue_and_play(state, client, mpv, first, false, false)?;
+    let rest: Vec<String> = album_ids.collect();
+    if rest.is_empty() {
+        return Ok(Some(StreamStart { filtered, loading: false }));
+    }
+    let serial = state
+        .lock()
+        .map_err(|_| anyhow!("State lock poisoned"))?
+        .queue_serial;
+    let state = state.clone();
+    let client = client.clone();
+    let config = config.clone();
+    thread::spawn(move || {
+        for album_id in rest {
+            match walked_album_songs(&client, &config, &album_id, filter_explicit) {
+                Ok((songs, _)) => {
+                    if !append_if_current(&state, serial, songs, None) {
+                        return;
+                    }
+                }
+                Err(err) => eprintln!("simplay: loading album {} failed: {}", album_id, err),
+            }
+        }
+    });
+    Ok(Some(StreamStart { filtered, loading: true }))
+}
+
+/// An album's songs in track order, and how many explicit ones were dropped.
+fn walked_album_songs(
+    client: &NavidromeClient,
+    config: &Config,
+    album_id: &str,
+    filter_explicit: bool,
+) -> Result<(Vec<Song>, usize)> {
+    let mut songs = client.album_songs(album_id)?;
+    sort_album_songs(&mut songs);
+    let filtered = if filter_explicit { drop_explicit(&mut songs, config) } else { 0 };
+    Ok((songs, filtered))
+}
+
+/// How a queue that keeps loading in the background started.
+struct StreamStart {
+    /// Explicit songs dropped from what was loaded before playback started.
+    filtered: usize,
+    /// More songs are still being fetched and appended.
+    loading: bool,
+}
+
+impl StreamStart {
+    /// `message` with the background load and the explicit songs dropped so
+    /// far, which is all the reply can know about.
+    fn note(&self, message: String) -> String {
+        match (self.loading, self.filtered) {
+            (false, filtered) => note_filtered(message, filtered),
+            (true, 0) => format!("{} (loading the rest in the background)", message),
+            (true, filtered) => format!(
+                "{} (loading the rest in the background; {} explicit tracks filtered so far)",
+                message, filtered
+            ),
+        }
+    }
+}
+
+/// Whether a library shuffle has to walk every album (no cap, no cache).
+fn walks_library(client: &NavidromeClient, config: &Config) -> bool {
+    config.max_shuffle() == 0 && !(config.library_cache() && client.music_folder().is_none())
+}
+
+/// Starts a library shuffle with a random album's songs, then walks the rest
+/// of the library in the background, shuffling each album into the tracks
+/// still to play as it arrives.
+fn shuffle_library_streaming(
+    req: &crate::protocol::Request,
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    config: &Config,
+) -> Result<StreamStart> {
+    let mut rng = shuffle_rng(req);
+    let mut album_ids = client.album_ids()?;
+    album_ids.shuffle(&mut rng);
+    let mut first = Vec::new();
+    let mut filtered = 0;
+    while first.is_empty() {
+        let album_id = album_ids.pop().ok_or_else(|| anyhow!("No songs found"))?;
+        let mut songs = client.album_songs(&album_id)?;
+        filtered += apply_shuffle_filters(&mut songs, req, config);
+        songs.shuffle(&mut rng);
+        first = songs;
+    }
+    set_queue_and_play(state, client, mpv, first, true, true)?;
+    if album_ids.is_empty() {
+        return Ok(StreamStart { filtered, loading: false });
+    }
+    let serial = state
+        .lock()
+        .map_err(|_| anyhow!("State lock poisoned"))?
+        .queue_serial;
+    let req = req.clone();
+    let state = state.clone();
+    let client = client.clone();
+    let config = config.clone();
+    thread::spawn(move || {
+        for album_id in album_ids {
+            match client.album_songs(&album_id) {
+                Ok(mut songs) => {
+                    apply_shuffle_filters(&mut songs, &req, &config);
+                    if !append_if_current(&state, serial, songs, Some(&mut rng)) {
+                        return;
+                    }
+                }
+                Err(err) => eprintln!("simplay: loading album {} failed: {}", album_id, err),
+            }
+        }
+    });
+    Ok(StreamStart { filtered, loading: true })
+}
+
+/// Shuffles a playlist while `getPlaylist` is still downloading: playback
+/// starts with the first `PLAYLIST_BATCH` entries shuffled, and later
+/// entries are shuffled into the tracks still to play as they are parsed.
+/// Returns `None` if the playlist had no playable songs.
+fn shuffle_playlist_streaming(
+    req: &crate::protocol::Request,
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    config: &Config,
+    playlist_id: &str,
+) -> Result<Option<StreamStart>> {
+    let (tx, rx) = mpsc::channel::<Result<Vec<Song>>>();
+    {
+        let client = client.clone();
+        let playlist_id = playlist_id.to_string();
+        thread::spawn(move || {
+            // Sending fails once the loader below has given up on the queue.
+            let read = client.stream_playlist_songs(&playlist_id, PLAYLIST_BATCH, |songs| {
+                tx.send(Ok(songs)).is_ok()
+            });
+            if let Err(err) = read {
+                let _ = tx.send(Err(err.into()));
+            }
+        });
+    }
+    let mut rng = shuffle_rng(req);
+    let mut first = Vec::new();
+    let mut filtered = 0;
+    while first.is_empty() {
+        match rx.recv() {
+            Ok(songs) => {
+                first = songs?;
+                filtered += apply_playlist_filters(&mut first, req, config);
+            }
+            // The whole playlist was read without a playable song.
+            Err(_) => return Ok(None),
+        }
+    }
+    first.shuffle(&mut rng);
+    set_queue_and_play(state, client, mpv, first, true, true)?;
+    // Anything already read goes to the loader with the rest.
+    let early = match rx.try_recv() {
+        Ok(songs) => Some(songs),
+        Err(mpsc::TryRecvError::Empty) => None,
+        Err(mpsc::TryRecvError::Disconnected) => {
+            return Ok(Some(StreamStart { filtered, loading: false }));
+        }
+    };
+    let serial = state
+        .lock()
+        .map_err(|_| anyhow!("State lock poisoned"))?
+        .queue_serial;
+    let req = req.clone();
+    let state = state.clone();
+    let config = config.clone();
+    thread::spawn(move || {
+        for songs in early.into_iter().chain(rx) {
+            match songs {
+                Ok(mut songs) => {
+                    apply_playlist_filters(&mut songs, &req, &config);
+                    if !append_if_current(&state, serial, songs, Some(&mut rng)) {
+                        return;
+                    }
+                }
+                Err(err) => eprintln!("simplay: loading the playlist failed: {}", err),
+            }
+        }
+    });
+    Ok(Some(StreamStart { filtered, loading: true }))
+}
+
+/// The filters `shuffleplaylist` applies. Returns the number of explicit
+/// songs dropped.
+fn apply_playlist_filters(songs: &mut Vec<Song>, req: &crate::protocol::Request, config: &Config) -> usize {
+    let filtered = drop_explicit(songs, config);
+    filter_min_rating(songs, req);
+    filtered
+}
+
+/// Appends `songs` to the queue if it is still the one identified by
+/// `serial`; with `rng`, shuffled in among the tracks still to play.
+/// Returns `false` once the queue has been replaced.
+fn append_if_current(
+    state: &Arc<Mutex<State>>,
+    serial: u64,
+    songs: Vec<Song>,
+    rng: Option<&mut StdRng>,
+) -> bool {
+    match state.lock() {
+        Ok(mut st) if st.queue_serial == serial => {
+            match rng {
+                Some(rng) => {
+                    // Past a preloaded track too, so mpv's next entry stays
+                    // the queue's.
+                    let from = st.index + 1 + usize::from(st.preloaded.is_some());
+                    shuffle_into(&mut st.queue, from, songs, rng);
+                }
+                None => st.queue.extend(songs),
+            }
+            if st.auto_dedupe {
+                dedupe_queue(&mut st);
+            }
+            evict_played(&mut st);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Adds `songs` to `queue`, each at a random spot from `from` on, keeping
+/// that part of the queue evenly shuffled; what comes before stays put.
+fn shuffle_into(queue: &mut Vec<Song>, from: usize, songs: Vec<Song>, rng: &mut impl Rng) {
+    for song in songs {
+        queue.push(song);
+        let last = queue.len() - 1;
+        queue.swap(last, rng.gen_range(from.min(last)..=last));
+    }
+}
+
+/// Orders songs by disc then track. Songs without a track number go after the
+/// numbered ones on their disc, keeping server order among themselves.
+fn sort_album_songs(songs: &mut [Song]) {
+    songs.sort_by_key(|song| {
+        (
+            song.disc.unwrap_or(0),
+            song.track.is_none(),
+            song.track.unwrap_or(0),
+        )
+    });
+}
+
+/// The tracks of `song`'s album after it, in disc and track order. The
+/// album id comes from the song, or from the server when the queued copy
+/// lacks it.
+fn album_remainder(client: &NavidromeClient, song: &Song) -> Result<Vec<Song>> {
+    let album_id = match song.album_id.clone() {
+        Some(id) => id,
+        None => client
+            .get_song(&song.id)?
+            .album_id
+            .ok_or_else(|| anyhow!("Could not determine the album of {}", song.title))?,
+    };
+    let mut songs = client.album_songs(&album_id)?;
+    sort_album_songs(&mut songs);
+    let position = songs
+        .iter()
+        .position(|track| track.id == song.id)
+        .ok_or_else(|| anyhow!("{} is not on its album's track list", song.title))?;
+    Ok(songs.split_off(position + 1))
+}
+
 fn set_queue_and_play(
     state: &Arc<Mutex<State>>,
     client: &NavidromeClient,
@@ -450,26 +3370,425 @@ fn set_queue_and_play(
     let first = songs[0].clone();
     {
         let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        st.snapshot_queue();
         st.queue = songs;
+        st.queue_serial += 1;
+        if st.auto_dedupe {
+            st.index = 0;
+            dedupe_queue(&mut st);
+        }
         st.index = 0;
         st.current = Some(first.clone());
         st.paused = false;
         st.repeat = repeat;
+        st.repeats_left = None;
         st.shuffle = shuffle;
         st.suppress_next_end = false;
+        st.album_play = None;
+        st.resume_offer = None;
     }
+    apply_initial_volume(state, mpv);
     play_song(state, client, mpv, &first)?;
     Ok(())
 }
 
-fn play_next(
+/// Sets `initial_volume` before the first queue starts, so a restarted
+/// daemon doesn't come back at whatever level mpv was last left.
+fn apply_initial_volume(state: &Arc<Mutex<State>>, mpv: &MpvController) {
+    let volume = match state.lock() {
+        Ok(mut st) => st.initial_volume.take(),
+        Err(_) => return,
+    };
+    if let Some(volume) = volume {
+        if let Err(err) = mpv.set_volume(volume as f64) {
+            eprintln!("simplay: failed to set initial volume: {}", err);
+        }
+    }
+}
+
+/// Replaces the queue with an album and starts it at `index`/`position`.
+/// With `remember` set, the position in that album is saved as it plays.
+fn play_album(
     state: &Arc<Mutex<State>>,
     client: &NavidromeClient,
     mpv: &Arc<MpvController>,
-    manual: bool,
-    expected_id: Option<&str>,
+    songs: Vec<Song>,
+    remember: Option<String>,
+    index: usize,
+    position: f64,
 ) -> Result<()> {
-    let next = {
+    let song = songs
+        .get(index)
+        .cloned()
+        .ok_or_else(|| anyhow!("No song at album position {}", index + 1))?;
+    {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        st.snapshot_queue();
+        st.queue = songs;
+        st.queue_serial += 1;
+        st.index = index;
+        st.current = Some(song.clone());
+        st.paused = false;
+        st.repeat = false;
+        st.repeats_left = None;
+        st.shuffle = false;
+        st.suppress_next_end = false;
+        st.album_play = remember;
+        st.resume_offer = None;
+    }
+    apply_initial_volume(state, mpv);
+    play_song(state, client, mpv, &song)?;
+    if position > 0.0 && !seek_when_loaded(state, mpv, position)? {
+        return Err(anyhow!("Could not load {}", song.title));
+    }
+    Ok(())
+}
+
+/// Plays the queued song at `index` from `position` seconds.
+fn jump_to(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    index: usize,
+    position: f64,
+) -> Result<()> {
+    let song = {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        let song = st
+            .queue
+            .get(index)
+            .cloned()
+            .ok_or_else(|| anyhow!("No song at queue position {}", index + 1))?;
+        st.index = index;
+        st.current = Some(song.clone());
+        st.paused = false;
+        st.suppress_next_end = true;
+        song
+    };
+    play_song(state, client, mpv, &song)?;
+    if position > 0.0 && !seek_when_loaded(state, mpv, position)? {
+        return Err(anyhow!("Could not load {}", song.title));
+    }
+    Ok(())
+}
+
+/// Queue index of track `number` within the contiguous block of the current
+/// album, preferring the current disc.
+fn album_track_index(st: &State, number: u32) -> Result<usize> {
+    let (current, start, end) = current_album_block(st)?;
+    let matches: Vec<usize> = (start..end)
+        .filter(|&i| st.queue[i].track == Some(number))
+        .collect();
+    matches
+        .iter()
+        .copied()
+        .find(|&i| st.queue[i].disc == current.disc)
+        .or_else(|| matches.first().copied())
+        .ok_or_else(|| anyhow!("No track {} queued from {}", number, current.album))
+}
+
+/// Queue index of the first track of the next (or previous) disc within the
+/// contiguous block of the current album.
+fn album_disc_index(st: &State, forward: bool) -> Result<usize> {
+    let (current, start, end) = current_album_block(st)?;
+    let queue = &st.queue;
+    if queue[start..end].iter().all(|song| song.disc == current.disc) {
+        return Err(anyhow!("{} has a single disc in the queue", current.album));
+    }
+    if forward {
+        return (st.index + 1..end)
+            .find(|&i| queue[i].disc != current.disc)
+            .ok_or_else(|| anyhow!("Already on the last disc"));
+    }
+    // The last track before the current disc began belongs to the previous one.
+    let previous = (start..st.index)
+        .rev()
+        .find(|&i| queue[i].disc != current.disc)
+        .ok_or_else(|| anyhow!("Already on the first disc"))?;
+    let disc = queue[previous].disc;
+    Ok((start..previous)
+        .rev()
+        .find(|&i| queue[i].disc != disc)
+        .map(|i| i + 1)
+        .unwrap_or(start))
+}
+
+/// The current song and the `start..end` range of the queue holding the
+/// contiguous block of its album.
+fn current_album_block(st: &State) -> Result<(&Song, usize, usize)> {
+    let current = match st.current.as_ref() {
+        Some(song) if !st.external => song,
+        _ => return Err(anyhow!("No song playing")),
+    };
+    let album_id = current
+        .album_id
+        .as_deref()
+        .ok_or_else(|| anyhow!("Current song has no album"))?;
+    let same_album = |song: &Song| song.album_id.as_deref() == Some(album_id);
+    let start = st.queue[..st.index]
+        .iter()
+        .rposition(|song| !same_album(song))
+        .map(|i| i + 1)
+        .unwrap_or(0);
+    let end = st.queue[st.index..]
+        .iter()
+        .position(|song| !same_album(song))
+        .map(|i| st.index + i)
+        .unwrap_or(st.queue.len());
+    Ok((current, start, end))
+}
+
+/// The queue index and position saved for `album_id`, if it is worth
+/// offering (i.e. not the very start of the album).
+fn saved_album_position(config: &Config, album_id: &str, songs: &[Song]) -> Option<(usize, f64)> {
+    if !config.resume_albums_enabled() {
+        return None;
+    }
+    let saved = match session::album_position(album_id) {
+        Ok(saved) => saved?,
+        Err(err) => {
+            eprintln!("simplay: failed to read album positions: {}", err);
+            return None;
+        }
+    };
+    let index = songs.iter().position(|song| song.id == saved.song_id)?;
+    if index == 0 && saved.position < 5.0 {
+        return None;
+    }
+    Some((index, saved.position))
+}
+
+/// Records the current song and `position` for the album being played with
+/// `playalbum`; songs from other albums are ignored.
+fn remember_album_position(state: &Arc<Mutex<State>>, position: f64) {
+    if let Ok(st) = state.lock() {
+        if let (Some(album_id), Some(song)) = (st.album_play.clone(), st.current.as_ref()) {
+            if song.album_id.as_deref() == Some(album_id.as_str()) {
+                let song_id = song.id.clone();
+                st.change_album_position(album_id, Some(AlbumPosition { song_id, position }));
+            }
+        }
+    }
+}
+
+/// Starts the thread behind `State::change_album_position`. A burst of
+/// changes becomes one write, keeping the newest change per album.
+fn start_position_writer(state: &Arc<Mutex<State>>) {
+    let (tx, rx) = mpsc::channel::<(String, Option<AlbumPosition>)>();
+    let thread = thread::spawn(move || {
+        while let Ok(first) = rx.recv() {
+            let mut changes = HashMap::from([first]);
+            while let Ok((album_id, change)) = rx.try_recv() {
+                changes.insert(album_id, change);
+            }
+            if let Err(err) = session::update_album_positions(changes) {
+                eprintln!("simplay: failed to save album positions: {}", err);
+            }
+        }
+    });
+    if let Ok(mut st) = state.lock() {
+        st.position_writer = Some(PositionWriter { tx, thread });
+    }
+}
+
+/// Waits for album position changes still queued, before the daemon exits.
+fn finish_position_writes(state: &Arc<Mutex<State>>) {
+    let writer = match state.lock() {
+        Ok(mut st) => st.position_writer.take(),
+        Err(_) => return,
+    };
+    if let Some(PositionWriter { tx, thread }) = writer {
+        drop(tx);
+        let _ = thread.join();
+    }
+}
+
+fn format_secs(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+/// Inserts `songs` right after the current track as one group that queue
+/// reshuffles keep together. Starts playback if nothing is queued.
+fn queue_group_next(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    mut songs: Vec<Song>,
+) -> Result<()> {
+    {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        if !st.queue.is_empty() {
+            let group = st.next_group;
+            st.next_group += 1;
+            for song in songs.iter_mut() {
+                song.queue_group = Some(group);
+            }
+            let at = (st.index + 1).min(st.queue.len());
+            st.queue.splice(at..at, songs);
+            if st.auto_dedupe {
+                dedupe_queue(&mut st);
+            }
+            evict_played(&mut st);
+            return Ok(());
+        }
+    }
+    set_queue_and_play(state, client, mpv, songs, false, false)
+}
+
+fn describe_scan(what: &str, status: &subsonic::ScanStatus) -> String {
+    match status.count {
+        Some(count) => format!("{} ({} files scanned)", what, count),
+        None => what.to_string(),
+    }
+}
+
+/// The track `play_next` would move to, without moving. `None` at the end
+/// of the queue, and when a shuffled repeat would reshuffle first.
+fn peek_next(st: &State) -> Option<Song> {
+    if st.external {
+        return None;
+    }
+    if st.index + 1 < st.queue.len() {
+        return st.queue.get(st.index + 1).cloned();
+    }
+    let repeats = st.repeat || st.repeats_left.map(|left| left > 0).unwrap_or(false);
+    if repeats && !st.shuffle {
+        return st.queue.first().cloned();
+    }
+    None
+}
+
+/// Drops already played tracks from the front while the queue is longer than
+/// `max_queue_len`. Upcoming tracks and the current one are never evicted.
+fn evict_played(st: &mut State) {
+    if st.max_queue_len == 0 || st.queue.len() <= st.max_queue_len {
+        return;
+    }
+    let evict = (st.queue.len() - st.max_queue_len).min(st.index);
+    if evict == 0 {
+        return;
+    }
+    st.queue.drain(..evict);
+    st.index -= evict;
+    st.resume_offer = st
+        .resume_offer
+        .and_then(|(index, position)| index.checked_sub(evict).map(|index| (index, position)));
+}
+
+/// Drops repeated song ids from the queue, keeping each song's first
+/// occurrence, except that the current entry always stays and `index`
+/// follows it. Returns how many songs were removed.
+fn dedupe_queue(st: &mut State) -> usize {
+    let current_id = st.queue.get(st.index).map(|song| song.id.clone());
+    let before = st.queue.len();
+    let mut seen = HashSet::new();
+    let mut index = 0;
+    let mut kept = Vec::with_capacity(before);
+    for (i, song) in st.queue.drain(..).enumerate() {
+        let keep = if i == st.index {
+            true
+        } else if Some(&song.id) == current_id.as_ref() {
+            false
+        } else {
+            seen.insert(song.id.clone())
+        };
+        if keep {
+            if i == st.index {
+                index = kept.len();
+            }
+            kept.push(song);
+        }
+    }
+    st.queue = kept;
+    st.index = index;
+    before - st.queue.len()
+}
+
+/// Makes the songs from `index` on contiguous per artist, keeping their
+/// relative order. The current song's artist comes first so `index` still
+/// points at the current song; earlier songs are left alone. Returns the
+/// number of artist blocks.
+fn group_by_artist(queue: &mut Vec<Song>, index: usize) -> usize {
+    let upcoming = queue.split_off(index.min(queue.len()));
+    let mut order = Vec::new();
+    let mut blocks: HashMap<String, Vec<Song>> = HashMap::new();
+    for song in upcoming {
+        let key = song.artist.trim().to_lowercase();
+        if !blocks.contains_key(&key) {
+            order.push(key.clone());
+        }
+        blocks.entry(key).or_default().push(song);
+    }
+    for key in &order {
+        if let Some(mut block) = blocks.remove(key) {
+            queue.append(&mut block);
+        }
+    }
+    order.len()
+}
+
+/// Moves the song at `index` to `target` (the end when `None` or past it)
+/// and returns where it now is. Playback is untouched; only what plays
+/// after it, and where wrapping or `--rewind` lead, changes.
+fn requeue_current(queue: &mut Vec<Song>, index: usize, target: Option<usize>) -> usize {
+    let song = queue.remove(index);
+    let target = target.unwrap_or(queue.len()).min(queue.len());
+    queue.insert(target, song);
+    target
+}
+
+/// Orders the songs after the current one by rating, highest first, then by
+/// play count; ties keep their order and unrated songs go last. The current
+/// song and earlier ones stay put, so `index` is unchanged. Returns how many
+/// songs were sorted and how many of them had no rating.
+fn sort_by_rating(queue: &mut [Song], index: usize) -> (usize, usize) {
+    let upcoming = match queue.get_mut(index + 1..) {
+        Some(upcoming) => upcoming,
+        None => return (0, 0),
+    };
+    upcoming.sort_by_key(|song| {
+        (
+            Reverse(song.user_rating.unwrap_or(0)),
+            Reverse(song.play_count.unwrap_or(0)),
+        )
+    });
+    let unrated = upcoming.iter().filter(|song| song.user_rating.unwrap_or(0) == 0).count();
+    (upcoming.len(), unrated)
+}
+
+/// Shuffles the queue, moving songs that share a `queue_group` as one block.
+fn shuffle_queue(queue: &mut Vec<Song>) {
+    if queue.iter().all(|song| song.queue_group.is_none()) {
+        queue.shuffle(&mut rand::thread_rng());
+        return;
+    }
+    let mut blocks: Vec<Vec<Song>> = Vec::new();
+    for song in queue.drain(..) {
+        match (song.queue_group, blocks.last_mut()) {
+            (Some(group), Some(block)) if block[0].queue_group == Some(group) => block.push(song),
+            _ => blocks.push(vec![song]),
+        }
+    }
+    blocks.shuffle(&mut rand::thread_rng());
+    queue.extend(blocks.into_iter().flatten());
+}
+
+fn play_next(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    manual: bool,
+    expected_id: Option<&str>,
+) -> Result<()> {
+    // A skip (not the end fallback) still counts when scrobble_on_skip is on
+    // and the track got far enough; read how far before anything changes.
+    let skipped_at = if manual && expected_id.is_none() && scrobbles_skips(state) {
+        mpv.get_time_pos().ok().flatten()
+    } else {
+        None
+    };
+    let (next, gap, skipped, adopt) = {
         let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
         if st.queue.is_empty() {
             return Err(anyhow!("Queue is empty"));
@@ -484,30 +3803,79 @@ fn play_next(
                 return Ok(());
             }
         }
-        if st.index + 1 >= st.queue.len() {
-            if st.repeat {
-                if st.shuffle {
-                    st.queue.shuffle(&mut rand::thread_rng());
-                }
-                st.index = 0;
-            } else {
-                return Err(anyhow!("End of queue"));
+        let skipped = match skipped_at {
+            Some(pos) if !st.external => st.current.clone().filter(|song| past_scrobble_point(song, pos)),
+            _ => None,
+        };
+        // The next track is loaded but held paused, so play resumes with it.
+        let hold = !manual && st.pause_after_current == Some(st.track_serial);
+        st.pause_after_current = None;
+        if !st.advance() {
+            // The album played to the end; the next playalbum starts fresh.
+            if let Some(album_id) = st.album_play.take() {
+                st.change_album_position(album_id, None);
             }
-        } else {
-            st.index += 1;
+            return Err(anyhow!("End of queue"));
         }
         let song = st.queue[st.index].clone();
         st.current = Some(song.clone());
-        st.paused = false;
+        st.paused = hold;
         if manual {
             st.suppress_next_end = true;
         }
-        song
+        // The track ended by itself: an mpv event, or the end fallback (the
+        // only caller passing `expected_id`).
+        let ended = !manual || expected_id.is_some();
+        let gap = (ended && !hold && st.transition == "gap").then_some(st.transition_gap_secs);
+        let adopt = st.take_preload(&song.id, manual, hold);
+        (song, gap, skipped, adopt)
     };
-    play_song(state, client, mpv, &next)?;
+    // The skip suppresses the old track's end-file, so it is scrobbled here
+    // instead of by the event handler.
+    if let Some(song) = skipped {
+        scrobble_song(state, client, song);
+    }
+    match (adopt, gap) {
+        (Some(preload), _) => start_track(state, client, mpv, &next, preload.url, preload.local, false)?,
+        (None, Some(secs)) if secs > 0 => play_after_gap(state, client, mpv, next, secs),
+        _ => play_song(state, client, mpv, &next)?,
+    }
     Ok(())
 }
 
+/// Plays `song` after `secs` of silence, unless something else was loaded
+/// meanwhile.
+fn play_after_gap(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    song: Song,
+    secs: u32,
+) {
+    let serial = match state.lock() {
+        Ok(st) => st.track_serial,
+        Err(_) => return,
+    };
+    let state = state.clone();
+    let client = client.clone();
+    let mpv = mpv.clone();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_secs(secs as u64));
+        let current = state
+            .lock()
+            .map(|st| {
+                st.track_serial == serial
+                    && st.current.as_ref().map(|current| current.id == song.id) == Some(true)
+            })
+            .unwrap_or(false);
+        if current {
+            if let Err(err) = play_song(&state, &client, &mpv, &song) {
+                eprintln!("simplay: next track failed: {}", err);
+            }
+        }
+    });
+}
+
 fn play_previous(
     state: &Arc<Mutex<State>>,
     client: &NavidromeClient,
@@ -535,17 +3903,151 @@ fn play_previous(
     Ok(())
 }
 
+/// Plays an arbitrary URL as a single-item queue, bypassing the server: no
+/// scrobbling and no end-of-track fallback.
+fn play_external(state: &Arc<Mutex<State>>, mpv: &Arc<MpvController>, url: &str) -> Result<()> {
+    state
+        .lock()
+        .map_err(|_| anyhow!("State lock poisoned"))?
+        .start_external(url);
+    mpv.load(url)?;
+    mpv.pause(false)?;
+    Ok(())
+}
+
+fn restart_queue(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+) -> Result<()> {
+    let first = {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        if st.queue.is_empty() {
+            return Err(anyhow!("Queue is empty"));
+        }
+        st.index = 0;
+        let song = st.queue[0].clone();
+        // Replacing a loaded file emits an end-file we must not advance on.
+        st.suppress_next_end = st.current.is_some();
+        st.current = Some(song.clone());
+        st.paused = false;
+        song
+    };
+    play_song(state, client, mpv, &first)?;
+    Ok(())
+}
+
+/// What mpv should load for `song`: its file under `local_library_root` when
+/// local playback is on and the file exists, otherwise the stream URL. The
+/// flag is true for a local file.
+fn song_source(state: &Arc<Mutex<State>>, client: &NavidromeClient, song: &Song) -> Result<(String, bool)> {
+    let local = match state.lock() {
+        Ok(st) if st.local_playback => st
+            .local_root
+            .as_deref()
+            .and_then(|root| local_file(root, &st.local_path_template, song)),
+        _ => None,
+    };
+    match local {
+        Some(path) => Ok((path.to_string_lossy().into_owned(), true)),
+        None => Ok((client.stream_url(&song.id)?, false)),
+    }
+}
+
+/// Expands `template` for `song` under `root`, if that file exists. A
+/// placeholder the song has no value for means there is no local file.
+fn local_file(root: &Path, template: &str, song: &Song) -> Option<PathBuf> {
+    // Tag values are single path components.
+    let clean = |value: &str| value.replace('/', "_");
+    let fields = [
+        ("{path}", song.path.clone()),
+        ("{artist}", Some(clean(&song.artist))),
+        ("{album}", Some(clean(&song.album))),
+        ("{title}", Some(clean(&song.title))),
+        ("{track}", song.track.map(|track| format!("{:02}", track))),
+        ("{disc}", song.disc.map(|disc| disc.to_string())),
+        ("{suffix}", song.suffix.clone()),
+    ];
+    let mut relative = template.to_string();
+    for (key, value) in fields {
+        if relative.contains(key) {
+            relative = relative.replace(key, &value?);
+        }
+    }
+    let path = root.join(relative.trim_start_matches('/'));
+    path.is_file().then_some(path)
+}
+
 fn play_song(
     state: &Arc<Mutex<State>>,
     client: &NavidromeClient,
     mpv: &Arc<MpvController>,
     song: &Song,
 ) -> Result<()> {
-    let url = client.stream_url(&song.id)?;
-    mpv.load(&url)?;
-    mpv.pause(false)?;
-    if let Err(err) = client.scrobble_now_playing(&song.id) {
-        eprintln!("simplay: now playing update failed: {}", err);
+    let (url, local) = song_source(state, client, song)?;
+    start_track(state, client, mpv, song, url, local, true)
+}
+
+/// Makes `song`, playing from `url`, the current track. With `load` off mpv
+/// is already playing it from its playlist, so it is only taken over.
+fn start_track(
+    state: &Arc<Mutex<State>>,
+    client: &NavidromeClient,
+    mpv: &Arc<MpvController>,
+    song: &Song,
+    url: String,
+    local: bool,
+    load: bool,
+) -> Result<()> {
+    let (serial, paused) = {
+        let mut st = state.lock().map_err(|_| anyhow!("State lock poisoned"))?;
+        st.track_serial += 1;
+        st.stream_url = Some(url.clone());
+        st.external = false;
+        st.local_file = local;
+        // Replacing the file clears mpv's playlist; taking over the
+        // preloaded track uses up its entry.
+        st.preloaded = None;
+        if st.history.len() >= MAX_HISTORY {
+            st.history.pop_front();
+        }
+        st.history.push_back(HistoryEntry {
+            id: song.id.clone(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            played_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        });
+        (st.track_serial, st.paused)
+    };
+    // Callers clear `paused` unless the track should load held.
+    if load {
+        if paused {
+            mpv.pause(true)?;
+        }
+        mpv.load(&url)?;
+        if !paused {
+            mpv.pause(false)?;
+        }
+    }
+    remember_album_position(state, 0.0);
+    if !paused {
+        if let Err(err) = client.scrobble_now_playing(&song.id) {
+            eprintln!("simplay: now playing update failed: {}", err);
+        }
+        announce_track(state, mpv, song);
+        let listenbrainz = state.lock().ok().and_then(|st| st.listenbrainz.clone());
+        if let Some(listenbrainz) = listenbrainz {
+            let song = song.clone();
+            thread::spawn(move || {
+                if let Err(err) = listenbrainz.playing_now(&song) {
+                    eprintln!("simplay: ListenBrainz now playing failed: {}", err);
+                }
+            });
+        }
     }
     if let Some(duration) = song.duration {
         schedule_end_fallback(
@@ -553,48 +4055,305 @@ fn play_song(
             client.clone(),
             mpv.clone(),
             song.id.clone(),
+            serial,
             duration,
+            0.0,
         );
     }
+    preload_next(state, client, mpv);
     Ok(())
 }
 
+/// Forgets the gapless preload and takes it out of mpv's playlist, so mpv
+/// doesn't start it by itself when the current track ends.
+fn drop_preload(state: &Arc<Mutex<State>>, mpv: &MpvController) {
+    let dropped = match state.lock() {
+        Ok(mut st) => st.preloaded.take().is_some(),
+        Err(_) => return,
+    };
+    if dropped {
+        if let Err(err) = mpv.clear_upcoming() {
+            eprintln!("simplay: could not drop the preloaded track: {}", err);
+        }
+    }
+}
+
+/// With the `gapless` transition, appends the track after the current one to
+/// mpv's playlist, so mpv opens it early and plays on without a gap. Never
+/// reaches across the wrap of a repeating queue, and leaves a held
+/// `pauseafter` track alone. Failures are only logged; the track then loads
+/// the usual way when its turn comes.
+fn preload_next(state: &Arc<Mutex<State>>, client: &NavidromeClient, mpv: &MpvController) {
+    let next = match state.lock() {
+        Ok(st)
+            if st.transition == "gapless"
+                && !st.external
+                && st.preloaded.is_none()
+                && st.pause_after_current.is_none() =>
+        {
+            match st.queue.get(st.index + 1) {
+                Some(song) => song.clone(),
+                None => return,
+            }
+        }
+        _ => return,
+    };
+    let result = song_source(state, client, &next).and_then(|(url, local)| {
+        mpv.append(&url)?;
+        Ok((url, local))
+    });
+    match result {
+        Ok((url, local)) => {
+            if let Ok(mut st) = state.lock() {
+                st.preloaded = Some(Preload { id: next.id, url, local });
+            }
+        }
+        Err(err) => eprintln!("simplay: could not preload {}: {}", next.title, err),
+    }
+}
+
+/// Speaks the track's title and artist in the background with the music
+/// ducked, when announcements are on. Failures are only logged.
+fn announce_track(state: &Arc<Mutex<State>>, mpv: &Arc<MpvController>, song: &Song) {
+    let command = match state.lock() {
+        // One announcement at a time; a quick skip just isn't announced.
+        Ok(mut st) if st.announce && !st.announcing => {
+            st.announcing = true;
+            st.announce_command.clone()
+        }
+        _ => return,
+    };
+    let state = state.clone();
+    let mpv = mpv.clone();
+    let text = format!("{} by {}", song.title, song.artist);
+    thread::spawn(move || {
+        let volume = mpv.get_volume().ok();
+        let ducked = volume.map(|volume| (volume * ANNOUNCE_DUCK).round());
+        if let Some(ducked) = ducked {
+            let _ = mpv.set_volume(ducked);
+        }
+        if let Err(err) = announce::speak(&command, &text) {
+            eprintln!("simplay: announce failed: {}", err);
+        }
+        // Leave the volume alone if it was changed during the announcement.
+        if let (Some(volume), Some(ducked)) = (volume, ducked) {
+            if mpv.get_volume().map(|now| now == ducked).unwrap_or(false) {
+                let _ = mpv.set_volume(volume);
+            }
+        }
+        if let Ok(mut st) = state.lock() {
+            st.announcing = false;
+        }
+    });
+}
+
+/// Pauses with a fade, or holds the pause for the track boundary when the
+/// current track ends within `soft_pause_window_secs`. When already paused,
+/// resumes with a fade back in.
+fn soft_pause(state: &Arc<Mutex<State>>, mpv: &MpvController, config: &Config) -> Response {
+    let (song, paused) = match state.lock() {
+        Ok(st) => (st.current.clone(), st.paused),
+        Err(_) => return Response::err("State lock poisoned"),
+    };
+    let song = match song {
+        Some(song) => song,
+        None => return Response::err("No song playing"),
+    };
+    let volume = match mpv.get_volume() {
+        Ok(volume) => volume,
+        Err(err) => return error_response(err),
+    };
+    if paused {
+        let _ = mpv.set_volume(0.0);
+        if let Err(err) = mpv.pause(false) {
+            let _ = mpv.set_volume(volume);
+            return error_response(err);
+        }
+        if let Ok(mut st) = state.lock() {
+            st.paused = false;
+        }
+        fade_volume(mpv, 0.0, volume);
+        return Response::ok("Playing");
+    }
+    let position = mpv.get_time_pos().ok().flatten().unwrap_or(0.0);
+    if let Some(duration) = song.duration {
+        let window = config.soft_pause_window_secs() as f64;
+        if duration as f64 - position <= window {
+            // Same hold as pauseafter: the next track loads paused.
+            match state.lock() {
+                Ok(mut st) => st.pause_after_current = Some(st.track_serial),
+                Err(_) => return Response::err("State lock poisoned"),
+            }
+            drop_preload(state, mpv);
+            return Response::ok("Will pause when this track ends");
+        }
+    }
+    fade_volume(mpv, volume, 0.0);
+    let result = mpv.pause(true);
+    // Paused at the original volume, so a plain play isn't silent.
+    let _ = mpv.set_volume(volume);
+    match result {
+        Ok(_) => {
+            if let Ok(mut st) = state.lock() {
+                st.paused = true;
+            }
+            let position = mpv.get_time_pos().ok().flatten().unwrap_or(position);
+            remember_album_position(state, position);
+            Response::ok("Paused")
+        }
+        Err(err) => error_response(err),
+    }
+}
+
+/// Steps mpv's volume from `from` to `to` over `SOFT_PAUSE_FADE_MS`.
+fn fade_volume(mpv: &MpvController, from: f64, to: f64) {
+    for step in 1..=SOFT_PAUSE_FADE_STEPS {
+        thread::sleep(Duration::from_millis(SOFT_PAUSE_FADE_MS / SOFT_PAUSE_FADE_STEPS));
+        let volume = from + (to - from) * step as f64 / SOFT_PAUSE_FADE_STEPS as f64;
+        let _ = mpv.set_volume(volume.round());
+    }
+}
+
+/// Counts a full play of `song` when `auto_like_after_plays` is set, and
+/// stars it on reaching that many. Skips never get here, so only genuine
+/// completions count.
+fn count_completion(state: &Arc<Mutex<State>>, client: &NavidromeClient, song: &Song) {
+    let threshold = match state.lock() {
+        Ok(st) if st.auto_like_after_plays > 0 => st.auto_like_after_plays,
+        _ => return,
+    };
+    let client = client.clone();
+    let song = song.clone();
+    thread::spawn(move || {
+        let count = match session::record_completion(&song.id) {
+            Ok(count) => count,
+            Err(err) => {
+                eprintln!("simplay: failed to count completed play: {}", err);
+                return;
+            }
+        };
+        // Only on reaching it, so unhearting a song later sticks.
+        if count != threshold || song.starred {
+            return;
+        }
+        match client.star_song(&song.id) {
+            Ok(_) => eprintln!("simplay: hearted {} - {} after {} full plays", song.artist, song.title, count),
+            Err(err) => eprintln!("simplay: auto-like failed: {}", err),
+        }
+    });
+}
+
+/// Scrobbles a played song to the server and, when configured, ListenBrainz,
+/// off the calling thread.
+fn scrobble_song(state: &Arc<Mutex<State>>, client: &NavidromeClient, song: Song) {
+    let client = client.clone();
+    let song_id = song.id.clone();
+    thread::spawn(move || {
+        if let Err(err) = client.scrobble_submission(&song_id) {
+            eprintln!("simplay: scrobble failed: {}", err);
+        }
+    });
+    listenbrainz_listen(state, song);
+}
+
+fn scrobbles_skips(state: &Arc<Mutex<State>>) -> bool {
+    state.lock().map(|st| st.scrobble_on_skip).unwrap_or(false)
+}
+
+/// Whether `pos` seconds into `song` is enough for a scrobble: half the
+/// track or four minutes, whichever comes first (four minutes when the
+/// length is unknown).
+fn past_scrobble_point(song: &Song, pos: f64) -> bool {
+    let point = match song.duration {
+        Some(secs) if secs > 0 => (secs as f64 / 2.0).min(SCROBBLE_POINT_MAX_SECS),
+        _ => SCROBBLE_POINT_MAX_SECS,
+    };
+    pos >= point
+}
+
+/// Submits a finished song to ListenBrainz, when configured, timed from
+/// when it started playing.
+fn listenbrainz_listen(state: &Arc<Mutex<State>>, song: Song) {
+    let (listenbrainz, started) = match state.lock() {
+        Ok(st) => match st.listenbrainz.clone() {
+            Some(listenbrainz) => {
+                let started = st
+                    .history
+                    .back()
+                    .filter(|entry| entry.id == song.id)
+                    .map(|entry| entry.played_at);
+                (listenbrainz, started)
+            }
+            None => return,
+        },
+        Err(_) => return,
+    };
+    let listened_at = started.unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        now.saturating_sub(song.duration.unwrap_or(0) as u64)
+    });
+    thread::spawn(move || {
+        if let Err(err) = listenbrainz.listen(&song, listened_at) {
+            eprintln!("simplay: ListenBrainz scrobble failed: {}", err);
+        }
+    });
+}
+
 fn schedule_end_fallback(
     state: Arc<Mutex<State>>,
     client: NavidromeClient,
     mpv: Arc<MpvController>,
     song_id: String,
+    serial: u64,
     duration_secs: u32,
+    elapsed_secs: f64,
 ) {
     thread::spawn(move || {
-        let mut remaining = duration_secs as f64;
+        let mut remaining = (duration_secs as f64 - elapsed_secs).max(0.1);
+        let mut sleep_ms = 0;
         loop {
-            let grace_ms = state
-                .lock()
-                .ok()
-                .map(|s| s.end_grace_ms)
-                .unwrap_or(500);
-            let sleep_ms = ((remaining * 1000.0) as u64).saturating_add(grace_ms);
+            if sleep_ms == 0 {
+                let grace_ms = state
+                    .lock()
+                    .ok()
+                    .map(|s| s.end_grace_ms)
+                    .unwrap_or(500);
+                sleep_ms = ((remaining * 1000.0) as u64).saturating_add(grace_ms);
+            }
             thread::sleep(Duration::from_millis(sleep_ms));
+            sleep_ms = 0;
 
             let (paused, current_matches) = match state.lock() {
-                Ok(st) => (
-                    st.paused,
-                    st.current.as_ref().map(|song| song.id == song_id).unwrap_or(false),
-                ),
-                Err(_) => (true, false),
+                Ok(st) => (st.paused, st.track_serial == serial),
+                Err(_) => (false, false),
             };
-            if !current_matches || paused {
+            if !current_matches {
                 return;
             }
-
-            if let Ok(Some(pos)) = mpv.get_time_pos() {
-                if pos + 0.25 < duration_secs as f64 {
-                    remaining = (duration_secs as f64 - pos).max(0.1);
+            match fallback_step(paused, || mpv.get_time_pos().ok().flatten(), duration_secs) {
+                FallbackStep::PollPaused => {
+                    sleep_ms = PAUSED_POLL_MS;
+                    continue;
+                }
+                FallbackStep::Wait(secs) => {
+                    remaining = secs;
                     continue;
                 }
+                FallbackStep::Fire => {}
             }
 
+            if let Ok(mut st) = state.lock() {
+                st.grace.fallbacks += 1;
+                st.grace.fallback_fired = true;
+                log_grace_hint(&mut st);
+            }
+            // mpv played it to the end; its own eof event is suppressed.
+            if let Some(song) = current_song(&state).filter(|song| song.id == song_id) {
+                count_completion(&state, &client, &song);
+            }
             if let Err(err) = play_next(&state, &client, &mpv, true, Some(song_id.as_str())) {
                 eprintln!("simplay: fallback next track failed: {}", err);
             }
@@ -603,19 +4362,664 @@ fn schedule_end_fallback(
     });
 }
 
+/// What the end fallback does when it wakes up.
+#[derive(Debug, PartialEq)]
+enum FallbackStep {
+    /// Paused: check again later without asking mpv.
+    PollPaused,
+    /// mpv is short of the end (e.g. after a seek); wait this many seconds.
+    Wait(f64),
+    /// The track's time is up; advance.
+    Fire,
+}
+
+/// Decides the end fallback's next step. A paused track (possibly seeked
+/// meanwhile) keeps its timer, and the remaining time is recomputed from
+/// `position` only once playback resumes.
+fn fallback_step(
+    paused: bool,
+    position: impl FnOnce() -> Option<f64>,
+    duration_secs: u32,
+) -> FallbackStep {
+    if paused {
+        return FallbackStep::PollPaused;
+    }
+    match position() {
+        Some(pos) if pos + 0.25 < duration_secs as f64 => {
+            FallbackStep::Wait((duration_secs as f64 - pos).max(0.1))
+        }
+        _ => FallbackStep::Fire,
+    }
+}
+
+/// Logs the `end_grace_ms` suggestion the first time there is one.
+fn log_grace_hint(st: &mut State) {
+    if st.grace.hint_logged {
+        return;
+    }
+    if let Some(hint) = st.grace.suggestion(st.end_grace_ms) {
+        eprintln!("simplay: {}", hint);
+        st.grace.hint_logged = true;
+    }
+}
+
+/// The chapter mpv is in and its title; `None` for files without chapters
+/// or before the first chapter starts.
+fn current_chapter(mpv: &MpvController) -> Option<(u32, Option<String>)> {
+    let chapter = mpv
+        .get_property("chapter")
+        .ok()
+        .flatten()
+        .and_then(|v| v.as_i64())
+        .filter(|chapter| *chapter >= 0)?;
+    let text = |name: String| {
+        mpv.get_property(&name)
+            .ok()
+            .flatten()
+            .and_then(|v| v.as_str().map(|s| s.to_string()))
+            .filter(|s| !s.trim().is_empty())
+    };
+    let title = text("chapter-metadata/title".to_string())
+        .or_else(|| text(format!("chapter-list/{}/title", chapter)));
+    Some((chapter as u32, title))
+}
+
+/// Where each chapter of the loaded file starts, in seconds; empty for
+/// files without chapters.
+fn chapter_starts(mpv: &MpvController) -> Vec<f64> {
+    mpv.get_property("chapter-list")
+        .ok()
+        .flatten()
+        .and_then(|v| v.as_array().cloned())
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|chapter| chapter.get("time").and_then(|v| v.as_f64()))
+        .collect()
+}
+
+/// Where a relative seek of `offset` from `pos` lands. In a chaptered file
+/// it stops at the edge of the current chapter instead of running into the
+/// next or previous one; seeking again from that edge crosses it.
+fn chapter_bounded_seek(pos: f64, offset: f64, chapters: &[f64]) -> f64 {
+    let target = (pos + offset).max(0.0);
+    if offset >= 0.0 {
+        match chapters.iter().copied().find(|start| *start > pos + CHAPTER_EDGE_SECS) {
+            Some(next) => target.min(next),
+            None => target,
+        }
+    } else {
+        match chapters.iter().copied().rfind(|start| *start < pos - CHAPTER_EDGE_SECS) {
+            Some(start) => target.max(start),
+            None => target,
+        }
+    }
+}
+
+/// Whether mpv has nothing loaded. A `stop` caused by loading the next
+/// track leaves mpv busy with that track instead.
+fn mpv_idle(mpv: &MpvController) -> bool {
+    mpv.get_property("idle-active")
+        .ok()
+        .flatten()
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false)
+}
+
+/// Whether mpv's own playlist holds an entry after the playing one.
+fn next_preloaded(mpv: &MpvController) -> bool {
+    let number = |name: &str| {
+        mpv.get_property(name)
+            .ok()
+            .flatten()
+            .and_then(|v| v.as_i64())
+    };
+    match (number("playlist-pos"), number("playlist-count")) {
+        (Some(pos), Some(count)) => pos >= 0 && pos + 1 < count,
+        _ => false,
+    }
+}
+
+fn track_info(mpv: &MpvController) -> Result<Option<TrackInfo>> {
+    let codec = mpv
+        .get_property("audio-codec")?
+        .and_then(|v| v.as_str().map(|s| s.to_string()));
+    if codec.is_none() {
+        return Ok(None);
+    }
+    let number = |name: &str| -> Result<Option<u64>> {
+        Ok(mpv.get_property(name)?.and_then(|v| v.as_f64()).map(|v| v as u64))
+    };
+    let text = |name: &str| -> Result<Option<String>> {
+        Ok(mpv.get_property(name)?.and_then(|v| v.as_str().map(|s| s.to_string())))
+    };
+    Ok(Some(TrackInfo {
+        codec,
+        codec_name: text("audio-codec-name")?,
+        file_format: text("file-format")?,
+        bitrate_kbps: number("audio-bitrate")?.map(|bits| bits / 1000),
+        sample_rate: number("audio-params/samplerate")?,
+        channels: number("audio-params/channel-count")?,
+    }))
+}
+
+/// Samples mpv's position twice to tell whether audio is really moving, and
+/// why not when it isn't.
+fn diagnose(mpv: &MpvController) -> Result<Diagnosis> {
+    let flag = |name: &str| -> Result<bool> {
+        Ok(mpv.get_property(name)?.and_then(|v| v.as_bool()).unwrap_or(false))
+    };
+    let position_before = mpv.get_time_pos()?;
+    thread::sleep(Duration::from_millis(DIAGNOSE_SAMPLE_MS));
+    let position_after = mpv.get_time_pos()?;
+    let paused = flag("pause")?;
+    let paused_for_cache = flag("paused-for-cache")?;
+    let core_idle = flag("core-idle")?;
+    let idle = flag("idle-active")?;
+    let advancing = match (position_before, position_after) {
+        (Some(before), Some(after)) => after > before,
+        _ => false,
+    };
+    let verdict = if idle {
+        "idle"
+    } else if advancing {
+        "advancing"
+    } else if paused {
+        "paused"
+    } else if paused_for_cache {
+        "stalled (buffering)"
+    } else {
+        "stalled"
+    };
+    Ok(Diagnosis {
+        verdict: verdict.to_string(),
+        position_before,
+        position_after,
+        paused,
+        paused_for_cache,
+        core_idle,
+        idle,
+    })
+}
+
+fn status_response(status: Status) -> Response {
+    Response {
+        ok: true,
+        message: "ok".to_string(),
+        status: Some(status),
+        data: None,
+        code: None,
+    }
+}
+
+/// The current Subsonic song; external URLs have no server-side identity.
 fn current_song(state: &Arc<Mutex<State>>) -> Option<Song> {
-    state.lock().ok().and_then(|s| s.current.clone())
+    state
+        .lock()
+        .ok()
+        .filter(|s| !s.external)
+        .and_then(|s| s.current.clone())
 }
 
-fn adjust_volume(mpv: &Arc<MpvController>, delta: i32) -> Response {
+/// Switches mpv to the device matching `query` (its name, or best match on
+/// its description). The old device's volume is remembered and the new
+/// device's remembered volume, if any, is restored.
+fn switch_audio_device(
+    state: &Arc<Mutex<State>>,
+    mpv: &MpvController,
+    config: &Config,
+    query: &str,
+) -> Result<String> {
+    let devices = mpv.audio_devices()?;
+    let items: Vec<subsonic::Item> = devices
+        .iter()
+        .map(|(name, description)| subsonic::Item {
+            id: name.clone(),
+            name: description.clone(),
+        })
+        .collect();
+    let device = items
+        .iter()
+        .find(|item| item.id == query)
+        .cloned()
+        .or_else(|| subsonic::best_match(query, &items))
+        .ok_or_else(|| anyhow!("No audio device matches {}; see --audio-devices", query))?;
+
+    if let (Ok(Some(previous)), Ok(volume)) = (mpv.audio_device(), mpv.get_volume()) {
+        if let Err(err) = session::save_device_volume(&previous, volume) {
+            eprintln!("simplay: failed to save device volume: {}", err);
+        }
+    }
+    mpv.set_audio_device(&device.id)?;
+    let restored = session::device_volume(&device.id).unwrap_or_else(|err| {
+        eprintln!("simplay: failed to load device volumes: {}", err);
+        None
+    });
+    match restored {
+        Some(volume) => {
+            let volume = config.clamp_volume(volume.round() as i32);
+            mpv.set_volume(volume as f64)?;
+            // The device's own level wins over a pending initial_volume.
+            if let Ok(mut st) = state.lock() {
+                st.initial_volume = None;
+            }
+            Ok(format!("Audio device {} (volume {})", device.name, volume))
+        }
+        None => Ok(format!("Audio device {}", device.name)),
+    }
+}
+
+fn adjust_volume(mpv: &Arc<MpvController>, config: &Config, delta: i32) -> Response {
     match mpv.get_volume() {
         Ok(volume) => {
-            let new_volume = (volume as i32 + delta).clamp(0, 100) as f64;
+            let new_volume = config.clamp_volume(volume as i32 + delta) as f64;
             match mpv.set_volume(new_volume) {
                 Ok(_) => Response::ok(format!("Volume {}", new_volume as i32)),
-                Err(err) => Response::err(err.to_string()),
+                Err(err) => error_response(err),
             }
         }
-        Err(err) => Response::err(err.to_string()),
+        Err(err) => error_response(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: &str, artist: &str) -> Song {
+        Song {
+            artist: artist.to_string(),
+            album: format!("{} album", artist),
+            ..Song::external(id)
+        }
+    }
+
+    fn ids(songs: &[Song]) -> Vec<&str> {
+        songs.iter().map(|song| song.id.as_str()).collect()
+    }
+
+    #[test]
+    fn shuffle_excludes_match_artist_genre_and_album_ignoring_case() {
+        let config = Config::from_toml(
+            r#"
+            shuffle_exclude_artists = ["  the band "]
+            shuffle_exclude_genres = ["christmas"]
+            shuffle_exclude_albums = ["LIVE AT HOME"]
+            "#,
+        );
+        let mut songs = vec![
+            song("1", "The Band"),
+            Song {
+                genre: Some("Christmas".to_string()),
+                ..song("2", "Other")
+            },
+            Song {
+                album: "Live at Home".to_string(),
+                ..song("3", "Other")
+            },
+            Song {
+                genre: Some("Rock".to_string()),
+                ..song("4", "The Bandits")
+            },
+            song("5", "Other"),
+        ];
+        apply_shuffle_excludes(&mut songs, &config);
+        assert_eq!(ids(&songs), ["4", "5"]);
+    }
+
+    #[test]
+    fn shuffle_excludes_keep_songs_without_a_genre() {
+        let config = Config::from_toml(r#"shuffle_exclude_genres = ["Christmas"]"#);
+        let mut songs = vec![song("1", "A"), song("2", "B")];
+        apply_shuffle_excludes(&mut songs, &config);
+        assert_eq!(ids(&songs), ["1", "2"]);
+    }
+
+    #[test]
+    fn end_fallback_waits_out_a_seek_made_while_paused() {
+        // Paused and seeked back to 30s: the position isn't even read.
+        let step = fallback_step(true, || panic!("read position while paused"), 200);
+        assert_eq!(step, FallbackStep::PollPaused);
+        // Resumed: the timer restarts from the seeked position.
+        assert_eq!(fallback_step(false, || Some(30.0), 200), FallbackStep::Wait(170.0));
+    }
+
+    #[test]
+    fn end_fallback_fires_at_the_end_or_without_a_position() {
+        assert_eq!(fallback_step(false, || Some(199.9), 200), FallbackStep::Fire);
+        assert_eq!(fallback_step(false, || None, 200), FallbackStep::Fire);
+    }
+
+    #[test]
+    fn album_songs_sort_by_disc_then_track_with_unnumbered_last() {
+        let track = |id: &str, disc: Option<u32>, number: Option<u32>| Song {
+            disc,
+            track: number,
+            ..song(id, "Various")
+        };
+        let mut songs = vec![
+            track("d2-1", Some(2), Some(1)),
+            track("d1-none-a", Some(1), None),
+            track("d1-3", Some(1), Some(3)),
+            track("d1-none-b", Some(1), None),
+            track("d1-1", Some(1), Some(1)),
+            track("nodisc-2", None, Some(2)),
+            track("d2-none", Some(2), None),
+        ];
+        sort_album_songs(&mut songs);
+        assert_eq!(
+            ids(&songs),
+            ["nodisc-2", "d1-1", "d1-3", "d1-none-a", "d1-none-b", "d2-1", "d2-none"]
+        );
+    }
+
+    fn queue(artists: &[&str]) -> Vec<Song> {
+        artists
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| song(&format!("{}{}", artist, i), artist))
+            .collect()
+    }
+
+    #[test]
+    fn group_by_artist_keeps_the_current_song_current() {
+        let mut songs = queue(&["x", "b", "a", "b", "a", "c"]);
+        let current = songs[2].id.clone();
+        let blocks = group_by_artist(&mut songs, 2);
+        assert_eq!(blocks, 3);
+        assert_eq!(songs[2].id, current);
+        assert_eq!(ids(&songs), ["x0", "b1", "a2", "a4", "b3", "c5"]);
+    }
+
+    #[test]
+    fn group_by_artist_matches_artists_ignoring_case() {
+        let mut songs = queue(&["A", "b", "a", "B"]);
+        let blocks = group_by_artist(&mut songs, 0);
+        assert_eq!(blocks, 2);
+        assert_eq!(songs[0].id, "A0");
+        assert_eq!(ids(&songs), ["A0", "a2", "b1", "B3"]);
+    }
+
+    #[test]
+    fn requeue_current_moves_to_the_front() {
+        let mut songs = queue(&["a", "b", "c", "d"]);
+        assert_eq!(requeue_current(&mut songs, 2, Some(0)), 0);
+        assert_eq!(ids(&songs), ["c2", "a0", "b1", "d3"]);
+    }
+
+    #[test]
+    fn requeue_current_moves_to_the_middle() {
+        let mut songs = queue(&["a", "b", "c", "d", "e"]);
+        assert_eq!(requeue_current(&mut songs, 0, Some(2)), 2);
+        assert_eq!(ids(&songs), ["b1", "c2", "a0", "d3", "e4"]);
+        assert_eq!(requeue_current(&mut songs, 4, Some(1)), 1);
+        assert_eq!(ids(&songs), ["b1", "e4", "c2", "a0", "d3"]);
+    }
+
+    #[test]
+    fn requeue_current_to_its_own_position_changes_nothing() {
+        let mut songs = queue(&["a", "b", "c"]);
+        assert_eq!(requeue_current(&mut songs, 1, Some(1)), 1);
+        assert_eq!(ids(&songs), ["a0", "b1", "c2"]);
+    }
+
+    #[test]
+    fn requeue_current_moves_to_the_end() {
+        let mut songs = queue(&["a", "b", "c"]);
+        assert_eq!(requeue_current(&mut songs, 0, None), 2);
+        assert_eq!(ids(&songs), ["b1", "c2", "a0"]);
+    }
+
+    #[test]
+    fn requeue_current_past_the_end_goes_last() {
+        let mut songs = queue(&["a", "b", "c"]);
+        assert_eq!(requeue_current(&mut songs, 1, Some(10)), 2);
+        assert_eq!(ids(&songs), ["a0", "c2", "b1"]);
+        let mut single = queue(&["a"]);
+        assert_eq!(requeue_current(&mut single, 0, Some(5)), 0);
+        assert_eq!(ids(&single), ["a0"]);
+    }
+
+    #[test]
+    fn seeks_wait_for_the_new_file() {
+        let new = Some("http://music/b");
+        // Still the previous file, with its position.
+        assert!(!file_ready(Some("http://music/a"), new, Some(93.0)));
+        // The new file, not playable yet.
+        assert!(!file_ready(new, new, None));
+        assert!(file_ready(new, new, Some(0.0)));
+        // Nothing recorded to compare with: any loaded file will do.
+        assert!(file_ready(Some("http://music/a"), None, Some(0.0)));
+    }
+
+    #[test]
+    fn playing_a_url_can_be_undone() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.queue = queue(&["a", "b", "c"]);
+        st.index = 1;
+        st.start_external("http://radio.example/stream");
+        let snapshot = st.undo.as_ref().expect("a snapshot");
+        assert_eq!(ids(&snapshot.queue), ["a0", "b1", "c2"]);
+        assert_eq!(snapshot.index, 1);
+        // A second URL keeps the library queue as the one to go back to.
+        st.start_external("http://radio.example/other");
+        assert_eq!(ids(&st.undo.as_ref().expect("a snapshot").queue), ["a0", "b1", "c2"]);
+    }
+
+    #[test]
+    fn a_held_lock_fails_the_liveness_check() {
+        let lock = Mutex::new(());
+        let guard = lock.lock().unwrap();
+        assert!(!lock_frees_within(&lock, Duration::from_millis(30)));
+        drop(guard);
+        assert!(lock_frees_within(&lock, Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn a_stuck_accept_loop_fails_the_liveness_check() {
+        let dir = std::env::temp_dir().join(format!("simplay-alive-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("daemon.sock");
+        fs::remove_file(&path).ok();
+        // Bound but never accepted from, like a wedged serve loop.
+        let _listener = UnixListener::bind(&path).unwrap();
+        let state = Mutex::new(State::new(&Config::from_toml("")));
+        let accepted = AtomicU64::new(0);
+        assert!(!daemon_alive(&state, &path, &accepted, Duration::from_millis(30)));
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// Advances through `passes` full passes of the queue, returning whether
+    /// every step found a next track.
+    fn play_passes(st: &mut State, passes: usize) -> bool {
+        (0..passes * st.queue.len()).all(|_| st.advance())
+    }
+
+    #[test]
+    fn a_repeat_count_that_runs_out_restores_repeat() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.queue = queue(&["a", "b"]);
+        st.repeat = true;
+        st.start_repeat_count(2);
+        assert!(!st.repeat);
+        // The second pass wraps once, then the count is used up.
+        assert!(play_passes(&mut st, 1));
+        assert_eq!(st.repeats_left, Some(0));
+        assert!(st.advance());
+        assert!(!st.advance());
+        assert!(st.repeat);
+        assert_eq!(st.repeats_left, None);
+    }
+
+    #[test]
+    fn a_cleared_repeat_count_restores_repeat() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.queue = queue(&["a", "b"]);
+        st.repeat = true;
+        st.start_repeat_count(3);
+        // Changing the count mid-way keeps the repeat from before the first.
+        st.start_repeat_count(5);
+        st.start_repeat_count(0);
+        assert!(st.repeat);
+        assert_eq!(st.repeats_left, None);
+        // Without repeat on, a count that runs out leaves it off.
+        st.repeat = false;
+        st.start_repeat_count(1);
+        assert!(st.advance());
+        assert!(!st.advance());
+        assert!(!st.repeat);
+    }
+
+    fn preload(id: &str) -> Option<Preload> {
+        Some(Preload { id: id.to_string(), url: format!("http://music/{}", id), local: false })
+    }
+
+    #[test]
+    fn gapless_is_a_transition_mode() {
+        let st = State::new(&Config::from_toml("transition = \"gapless\""));
+        assert_eq!(st.transition, "gapless");
+    }
+
+    #[test]
+    fn a_natural_end_takes_over_the_matching_preload() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.preloaded = preload("b1");
+        assert_eq!(st.take_preload("b1", false, false).map(|p| p.id), Some("b1".to_string()));
+        assert!(st.preloaded.is_none());
+        assert!(!st.suppress_next_end);
+    }
+
+    #[test]
+    fn a_stale_preload_is_replaced_without_advancing_twice() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.preloaded = preload("c2");
+        assert!(st.take_preload("b1", false, false).is_none());
+        assert!(st.preloaded.is_none());
+        assert!(st.suppress_next_end);
+        // A held track loads paused in place of the one mpv started.
+        st.suppress_next_end = false;
+        st.preloaded = preload("b1");
+        assert!(st.take_preload("b1", false, true).is_none());
+        assert!(st.suppress_next_end);
+    }
+
+    #[test]
+    fn a_skip_drops_the_preload_with_the_replacing_load() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.preloaded = preload("b1");
+        assert!(st.take_preload("b1", true, false).is_none());
+        assert!(st.preloaded.is_none());
+        assert!(!st.suppress_next_end);
+    }
+
+    #[test]
+    fn status_reports_gapless_only_for_the_next_track() {
+        let mut st = State::new(&Config::from_toml(""));
+        st.queue = queue(&["a", "b", "c"]);
+        assert!(!st.status().gapless);
+        st.preloaded = preload("b1");
+        assert!(st.status().gapless);
+        // The queue changed after the preload.
+        st.queue.swap(1, 2);
+        assert!(!st.status().gapless);
+    }
+
+    #[test]
+    fn songs_shuffled_in_stay_after_the_current_track() {
+        let mut songs = queue(&["a", "b", "c"]);
+        let mut rng = StdRng::seed_from_u64(7);
+        shuffle_into(&mut songs, 2, queue(&["x", "y", "z", "w"]), &mut rng);
+        assert_eq!(ids(&songs[..2]), ["a0", "b1"]);
+        let mut rest = ids(&songs[2..]);
+        rest.sort();
+        assert_eq!(rest, ["c2", "w3", "x0", "y1", "z2"]);
+        // Past the end of the queue they are simply appended.
+        let mut short = queue(&["a"]);
+        shuffle_into(&mut short, 5, queue(&["x"]), &mut rng);
+        assert_eq!(ids(&short), ["a0", "x0"]);
+    }
+
+    #[test]
+    fn streamed_starts_report_explicit_songs_dropped_so_far() {
+        let done = StreamStart { filtered: 2, loading: false };
+        assert_eq!(done.note("Shuffling".to_string()), "Shuffling (2 explicit tracks filtered)");
+        let loading = StreamStart { filtered: 2, loading: true };
+        assert_eq!(
+            loading.note("Shuffling".to_string()),
+            "Shuffling (loading the rest in the background; 2 explicit tracks filtered so far)"
+        );
+        let clean = StreamStart { filtered: 0, loading: true };
+        assert_eq!(clean.note("Shuffling".to_string()), "Shuffling (loading the rest in the background)");
+    }
+
+    #[test]
+    fn relative_seeks_ignore_chapters_in_unchaptered_files() {
+        assert_eq!(chapter_bounded_seek(100.0, 30.0, &[]), 130.0);
+        assert_eq!(chapter_bounded_seek(100.0, -30.0, &[]), 70.0);
+        assert_eq!(chapter_bounded_seek(10.0, -30.0, &[]), 0.0);
+    }
+
+    #[test]
+    fn relative_seeks_stop_at_the_chapter_edge() {
+        let chapters = [0.0, 600.0, 1200.0];
+        // Within the chapter nothing changes.
+        assert_eq!(chapter_bounded_seek(700.0, 30.0, &chapters), 730.0);
+        assert_eq!(chapter_bounded_seek(700.0, -30.0, &chapters), 670.0);
+        // Past either edge the seek stops on it.
+        assert_eq!(chapter_bounded_seek(590.0, 30.0, &chapters), 600.0);
+        assert_eq!(chapter_bounded_seek(610.0, -30.0, &chapters), 600.0);
+    }
+
+    #[test]
+    fn relative_seeks_from_a_chapter_edge_cross_it() {
+        let chapters = [0.0, 600.0, 1200.0];
+        assert_eq!(chapter_bounded_seek(600.0, -30.0, &chapters), 570.0);
+        assert_eq!(chapter_bounded_seek(599.5, 30.0, &chapters), 629.5);
+        // But only as far as the chapter after that.
+        assert_eq!(chapter_bounded_seek(600.0, 900.0, &chapters), 1200.0);
+    }
+
+    #[test]
+    fn end_file_eof_finishes_the_track() {
+        assert_eq!(end_file_action("eof", false, false), EndAction::Finished);
+        assert_eq!(end_file_action("eof", false, true), EndAction::Finished);
+    }
+
+    #[test]
+    fn end_file_error_goes_through_the_breaker() {
+        assert_eq!(end_file_action("error", false, false), EndAction::Failed);
+        assert_eq!(end_file_action("error", false, true), EndAction::Failed);
+    }
+
+    #[test]
+    fn end_file_stop_halts_unless_advance_on_stop() {
+        assert_eq!(end_file_action("stop", false, false), EndAction::Halt);
+        assert_eq!(end_file_action("stop", false, true), EndAction::Advance);
+    }
+
+    #[test]
+    fn end_file_without_a_reason_advances() {
+        assert_eq!(end_file_action("", false, false), EndAction::Advance);
+    }
+
+    #[test]
+    fn end_file_quit_and_redirect_are_ignored() {
+        assert_eq!(end_file_action("quit", false, true), EndAction::Ignore);
+        assert_eq!(end_file_action("redirect", false, true), EndAction::Ignore);
+    }
+
+    #[test]
+    fn suppressed_end_files_are_ignored_whatever_the_reason() {
+        for reason in ["eof", "error", "stop", "", "quit"] {
+            assert_eq!(end_file_action(reason, true, true), EndAction::Ignore);
+            assert_eq!(end_file_action(reason, true, false), EndAction::Ignore);
+        }
+    }
+
+    #[test]
+    fn shuffle_excludes_do_nothing_when_empty() {
+        let config = Config::from_toml("");
+        let mut songs = vec![song("1", "A"), song("2", "B")];
+        apply_shuffle_excludes(&mut songs, &config);
+        assert_eq!(ids(&songs), ["1", "2"]);
     }
 }
@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::subsonic::{NavidromeClient, Song};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+
+const PAGE_SIZE: usize = 200;
+
+/// Local copy of the full song list so library shuffles don't have to walk
+/// every album on each request.
+#[derive(Debug, Serialize, Deserialize)]
+struct LibraryCache {
+    /// `created` timestamp of the newest album seen at the last sync.
+    last_sync: Option<String>,
+    songs: Vec<Song>,
+}
+
+/// Returns all library songs, fetching only albums added since the last
+/// sync when a cache exists.
+pub fn songs(client: &NavidromeClient) -> Result<Vec<Song>> {
+    let mut cache = match load()? {
+        Some(cache) => cache,
+        None => return rebuild(client),
+    };
+
+    let mut new_albums = Vec::new();
+    let mut newest = None;
+    let mut offset = 0;
+    'pages: loop {
+        let page = client.newest_albums(offset, PAGE_SIZE)?;
+        if page.is_empty() {
+            break;
+        }
+        for album in &page {
+            if newest.is_none() {
+                newest = album.created.clone();
+            }
+            // ISO 8601 timestamps from one server compare correctly as strings.
+            if let (Some(created), Some(last)) = (&album.created, &cache.last_sync) {
+                if created <= last {
+                    break 'pages;
+                }
+            }
+            new_albums.push(album.id.clone());
+        }
+        if page.len() < PAGE_SIZE {
+            break;
+        }
+        offset += PAGE_SIZE;
+    }
+
+    if new_albums.is_empty() {
+        return Ok(cache.songs);
+    }
+
+    let changed: HashSet<&str> = new_albums.iter().map(String::as_str).collect();
+    cache
+        .songs
+        .retain(|song| !song.album_id.as_deref().map(|id| changed.contains(id)).unwrap_or(false));
+    for album_id in &new_albums {
+        let mut album_songs = client.album_songs(album_id)?;
+        cache.songs.append(&mut album_songs);
+    }
+    if newest.is_some() {
+        cache.last_sync = newest;
+    }
+    save(&cache)?;
+    Ok(cache.songs)
+}
+
+/// Discards the cache and walks the whole library again.
+pub fn rebuild(client: &NavidromeClient) -> Result<Vec<Song>> {
+    let last_sync = client
+        .newest_albums(0, 1)?
+        .into_iter()
+        .next()
+        .and_then(|album| album.created);
+    let songs = client.all_songs()?;
+    let cache = LibraryCache { last_sync, songs };
+    save(&cache)?;
+    Ok(cache.songs)
+}
+
+fn load() -> Result<Option<LibraryCache>> {
+    let path = Config::library_cache_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading library cache {}", path.display()))?;
+    match serde_json::from_str(&contents) {
+        Ok(cache) => Ok(Some(cache)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn save(cache: &LibraryCache) -> Result<()> {
+    let path = Config::library_cache_path()?;
+    fs::write(&path, serde_json::to_vec(cache)?)
+        .with_context(|| format!("Failed writing library cache {}", path.display()))?;
+    Ok(())
+}
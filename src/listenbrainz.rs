@@ -0,0 +1,77 @@
+use crate::subsonic::Song;
+use anyhow::{anyhow, Result};
+use reqwest::blocking::Client;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const SUBMIT_URL: &str = "https://api.listenbrainz.org/1/submit-listens";
+
+/// Scrobbles straight to ListenBrainz with a user token, independent of any
+/// relaying the Subsonic server does.
+#[derive(Clone)]
+pub struct ListenBrainz {
+    token: String,
+    http: Client,
+}
+
+impl ListenBrainz {
+    pub fn new(token: &str) -> Result<Self> {
+        let http = Client::builder().timeout(Duration::from_secs(10)).build()?;
+        Ok(Self {
+            token: token.trim().to_string(),
+            http,
+        })
+    }
+
+    pub fn playing_now(&self, song: &Song) -> Result<()> {
+        self.submit("playing_now", json!({ "track_metadata": track_metadata(song) }))
+    }
+
+    /// Records a finished listen that started at `listened_at` (Unix seconds).
+    pub fn listen(&self, song: &Song, listened_at: u64) -> Result<()> {
+        self.submit(
+            "single",
+            json!({
+                "listened_at": listened_at,
+                "track_metadata": track_metadata(song),
+            }),
+        )
+    }
+
+    fn submit(&self, listen_type: &str, listen: Value) -> Result<()> {
+        let body = json!({ "listen_type": listen_type, "payload": [listen] });
+        let resp = self
+            .http
+            .post(SUBMIT_URL)
+            .header("Authorization", format!("Token {}", self.token))
+            .json(&body)
+            .send()
+            .map_err(|err| anyhow!("ListenBrainz request failed: {}", err))?;
+        if !resp.status().is_success() {
+            let status = resp.status();
+            let detail = resp
+                .json::<Value>()
+                .ok()
+                .and_then(|v| v.get("error").and_then(|e| e.as_str()).map(|s| s.to_string()))
+                .unwrap_or_default();
+            return Err(anyhow!("ListenBrainz returned HTTP {} {}", status, detail));
+        }
+        Ok(())
+    }
+}
+
+fn track_metadata(song: &Song) -> Value {
+    let mut info = json!({ "submission_client": "simplay" });
+    if let Some(duration) = song.duration {
+        info["duration_ms"] = json!(duration as u64 * 1000);
+    }
+    if let Some(track) = song.track {
+        info["tracknumber"] = json!(track);
+    }
+    json!({
+        "artist_name": song.artist,
+        "track_name": song.title,
+        "release_name": song.album,
+        "additional_info": info,
+    })
+}
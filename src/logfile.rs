@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+/// The running redirect, for `finish`.
+static ACTIVE: Mutex<Option<Drain>> = Mutex::new(None);
+
+struct Drain {
+    thread: JoinHandle<()>,
+    /// Where stderr pointed before the redirect.
+    stderr: OwnedFd,
+}
+
+/// Sends everything the daemon writes to stderr into `path` instead,
+/// rotating it once it passes `max_bytes` (0 = never) and keeping
+/// `keep_files` old logs as `path.1` (newest) to `path.N`.
+///
+/// Writers only fill a pipe; a background thread drains it line by line and
+/// rotates between lines, so logging never waits on the disk and no line is
+/// split across files. Call `finish` before exiting so the last lines reach
+/// the file.
+pub fn redirect_stderr(path: &Path, max_bytes: u64, keep_files: u32) -> Result<()> {
+    let mut log = RotatingLog::open(path, max_bytes, keep_files)?;
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(anyhow!("Failed to create log pipe: {}", io::Error::last_os_error()));
+    }
+    let (read, write) = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+    let saved = unsafe { libc::fcntl(libc::STDERR_FILENO, libc::F_DUPFD_CLOEXEC, 0) };
+    if saved < 0 {
+        return Err(anyhow!("Failed to keep stderr: {}", io::Error::last_os_error()));
+    }
+    let saved = unsafe { OwnedFd::from_raw_fd(saved) };
+    if unsafe { libc::dup2(write.as_raw_fd(), libc::STDERR_FILENO) } < 0 {
+        return Err(anyhow!("Failed to redirect stderr: {}", io::Error::last_os_error()));
+    }
+    drop(write);
+    let thread = thread::spawn(move || {
+        let mut reader = BufReader::new(File::from(read));
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            // Nowhere left to report a failed write.
+            let _ = log.write_line(&line);
+        }
+    });
+    if let Ok(mut active) = ACTIVE.lock() {
+        *active = Some(Drain { thread, stderr: saved });
+    }
+    Ok(())
+}
+
+/// Points stderr back where it went before `redirect_stderr` and waits until
+/// everything already logged is in the file. Later output goes to the
+/// original stderr. Does nothing when stderr was not redirected.
+pub fn finish() {
+    let drain = match ACTIVE.lock() {
+        Ok(mut active) => active.take(),
+        Err(_) => return,
+    };
+    if let Some(drain) = drain {
+        // This closes the pipe's only write end, so the thread reaches end
+        // of file once it has written out the rest.
+        if unsafe { libc::dup2(drain.stderr.as_raw_fd(), libc::STDERR_FILENO) } >= 0 {
+            let _ = drain.thread.join();
+        }
+    }
+}
+
+struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_bytes: u64,
+    keep_files: u32,
+    /// The last rotation failed and the file says so.
+    rotate_failed: bool,
+}
+
+impl RotatingLog {
+    fn open(path: &Path, max_bytes: u64, keep_files: u32) -> Result<Self> {
+        let file = open_append(path)?;
+        let len = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            len,
+            max_bytes,
+            keep_files,
+            rotate_failed: false,
+        })
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        if self.max_bytes > 0 && self.len > 0 && self.len + line.len() as u64 > self.max_bytes {
+            // On failure keep writing to the current file rather than lose
+            // the line, and note why it grows past the limit once.
+            match self.rotate() {
+                Ok(()) => self.rotate_failed = false,
+                Err(err) if !self.rotate_failed => {
+                    self.rotate_failed = true;
+                    let note =
+                        format!("simplay: failed to rotate {}: {}\n", self.path.display(), err);
+                    self.file.write_all(note.as_bytes())?;
+                    self.len += note.len() as u64;
+                }
+                Err(_) => {}
+            }
+        }
+        self.file.write_all(line)?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for n in (1..self.keep_files).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+        if self.keep_files > 0 {
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        } else {
+            fs::remove_file(&self.path)?;
+        }
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open log file {}", path.display()))
+}
+
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
@@ -1,14 +1,22 @@
 use anyhow::{anyhow, Result};
 use clap::{CommandFactory, Parser};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+mod announce;
 mod config;
 mod daemon;
+mod library;
+mod listenbrainz;
+mod logfile;
 mod player;
 mod protocol;
+mod session;
 mod subsonic;
+mod systemd;
 
 use config::Config;
-use protocol::Request;
+use protocol::{Request, SongInfo};
 use subsonic::NavidromeClient;
 
 #[derive(Parser, Debug)]
@@ -18,11 +26,49 @@ struct Cli {
     daemon: bool,
     #[arg(long, short = 'C', help = "Configure simplay")]
     configure: bool,
+    #[arg(long, help = "Print the effective config (password redacted)")]
+    show_config: bool,
+    #[arg(long, value_name = "PATH", help = "Control socket path (overrides SIMPLAY_SOCKET)")]
+    socket: Option<PathBuf>,
+    #[arg(long, help = "Send through the read-only observer socket (observer_socket)")]
+    observer: bool,
+    #[arg(long, help = "Print a line each time the playing song or state changes, until interrupted")]
+    follow: bool,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        help = "Keep retrying until the daemon is listening, up to SECONDS (default: 10)"
+    )]
+    wait: Option<u64>,
+    #[arg(long, help = "Start the daemon in the background if it is not running")]
+    autostart: bool,
 
-    #[arg(long, short = 's', help = "Shuffle the library")]
+    #[arg(long, short = 's', help = "Shuffle the library (or the default_shuffle target)")]
     shuffle: bool,
+    #[arg(long, help = "Finish the current album, then shuffle the library")]
+    shuffle_keep_album: bool,
+    #[arg(long, help = "Remove duplicate songs from the queue")]
+    dedupe: bool,
+    #[arg(long, help = "Reorder upcoming songs so each artist's songs play together")]
+    group_by_artist: bool,
+    #[arg(
+        long,
+        value_name = "N|end",
+        help = "Move the current song to queue position N (or the end) without interrupting it"
+    )]
+    requeue_current: Option<String>,
+    #[arg(long, help = "Reorder upcoming songs by rating, highest first")]
+    sort_by_rating: bool,
+    #[arg(long, help = "Rebuild the local library cache from scratch")]
+    refresh_cache: bool,
     #[arg(long, short = 'p', help = "Pause playback")]
     pause: bool,
+    #[arg(long, help = "Pause when the current track ends (again to cancel)")]
+    pause_after_track: bool,
+    #[arg(long, help = "Fade out and pause, or wait for the track to end when it's nearly over; fades back in when paused")]
+    soft_pause: bool,
     #[arg(long, short = 'P', help = "Resume playback")]
     play: bool,
     #[arg(long, short = 'f', help = "Play next track")]
@@ -31,20 +77,71 @@ struct Cli {
     rewind: bool,
     #[arg(long, short = 'o', help = "Restart current track")]
     startover: bool,
+    #[arg(
+        long,
+        value_name = "SECS",
+        allow_hyphen_values = true,
+        help = "Seek to SECS, or by +SECS/-SECS (relative seeks stop at chapter edges)"
+    )]
+    seek: Option<String>,
+    #[arg(long, value_name = "N", help = "Play the queue N times in total, then stop (0 clears)")]
+    repeat_count: Option<u32>,
+    #[arg(long, help = "Bring back the queue the last shuffle/play command replaced")]
+    undo: bool,
+    #[arg(long, help = "Restart the queue from the first song")]
+    restartqueue: bool,
     #[arg(long, short = 'l', help = "Heart current song")]
     likesong: bool,
     #[arg(long, short = 'u', help = "Unheart current song")]
     unlikesong: bool,
+    #[arg(long, help = "Heart and rate current song (love_rating, default 5)")]
+    love: bool,
     #[arg(long, short = 'R', value_name = "1-5", help = "Rate current song (1-5)")]
     rate: Option<u8>,
     #[arg(long, short = 'v', help = "Increase volume")]
     volumeup: bool,
     #[arg(long, short = 'V', help = "Decrease volume")]
     volumedown: bool,
+    #[arg(long, value_name = "on|off", value_parser = ["on", "off"], help = "Toggle dynamic loudness normalization")]
+    normalize: Option<String>,
+    #[arg(long, value_name = "on|off", value_parser = ["on", "off"], help = "Speak each new track's title and artist")]
+    announce: Option<String>,
+    #[arg(long, value_name = "on|off", help = "Play tracks from local_library_root when the file exists")]
+    local_playback: Option<String>,
+    #[arg(long, value_name = "on|off", value_parser = ["on", "off"], help = "Scrobble skipped tracks that played past half (or four minutes)")]
+    scrobble_on_skip: Option<String>,
     #[arg(long, short = 'H', help = "Shuffle liked (hearted) songs")]
     shuffleliked: bool,
     #[arg(long, short = 't', help = "Show playback status")]
     status: bool,
+    #[arg(long, help = "List the server's music folders")]
+    list_folders: bool,
+    #[arg(long, value_name = "NAME|ID", help = "Scope browse and shuffle to a music folder (\"all\" to reset)")]
+    set_folder: Option<String>,
+    #[arg(long, value_name = "MS", help = "Override end_grace_ms until the daemon restarts")]
+    grace: Option<u64>,
+    #[arg(long, help = "Show how track ends were detected, to tune end_grace_ms")]
+    grace_stats: bool,
+    #[arg(long, help = "Start the server transcoding the next track ahead of time")]
+    prefetch: bool,
+    #[arg(long, help = "Ask the server to rescan its library (admin only)")]
+    rescan: bool,
+    #[arg(long, help = "Show whether the server is scanning and how many files it has scanned")]
+    scan_status: bool,
+    #[arg(long, help = "List mpv's audio output devices")]
+    audio_devices: bool,
+    #[arg(long, value_name = "DEVICE", help = "Switch audio output, restoring that device's last volume")]
+    audio_device: Option<String>,
+    #[arg(long, help = "Show daemon uptime and mpv health")]
+    health: bool,
+    #[arg(long, help = "Show full server metadata for the current song")]
+    song_info: bool,
+    #[arg(long, help = "Show codec, bitrate, sample rate and channels of the current stream")]
+    track_info: bool,
+    #[arg(long, help = "Check whether playback is actually advancing, and why not")]
+    diagnose: bool,
+    #[arg(long, help = "Show the stream URL loaded in mpv")]
+    stream_url: bool,
 
     #[arg(long, short = 'a', value_name = "ARTIST", help = "Shuffle artist")]
     shuffleartist: Option<String>,
@@ -54,11 +151,128 @@ struct Cli {
     shuffleplaylist: Option<String>,
     #[arg(long, short = 'A', value_name = "ALBUM", help = "Play album")]
     playalbum: Option<String>,
+    #[arg(
+        long,
+        value_name = "ALBUM::TITLE",
+        help = "Play ALBUM from the track best matching TITLE to the album's end"
+    )]
+    play_track: Option<String>,
+    #[arg(
+        long,
+        value_name = "ALBUM",
+        help = "Jump to ALBUM if it is in the queue, otherwise play it"
+    )]
+    album_goto: Option<String>,
+    #[arg(long, value_name = "N", help = "Jump to track number N of the current album")]
+    track: Option<u32>,
+    #[arg(long, help = "Jump to the first track of the next disc of the current album")]
+    next_disc: bool,
+    #[arg(long, help = "Jump to the first track of the previous disc of the current album")]
+    prev_disc: bool,
+    #[arg(long, help = "Append the rest of the current song's album to the queue")]
+    finish_album: bool,
+    #[arg(long, help = "Replace the queue with a mix built around the current song")]
+    instant_mix: bool,
+    #[arg(long, help = "Retry the current track after too many load errors halted playback")]
+    resume_after_error: bool,
+    #[arg(long, help = "Jump to a random track in the current queue without reshuffling it")]
+    random_track: bool,
+    #[arg(long, help = "Continue the album just started with --playalbum where it was left off")]
+    resume_album: bool,
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "Play N random albums picked by the server, tracks in order (default: 10)"
+    )]
+    random_albums: Option<String>,
+    #[arg(long, value_name = "ARTIST", help = "Play artist's discography in release order")]
+    play_artist: Option<String>,
+    #[arg(long, value_name = "ALBUM", help = "Queue album after the current song, kept together on reshuffle")]
+    queue_album_next: Option<String>,
+    #[arg(long, value_name = "URL", help = "Play a URL directly through mpv")]
+    play_url: Option<String>,
+    #[arg(long, value_name = "YEAR", help = "Play albums from a year in order")]
+    play_year: Option<u32>,
+    #[arg(
+        long,
+        value_name = "ARTIST",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "List artists similar to ARTIST (default: current artist)"
+    )]
+    similar_to: Option<String>,
+    #[arg(
+        long,
+        value_name = "MODE",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "Show or set how tracks follow each other: none, gap or gapless"
+    )]
+    transition: Option<String>,
+    #[arg(
+        long,
+        value_name = "ALBUM",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "Show album notes and links (default: current album)"
+    )]
+    album_info: Option<String>,
+    #[arg(long, value_name = "NAME", help = "Bookmark the current position under NAME")]
+    bookmark: Option<String>,
+    #[arg(long, value_name = "NAME", help = "Play a named bookmark from its saved position")]
+    goto_bookmark: Option<String>,
     #[arg(long, short = 'c', value_name = "PLAYLIST", help = "Add current song to playlist")]
     addsongtoplaylist: Option<String>,
     #[arg(long, short = 'D', value_name = "PLAYLIST", help = "Delete playlist")]
     deleteplaylist: Option<String>,
 
+    #[arg(long, value_name = "QUERY", help = "Search albums and songs; results are numbered")]
+    search: Option<String>,
+    #[arg(long, value_name = "N", help = "Play result N of the last --search")]
+    play_result: Option<usize>,
+    #[arg(long, value_name = "PLAYLIST", help = "Create a public share link for a playlist (name or id)")]
+    share_playlist: Option<String>,
+    #[arg(
+        long,
+        value_name = "PLAYLIST",
+        help = "List the songs in a playlist without playing it"
+    )]
+    playlist_contents: Option<String>,
+
+    #[arg(long, value_name = "SECS", help = "Skip tracks shorter than SECS when shuffling")]
+    min_secs: Option<u32>,
+    #[arg(
+        long,
+        value_name = "1-5",
+        value_parser = clap::value_parser!(u8).range(1..=5),
+        help = "Shuffle only songs rated at least this"
+    )]
+    min_rating: Option<u8>,
+    #[arg(long, requires = "min_rating", help = "Keep unrated songs when using --min-rating")]
+    include_unrated: bool,
+    #[arg(long, value_name = "N", help = "Shuffle in the order seed N gives, to repeat a shuffle exactly")]
+    seed: Option<u64>,
+    #[arg(long, value_name = "N", help = "Show at most N entries with --playlist-contents")]
+    limit: Option<usize>,
+
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["TYPE", "NAME"],
+        help = "Look up an album, artist or playlist by name without playing it"
+    )]
+    resolve: Option<Vec<String>>,
+    #[arg(long, value_name = "PATH", help = "Write this session's play history to PATH (.csv or .json)")]
+    export_history: Option<PathBuf>,
+    #[arg(long, help = "Show how often each command was used (needs usage_stats = true)")]
+    usage_stats: bool,
+    #[arg(long, help = "List the tracks that failed to load since the daemon started")]
+    failures: bool,
+    #[arg(long, help = "Forget the tracks --failures lists")]
+    clear_failures: bool,
+
     #[arg(long, value_name = "ENDPOINT", help = "Raw Subsonic API call")]
     api: Option<String>,
     #[arg(long, value_name = "KEY=VALUE", help = "Parameter for --api", action = clap::ArgAction::Append)]
@@ -74,9 +288,28 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if cli.show_config {
+        let (config, path) = Config::load_existing()?;
+        println!("# {}", path.display());
+        print!("{}", config.redacted()?);
+        return Ok(());
+    }
+
+    let socket_path = match cli.socket.clone() {
+        Some(path) => Config::custom_socket_path(path)?,
+        None => Config::socket_path()?,
+    };
+
     if cli.daemon {
         let config = Config::load_or_prompt_required()?;
-        return daemon::run(config);
+        if let Err(err) = daemon::run(config, socket_path) {
+            // Printed here rather than returned so it still reaches log_file.
+            eprintln!("Error: {:?}", err);
+            logfile::finish();
+            std::process::exit(1);
+        }
+        logfile::finish();
+        return Ok(());
     }
 
     if let Some(endpoint) = cli.api.as_deref() {
@@ -84,6 +317,16 @@ fn main() -> Result<()> {
         return run_api_call(&config, endpoint, &cli.param);
     }
 
+    let socket_path = if cli.observer {
+        Config::observer_socket_path(&socket_path)
+    } else {
+        socket_path
+    };
+
+    if cli.follow {
+        return follow_status(&socket_path);
+    }
+
     let req = build_request(&cli)?;
     if req.is_none() {
         Cli::command().print_help()?;
@@ -91,10 +334,31 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
-    let socket_path = Config::socket_path()?;
     let req = req.unwrap();
-    let resp = match protocol::send_request(&socket_path, &req) {
+    // Only a failed connect falls through to starting or waiting for the
+    // daemon; once the request is written it is never sent again.
+    let sent = match protocol::connect(&socket_path) {
+        Ok(stream) => protocol::exchange(stream, &req),
+        Err(err) if protocol::not_listening(&err) && (cli.autostart || autostart_configured()) => {
+            match autostart_daemon(&cli, &socket_path, &req) {
+                Ok(resp) => Ok(resp),
+                Err(err) => {
+                    eprintln!("simplay: could not start the daemon: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Err(err) if protocol::not_listening(&err) && cli.wait.is_some() => {
+            send_with_wait(&socket_path, &req, cli.wait)
+        }
+        Err(err) => Err(err.into()),
+    };
+    let resp = match sent {
         Ok(resp) => resp,
+        Err(err) if cli.wait.is_some() => {
+            eprintln!("simplay: daemon did not come up within {}s: {}", cli.wait.unwrap_or(0), err);
+            std::process::exit(1);
+        }
         Err(err) => {
             eprintln!("simplay: daemon not running or socket unavailable: {}", err);
             eprintln!(
@@ -112,16 +376,193 @@ fn main() -> Result<()> {
 
     if let Some(status) = resp.status {
         if let Some(song) = status.song {
-            let state = if status.paused { "paused" } else { "playing" };
-            println!("{}: {} - {} ({})", state, song.artist, song.title, song.album);
+            let state = if status.paused {
+                "paused"
+            } else if status.buffering {
+                "buffering"
+            } else {
+                "playing"
+            };
+            match status.source {
+                Some(source) => println!("{}: {} ({})", state, song.title, source),
+                None => println!("{}: {} - {} ({})", state, song.artist, song.title, song.album),
+            }
+            if let Some(position) = status.position {
+                match status.ends_in_secs {
+                    Some(left) => println!("position: {} (ends in {})", clock(position as u64), clock(left)),
+                    None => println!("position: {}", clock(position as u64)),
+                }
+            }
             println!("queue: {} | index: {}", status.queue_len, status.index);
+            if let Some(left) = status.repeats_left {
+                println!("repeats left: {}", left);
+            }
+            if status.pause_after {
+                println!("will pause after current");
+            }
+            if let Some(chapter) = status.chapter {
+                match status.chapter_title.as_deref() {
+                    Some(title) => println!("chapter {}: {}", chapter + 1, title),
+                    None => println!("chapter {}", chapter + 1),
+                }
+            }
+            if status.halted_by_errors {
+                println!(
+                    "halted after {} failed tracks (--resume-after-error to retry)",
+                    status.consecutive_errors
+                );
+            }
+            if status.gapless {
+                println!("gapless: next track preloaded");
+            }
+            if status.transition != "none" {
+                println!("transition: {}", status.transition);
+            }
+            if status.normalize {
+                println!("normalization: on");
+            }
+            if let Some(folder) = status.folder {
+                println!("folder: {}", folder);
+            }
         } else {
             println!("idle");
         }
     } else {
         println!("{}", resp.message);
+        if let Some(data) = resp.data {
+            if req.cmd == "playlistcontents" {
+                let songs: Vec<SongInfo> = serde_json::from_value(data)?;
+                for (number, song) in songs.iter().enumerate() {
+                    println!("{:>4}. {} - {} ({})", number + 1, song.artist, song.title, song.album);
+                }
+            } else {
+                print_data(&data)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn autostart_configured() -> bool {
+    Config::load_existing()
+        .map(|(config, _)| config.autostart())
+        .unwrap_or(false)
+}
+
+/// Spawns `simplay --daemon` detached from this terminal, then sends `req`
+/// once it is listening (within `--wait`, default 10 seconds).
+fn autostart_daemon(cli: &Cli, socket_path: &Path, req: &Request) -> Result<protocol::Response> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("--daemon");
+    if let Some(socket) = cli.socket.as_ref() {
+        command.arg("--socket").arg(socket);
     }
+    // Own process group, so Ctrl-C in this terminal doesn't reach it.
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+    let deadline = Instant::now() + Duration::from_secs(cli.wait.unwrap_or(10));
+    let mut delay = Duration::from_millis(50);
+    loop {
+        // Only the connect is retried; a request the daemon accepted may
+        // already have run.
+        match protocol::connect(socket_path) {
+            Ok(stream) => return protocol::exchange(stream, req),
+            Err(err) if !protocol::not_listening(&err) => return Err(err.into()),
+            Err(_) => {}
+        }
+        if let Some(status) = child.try_wait()? {
+            return Err(anyhow!(
+                "daemon exited ({}) before it was ready; run `simplay --daemon` to see why",
+                status
+            ));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(anyhow!("daemon was not listening on {} in time", socket_path.display()));
+        }
+        std::thread::sleep(delay.min(deadline - now));
+        delay = (delay * 2).min(Duration::from_secs(1));
+    }
+}
+
+/// Subscribes to the daemon's status and prints one line per change.
+fn follow_status(socket_path: &Path) -> Result<()> {
+    protocol::follow(socket_path, &Request::new("subscribe", None), |resp| {
+        if !resp.ok {
+            eprintln!("simplay: {}", resp.message);
+            std::process::exit(1);
+        }
+        // Heartbeats carry no status.
+        let status = match resp.status {
+            Some(status) => status,
+            None => return,
+        };
+        match status.song {
+            Some(song) => {
+                let state = if status.paused {
+                    "paused"
+                } else if status.buffering {
+                    "buffering"
+                } else {
+                    "playing"
+                };
+                println!("{}: {} - {} ({})", state, song.artist, song.title, song.album);
+            }
+            None => println!("idle"),
+        }
+    })?;
+    eprintln!("simplay: the daemon closed the connection");
+    Ok(())
+}
+
+/// Sends `req`, retrying the connect with growing delays for up to `wait`
+/// seconds while the daemon's socket is not accepting connections yet. The
+/// request itself is sent at most once.
+fn send_with_wait(socket_path: &Path, req: &Request, wait: Option<u64>) -> Result<protocol::Response> {
+    let deadline = wait.map(|secs| Instant::now() + Duration::from_secs(secs));
+    let mut delay = Duration::from_millis(50);
+    loop {
+        match protocol::connect(socket_path) {
+            Ok(stream) => return protocol::exchange(stream, req),
+            Err(err) if !protocol::not_listening(&err) => return Err(err.into()),
+            Err(err) => {
+                let deadline = match deadline {
+                    Some(deadline) => deadline,
+                    None => return Err(err.into()),
+                };
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(err.into());
+                }
+                std::thread::sleep(delay.min(deadline - now));
+                delay = (delay * 2).min(Duration::from_secs(1));
+            }
+        }
+    }
+}
 
+fn clock(secs: u64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn print_data(data: &serde_json::Value) -> Result<()> {
+    if let Some(items) = data.as_array() {
+        if items.iter().all(|item| item.is_string()) {
+            for item in items.iter().filter_map(|item| item.as_str()) {
+                println!("{}", item);
+            }
+            return Ok(());
+        }
+    }
+    println!("{}", serde_json::to_string_pretty(data)?);
     Ok(())
 }
 
@@ -131,9 +572,33 @@ fn build_request(cli: &Cli) -> Result<Option<Request>> {
     if cli.shuffle {
         requests.push(Request::new("shuffle", None));
     }
+    if cli.shuffle_keep_album {
+        requests.push(Request::new("shufflekeepalbum", None));
+    }
+    if cli.dedupe {
+        requests.push(Request::new("dedupe", None));
+    }
+    if cli.group_by_artist {
+        requests.push(Request::new("groupbyartist", None));
+    }
+    if let Some(position) = cli.requeue_current.clone() {
+        requests.push(Request::new("requeuecurrent", Some(position)));
+    }
+    if cli.sort_by_rating {
+        requests.push(Request::new("sortbyrating", None));
+    }
+    if cli.refresh_cache {
+        requests.push(Request::new("refreshcache", None));
+    }
     if cli.pause {
         requests.push(Request::new("pause", None));
     }
+    if cli.pause_after_track {
+        requests.push(Request::new("pauseafter", None));
+    }
+    if cli.soft_pause {
+        requests.push(Request::new("softpause", None));
+    }
     if cli.play {
         requests.push(Request::new("play", None));
     }
@@ -146,12 +611,27 @@ fn build_request(cli: &Cli) -> Result<Option<Request>> {
     if cli.startover {
         requests.push(Request::new("startover", None));
     }
+    if let Some(offset) = cli.seek.clone() {
+        requests.push(Request::new("seek", Some(offset)));
+    }
+    if let Some(count) = cli.repeat_count {
+        requests.push(Request::new("repeatcount", Some(count.to_string())));
+    }
+    if cli.undo {
+        requests.push(Request::new("undo", None));
+    }
+    if cli.restartqueue {
+        requests.push(Request::new("restartqueue", None));
+    }
     if cli.likesong {
         requests.push(Request::new("likesong", None));
     }
     if cli.unlikesong {
         requests.push(Request::new("unlikesong", None));
     }
+    if cli.love {
+        requests.push(Request::new("love", None));
+    }
     if let Some(rating) = cli.rate {
         if !(1..=5).contains(&rating) {
             return Err(anyhow!("Rating must be between 1 and 5"));
@@ -164,12 +644,66 @@ fn build_request(cli: &Cli) -> Result<Option<Request>> {
     if cli.volumedown {
         requests.push(Request::new("volumedown", None));
     }
+    if let Some(mode) = cli.normalize.clone() {
+        requests.push(Request::new("normalize", Some(mode)));
+    }
+    if let Some(mode) = cli.announce.clone() {
+        requests.push(Request::new("announce", Some(mode)));
+    }
+    if let Some(mode) = cli.local_playback.clone() {
+        requests.push(Request::new("localplayback", Some(mode)));
+    }
+    if let Some(mode) = cli.scrobble_on_skip.clone() {
+        requests.push(Request::new("scrobbleonskip", Some(mode)));
+    }
     if cli.shuffleliked {
         requests.push(Request::new("shuffleliked", None));
     }
     if cli.status {
         requests.push(Request::new("status", None));
     }
+    if cli.list_folders {
+        requests.push(Request::new("listfolders", None));
+    }
+    if let Some(folder) = cli.set_folder.clone() {
+        requests.push(Request::new("setfolder", Some(folder)));
+    }
+    if let Some(ms) = cli.grace {
+        requests.push(Request::new("grace", Some(ms.to_string())));
+    }
+    if cli.grace_stats {
+        requests.push(Request::new("gracestats", None));
+    }
+    if cli.prefetch {
+        requests.push(Request::new("prefetch", None));
+    }
+    if cli.rescan {
+        requests.push(Request::new("rescan", None));
+    }
+    if cli.scan_status {
+        requests.push(Request::new("scanstatus", None));
+    }
+    if cli.audio_devices {
+        requests.push(Request::new("audiodevices", None));
+    }
+    if let Some(device) = cli.audio_device.clone() {
+        requests.push(Request::new("audiodevice", Some(device)));
+    }
+    if cli.health {
+        requests.push(Request::new("health", None));
+    }
+    if cli.song_info {
+        requests.push(Request::new("songinfo", None));
+    }
+    if cli.track_info {
+        requests.push(Request::new("trackinfo", None));
+    }
+    if cli.diagnose {
+        requests.push(Request::new("diagnose", None));
+    }
+    if cli.stream_url {
+        requests.push(Request::new("streamurl", None));
+    }
 
     if let Some(artist) = cli.shuffleartist.clone() {
         requests.push(Request::new("shuffleartist", Some(artist)));
@@ -183,18 +717,119 @@ fn build_request(cli: &Cli) -> Result<Option<Request>> {
     if let Some(album) = cli.playalbum.clone() {
         requests.push(Request::new("playalbum", Some(album)));
     }
+    if let Some(track) = cli.play_track.clone() {
+        requests.push(Request::new("playtrack", Some(track)));
+    }
+    if let Some(album) = cli.album_goto.clone() {
+        requests.push(Request::new("albumgoto", Some(album)));
+    }
+    if let Some(number) = cli.track {
+        requests.push(Request::new("gototrack", Some(number.to_string())));
+    }
+    if cli.next_disc {
+        requests.push(Request::new("nextdisc", None));
+    }
+    if cli.prev_disc {
+        requests.push(Request::new("prevdisc", None));
+    }
+    if cli.finish_album {
+        requests.push(Request::new("finishalbum", None));
+    }
+    if cli.instant_mix {
+        requests.push(Request::new("instantmix", None));
+    }
+    if cli.resume_after_error {
+        requests.push(Request::new("resumeaftererror", None));
+    }
+    if cli.random_track {
+        requests.push(Request::new("randomtrack", None));
+    }
+    if cli.resume_album {
+        requests.push(Request::new("resumealbum", None));
+    }
+    if let Some(count) = cli.random_albums.clone() {
+        requests.push(Request::new("shufflerandomalbums", Some(count)));
+    }
+    if let Some(artist) = cli.play_artist.clone() {
+        requests.push(Request::new("playartist", Some(artist)));
+    }
+    if let Some(album) = cli.queue_album_next.clone() {
+        requests.push(Request::new("queuealbumnext", Some(album)));
+    }
+    if let Some(url) = cli.play_url.clone() {
+        requests.push(Request::new("playurl", Some(url)));
+    }
+    if let Some(year) = cli.play_year {
+        requests.push(Request::new("playyear", Some(year.to_string())));
+    }
+    if let Some(mode) = cli.transition.clone() {
+        requests.push(Request::new("transition", Some(mode)));
+    }
+    if let Some(artist) = cli.similar_to.clone() {
+        requests.push(Request::new("similarartists", Some(artist)));
+    }
+    if let Some(album) = cli.album_info.clone() {
+        requests.push(Request::new("albuminfo", Some(album)));
+    }
+    if let Some(name) = cli.bookmark.clone() {
+        requests.push(Request::new("bookmark", Some(name)));
+    }
+    if let Some(name) = cli.goto_bookmark.clone() {
+        requests.push(Request::new("gotobookmark", Some(name)));
+    }
+    if let Some(parts) = cli.resolve.as_ref() {
+        requests.push(Request::new("resolve", Some(parts.join(" "))));
+    }
+    if let Some(path) = cli.export_history.clone() {
+        // The daemon has its own working directory.
+        let path = if path.is_absolute() {
+            path
+        } else {
+            std::env::current_dir()?.join(path)
+        };
+        requests.push(Request::new("exporthistory", Some(path.display().to_string())));
+    }
+    if cli.usage_stats {
+        requests.push(Request::new("usagestats", None));
+    }
+    if cli.failures {
+        requests.push(Request::new("failures", None));
+    }
+    if cli.clear_failures {
+        requests.push(Request::new("clearfailures", None));
+    }
     if let Some(playlist) = cli.addsongtoplaylist.clone() {
         requests.push(Request::new("addsongtoplaylist", Some(playlist)));
     }
     if let Some(playlist) = cli.deleteplaylist.clone() {
         requests.push(Request::new("deleteplaylist", Some(playlist)));
     }
+    if let Some(query) = cli.search.clone() {
+        requests.push(Request::new("search", Some(query)));
+    }
+    if let Some(number) = cli.play_result {
+        requests.push(Request::new("playresult", Some(number.to_string())));
+    }
+    if let Some(playlist) = cli.share_playlist.clone() {
+        requests.push(Request::new("shareplaylist", Some(playlist)));
+    }
+    if let Some(playlist) = cli.playlist_contents.clone() {
+        requests.push(Request::new("playlistcontents", Some(playlist)));
+    }
 
     if requests.len() > 1 {
         return Err(anyhow!("Only one action can be specified at a time"));
     }
 
-    Ok(requests.pop())
+    let mut req = requests.pop();
+    if let Some(req) = req.as_mut() {
+        req.min_track_secs = cli.min_secs;
+        req.min_rating = cli.min_rating;
+        req.include_unrated = cli.include_unrated;
+        req.limit = cli.limit;
+        req.seed = cli.seed;
+    }
+    Ok(req)
 }
 
 fn run_api_call(config: &Config, endpoint: &str, params: &[String]) -> Result<()> {
@@ -219,3 +854,49 @@ fn split_param(param: &str) -> Result<(&str, String)> {
     }
     Ok((key, value.to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+    use std::os::unix::net::UnixListener;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("simplay-test-{}-{}.sock", std::process::id(), name))
+    }
+
+    #[test]
+    fn a_missing_socket_is_not_listening() {
+        let err = protocol::connect(&socket_path("missing")).unwrap_err();
+        assert!(protocol::not_listening(&err));
+    }
+
+    #[test]
+    fn a_failed_reply_is_not_sent_again() {
+        let path = socket_path("noreply");
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            listener.set_nonblocking(true).unwrap();
+            let mut requests = 0;
+            let until = Instant::now() + Duration::from_millis(500);
+            while Instant::now() < until {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false).unwrap();
+                        let mut line = String::new();
+                        std::io::BufReader::new(stream).read_line(&mut line).unwrap();
+                        requests += 1;
+                        // Closing without a reply, like a daemon shutting down.
+                    }
+                    Err(_) => std::thread::sleep(Duration::from_millis(10)),
+                }
+            }
+            requests
+        });
+        let sent = send_with_wait(&path, &Request::new("volumeup", None), Some(1));
+        assert!(sent.is_err());
+        assert_eq!(server.join().unwrap(), 1);
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -1,3 +1,4 @@
+use crate::config::Config;
 use anyhow::{anyhow, Context, Result};
 use serde_json::{json, Value};
 use std::env;
@@ -9,10 +10,18 @@ use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use std::sync::{mpsc::Sender, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const OBSERVED_PROPERTIES: &[&str] = &["core-idle", "paused-for-cache"];
+/// Oldest mpv (major, minor) simplay is known to work with.
+const MIN_MPV_VERSION: (u32, u32) = (0, 33);
+/// How often spawn checks for mpv's IPC socket while it starts.
+const STARTUP_POLL_MS: u64 = 50;
 
 pub enum MpvEvent {
-    EndFile { reason: Option<String> },
+    /// `file_error` is mpv's description of why an `error` end happened.
+    EndFile { reason: Option<String>, file_error: Option<String> },
+    PropertyChange { name: String, data: Value },
 }
 
 struct MpvIpc {
@@ -24,19 +33,32 @@ struct MpvIpc {
 pub struct MpvController {
     ipc_path: PathBuf,
     ipc: Mutex<MpvIpc>,
-    _child: Child,
+    child: Mutex<Child>,
+    /// mpv's `mpv-version`, e.g. "mpv 0.38.0".
+    version: Option<String>,
 }
 
 impl MpvController {
-    pub fn spawn(ipc_path: &Path) -> Result<Self> {
+    pub fn spawn(ipc_path: &Path, config: &Config) -> Result<Self> {
         if ipc_path.exists() {
             fs::remove_file(ipc_path).ok();
         }
 
         let mpv_bin = env::var("SIMPLAY_MPV").unwrap_or_else(|_| "mpv".to_string());
         let mut cmd = Command::new(&mpv_bin);
-        cmd.arg("--no-video")
-            .arg("--idle=yes")
+        // mpv reads its normal user config unless pointed elsewhere. Options
+        // given here are applied after config files, so a user config can't
+        // move the IPC socket or undo idle/keep-open.
+        if let Some(dir) = config.mpv_config_dir.as_deref() {
+            if !Path::new(dir).is_dir() {
+                return Err(anyhow!("mpv_config_dir {} is not a directory", dir));
+            }
+            cmd.arg(format!("--config-dir={}", dir));
+        }
+        if !config.video_enabled() {
+            cmd.arg("--no-video");
+        }
+        cmd.arg("--idle=yes")
             .arg("--keep-open=yes")
             .arg("--audio-display=no")
             .arg("--no-terminal")
@@ -47,7 +69,7 @@ impl MpvController {
             .stdout(Stdio::null())
             .stderr(Stdio::null());
 
-        let child = cmd.spawn().map_err(|err| {
+        let mut child = cmd.spawn().map_err(|err| {
             if err.kind() == ErrorKind::NotFound {
                 anyhow!(
                     "mpv binary '{}' not found. Install mpv or set SIMPLAY_MPV to its path.",
@@ -58,13 +80,27 @@ impl MpvController {
             }
         })?;
 
-        let mut attempts = 0;
+        let timeout = Duration::from_millis(config.mpv_startup_timeout_ms());
+        let started = Instant::now();
         while !ipc_path.exists() {
-            if attempts > 40 {
-                return Err(anyhow!("Timed out waiting for mpv IPC socket"));
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(anyhow!(
+                    "mpv ('{}') exited during startup with {}; run it by hand to see why (e.g. a bad option in mpv.conf)",
+                    mpv_bin,
+                    status
+                ));
             }
-            attempts += 1;
-            thread::sleep(Duration::from_millis(50));
+            if started.elapsed() >= timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(anyhow!(
+                    "Timed out after {} ms waiting for mpv ('{}') to open its IPC socket {}; raise mpv_startup_timeout_ms on slow systems",
+                    timeout.as_millis(),
+                    mpv_bin,
+                    ipc_path.display()
+                ));
+            }
+            thread::sleep(Duration::from_millis(STARTUP_POLL_MS));
         }
 
         let stream = UnixStream::connect(ipc_path).context("Failed to connect mpv IPC")?;
@@ -76,15 +112,59 @@ impl MpvController {
             next_id: 1,
         };
 
-        Ok(Self {
+        let mut mpv = Self {
             ipc_path: ipc_path.to_path_buf(),
             ipc: Mutex::new(ipc),
-            _child: child,
-        })
+            child: Mutex::new(child),
+            version: None,
+        };
+        // Only informational, so a failed read must not fail the spawn and
+        // leave the mpv child behind.
+        mpv.version = match mpv.get_property("mpv-version") {
+            Ok(version) => version.and_then(|v| v.as_str().map(|s| s.to_string())),
+            Err(err) => {
+                eprintln!("simplay: asking mpv for its version failed: {}", err);
+                None
+            }
+        };
+        match mpv.version.as_deref() {
+            Some(version) => {
+                eprintln!("simplay: using {}", version);
+                if !mpv.version_at_least(MIN_MPV_VERSION) {
+                    eprintln!(
+                        "simplay: mpv older than {}.{} is untested; some features may not work",
+                        MIN_MPV_VERSION.0, MIN_MPV_VERSION.1
+                    );
+                }
+            }
+            None => eprintln!("simplay: could not read mpv's version"),
+        }
+        Ok(mpv)
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    /// Whether mpv is at least `(major, minor)`. Unknown or unparsable
+    /// versions (e.g. git builds) count as new enough.
+    pub fn version_at_least(&self, (major, minor): (u32, u32)) -> bool {
+        match self.version.as_deref().and_then(parse_version) {
+            Some(version) => version >= (major, minor),
+            None => true,
+        }
     }
 
     pub fn start_event_loop(&self, tx: Sender<MpvEvent>) -> Result<()> {
         let stream = UnixStream::connect(&self.ipc_path).context("Failed to connect mpv event IPC")?;
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        for (id, name) in OBSERVED_PROPERTIES.iter().enumerate() {
+            let payload = json!({ "command": ["observe_property", id + 1, name] });
+            serde_json::to_writer(&mut writer, &payload)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+
         thread::spawn(move || {
             let reader = BufReader::new(stream);
             for line in reader.lines() {
@@ -92,20 +172,51 @@ impl MpvController {
                     Ok(line) => line,
                     Err(_) => break,
                 };
-                if let Ok(value) = serde_json::from_str::<Value>(&line) {
-                    if value.get("event").and_then(|v| v.as_str()) == Some("end-file") {
+                let value = match serde_json::from_str::<Value>(&line) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                let event = match value.get("event").and_then(|v| v.as_str()) {
+                    Some("end-file") => {
                         let reason = value
                             .get("reason")
                             .and_then(|v| v.as_str())
                             .map(|s| s.to_string());
-                        let _ = tx.send(MpvEvent::EndFile { reason });
+                        let file_error = value
+                            .get("file_error")
+                            .and_then(|v| v.as_str())
+                            .map(|s| s.to_string());
+                        MpvEvent::EndFile { reason, file_error }
+                    }
+                    Some("property-change") => {
+                        let name = match value.get("name").and_then(|v| v.as_str()) {
+                            Some(name) => name.to_string(),
+                            None => continue,
+                        };
+                        let data = value.get("data").cloned().unwrap_or(Value::Null);
+                        MpvEvent::PropertyChange { name, data }
                     }
+                    _ => continue,
+                };
+                if tx.send(event).is_err() {
+                    break;
                 }
             }
         });
         Ok(())
     }
 
+    pub fn is_alive(&self) -> bool {
+        match self.child.lock() {
+            Ok(mut child) => matches!(child.try_wait(), Ok(None)),
+            Err(_) => false,
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child.lock().ok().map(|child| child.id())
+    }
+
     pub fn load(&self, url: &str) -> Result<()> {
         self.command(json!(["loadfile", url, "replace"]))?;
         Ok(())
@@ -126,6 +237,56 @@ impl MpvController {
         Ok(())
     }
 
+    /// Queues `url` in mpv's own playlist after the playing file, so mpv
+    /// moves on to it by itself when the current file ends.
+    pub fn append(&self, url: &str) -> Result<()> {
+        self.command(json!(["loadfile", url, "append"]))?;
+        Ok(())
+    }
+
+    /// Drops every entry of mpv's playlist except the playing file.
+    pub fn clear_upcoming(&self) -> Result<()> {
+        self.command(json!(["playlist-clear"]))?;
+        Ok(())
+    }
+
+    /// Lets mpv open the next playlist entry early and join it to the
+    /// current one without reopening the audio output.
+    pub fn set_gapless(&self, enabled: bool) -> Result<()> {
+        let (audio, prefetch) = if enabled { ("yes", "yes") } else { ("weak", "no") };
+        self.command(json!(["set_property", "gapless-audio", audio]))?;
+        self.command(json!(["set_property", "prefetch-playlist", prefetch]))?;
+        Ok(())
+    }
+
+    pub fn quit(&self) {
+        let _ = self.command(json!(["quit"]));
+        if let Ok(mut child) = self.child.lock() {
+            for _ in 0..20 {
+                if !matches!(child.try_wait(), Ok(None)) {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// Adds or removes a labelled `dynaudnorm` filter, leaving any other
+    /// audio filters in the chain untouched.
+    pub fn set_dynamic_normalization(&self, enabled: bool) -> Result<()> {
+        let resp = if enabled {
+            self.command(json!(["af", "add", "@simplay-norm:dynaudnorm"]))?
+        } else {
+            self.command(json!(["af", "remove", "@simplay-norm"]))?
+        };
+        match resp.get("error").and_then(|v| v.as_str()) {
+            Some("success") | None => Ok(()),
+            Some(err) => Err(anyhow!("mpv rejected audio filter: {}", err)),
+        }
+    }
+
     pub fn set_volume(&self, volume: f64) -> Result<()> {
         self.command(json!(["set_property", "volume", volume]))?;
         Ok(())
@@ -140,8 +301,48 @@ impl MpvController {
         Ok(volume)
     }
 
+    /// Output devices mpv can use, as (name, description).
+    pub fn audio_devices(&self) -> Result<Vec<(String, String)>> {
+        let devices = self
+            .get_property("audio-device-list")?
+            .and_then(|v| v.as_array().cloned())
+            .unwrap_or_default();
+        Ok(devices
+            .iter()
+            .filter_map(|device| {
+                let name = device.get("name")?.as_str()?.to_string();
+                let description = device
+                    .get("description")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or(&name)
+                    .to_string();
+                Some((name, description))
+            })
+            .collect())
+    }
+
+    pub fn audio_device(&self) -> Result<Option<String>> {
+        Ok(self
+            .get_property("audio-device")?
+            .and_then(|v| v.as_str().map(|s| s.to_string())))
+    }
+
+    pub fn set_audio_device(&self, name: &str) -> Result<()> {
+        let resp = self.command(json!(["set_property", "audio-device", name]))?;
+        match resp.get("error").and_then(|v| v.as_str()) {
+            Some("success") | None => Ok(()),
+            Some(err) => Err(anyhow!("mpv rejected audio device {}: {}", name, err)),
+        }
+    }
+
     pub fn get_time_pos(&self) -> Result<Option<f64>> {
-        let resp = self.command(json!(["get_property", "time-pos"]))?;
+        Ok(self.get_property("time-pos")?.and_then(|v| v.as_f64()))
+    }
+
+    /// Reads an mpv property, returning `None` when it is unavailable (e.g.
+    /// no file loaded).
+    pub fn get_property(&self, name: &str) -> Result<Option<Value>> {
+        let resp = self.command(json!(["get_property", name]))?;
         if resp
             .get("error")
             .and_then(|v| v.as_str())
@@ -150,7 +351,7 @@ impl MpvController {
         {
             return Ok(None);
         }
-        Ok(resp.get("data").and_then(|v| v.as_f64()))
+        Ok(resp.get("data").cloned().filter(|v| !v.is_null()))
     }
 
     fn command(&self, command: Value) -> Result<Value> {
@@ -183,3 +384,13 @@ impl MpvController {
         }
     }
 }
+
+/// (major, minor) from an `mpv-version` string like "mpv 0.38.0" or
+/// "mpv v0.37.0-dirty".
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let number = version.split_whitespace().nth(1)?.trim_start_matches('v');
+    let mut parts = number.split(|c: char| !c.is_ascii_digit());
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
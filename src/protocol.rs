@@ -1,12 +1,22 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::os::unix::net::UnixStream;
 use std::path::Path;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Request {
     pub cmd: String,
     pub arg: Option<String>,
+    pub min_track_secs: Option<u32>,
+    pub min_rating: Option<u8>,
+    #[serde(default)]
+    pub include_unrated: bool,
+    /// Cap on how many entries a listing returns.
+    pub limit: Option<usize>,
+    /// Seeds a shuffle so the same songs come out in the same order.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
 impl Request {
@@ -14,6 +24,11 @@ impl Request {
         Self {
             cmd: cmd.to_string(),
             arg,
+            min_track_secs: None,
+            min_rating: None,
+            include_unrated: false,
+            limit: None,
+            seed: None,
         }
     }
 }
@@ -23,6 +38,9 @@ pub struct Response {
     pub ok: bool,
     pub message: String,
     pub status: Option<Status>,
+    pub data: Option<Value>,
+    /// Subsonic error code behind a failed command, if the server sent one.
+    pub code: Option<u32>,
 }
 
 impl Response {
@@ -31,6 +49,8 @@ impl Response {
             ok: true,
             message: message.into(),
             status: None,
+            data: None,
+            code: None,
         }
     }
 
@@ -39,16 +59,145 @@ impl Response {
             ok: false,
             message: message.into(),
             status: None,
+            data: None,
+            code: None,
         }
     }
+
+    pub fn with_code(mut self, code: Option<u32>) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub fn with_data(mut self, data: impl Serialize) -> Self {
+        self.data = serde_json::to_value(data).ok();
+        self
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Status {
     pub song: Option<SongInfo>,
     pub paused: bool,
+    pub buffering: bool,
+    pub source: Option<String>,
+    pub normalize: bool,
+    pub folder: Option<String>,
     pub queue_len: usize,
     pub index: usize,
+    pub pause_after: bool,
+    pub repeats_left: Option<u32>,
+    /// Seconds into the current track.
+    pub position: Option<f64>,
+    /// Estimated seconds until auto-advance, as of this reply; `None` while
+    /// paused or when the duration is unknown.
+    pub ends_in_secs: Option<u64>,
+    /// The next track is already in mpv's playlist, so the transition can
+    /// be gapless.
+    #[serde(default)]
+    pub gapless: bool,
+    /// Current chapter index for chaptered files (audiobooks, mixes).
+    #[serde(default)]
+    pub chapter: Option<u32>,
+    #[serde(default)]
+    pub chapter_title: Option<String>,
+    /// Tracks in a row that failed to load.
+    #[serde(default)]
+    pub consecutive_errors: u32,
+    /// Playback was halted by `max_consecutive_errors`.
+    #[serde(default)]
+    pub halted_by_errors: bool,
+    /// How tracks follow each other: `none` or `gap`.
+    #[serde(default)]
+    pub transition: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub uptime_secs: u64,
+    pub mpv_alive: bool,
+    pub mpv_pid: Option<u32>,
+    /// The client name the server sees.
+    pub client: String,
+    pub volume_min: u8,
+    pub volume_max: u8,
+    pub mpv_version: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TrackInfo {
+    pub codec: Option<String>,
+    /// mpv's `audio-codec-name`, e.g. `opus`, to check what a transcode
+    /// actually delivered.
+    #[serde(default)]
+    pub codec_name: Option<String>,
+    /// Container mpv opened, e.g. `ogg`.
+    #[serde(default)]
+    pub file_format: Option<String>,
+    pub bitrate_kbps: Option<u64>,
+    pub sample_rate: Option<u64>,
+    pub channels: Option<u64>,
+}
+
+/// Whether mpv is really playing, from two position samples.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Diagnosis {
+    /// `advancing`, `paused`, `stalled (buffering)`, `stalled` or `idle`.
+    pub verdict: String,
+    pub position_before: Option<f64>,
+    pub position_after: Option<f64>,
+    pub paused: bool,
+    pub paused_for_cache: bool,
+    pub core_idle: bool,
+    pub idle: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AlbumDetails {
+    pub name: String,
+    pub artist: String,
+    pub year: Option<u32>,
+    pub notes: Option<String>,
+    pub links: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SongDetails {
+    pub id: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
+    pub track: Option<u32>,
+    pub disc: Option<u32>,
+    pub duration: Option<u32>,
+    pub starred: bool,
+    pub rating: Option<u8>,
+    pub play_count: Option<u64>,
+    pub bit_rate: Option<u32>,
+    pub format: Option<String>,
+    pub content_type: Option<String>,
+    pub path: Option<String>,
+    pub cover_art: Option<String>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+}
+
+/// A name lookup answered without playing anything; `matches` is best
+/// first, so its head is what the play commands would pick.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Resolved {
+    pub kind: String,
+    pub query: String,
+    pub matches: Vec<ResolvedMatch>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResolvedMatch {
+    pub id: String,
+    pub name: String,
+    pub score: i32,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -59,8 +208,43 @@ pub struct SongInfo {
     pub album: String,
 }
 
-pub fn send_request(socket_path: &Path, req: &Request) -> anyhow::Result<Response> {
+/// Sends `req` and hands every line the daemon writes back to `each` until
+/// it closes the connection; for `subscribe`.
+pub fn follow(socket_path: &Path, req: &Request, mut each: impl FnMut(Response)) -> anyhow::Result<()> {
     let stream = UnixStream::connect(socket_path)?;
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let reader = BufReader::new(stream);
+
+    serde_json::to_writer(&mut writer, req)?;
+    writer.write_all(b"\n")?;
+    writer.flush()?;
+
+    for line in reader.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            each(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(())
+}
+
+/// Connects to the daemon without sending anything, so a caller waiting for
+/// the daemon can retry this part alone.
+pub fn connect(socket_path: &Path) -> std::io::Result<UnixStream> {
+    UnixStream::connect(socket_path)
+}
+
+/// Whether a `connect` error means nothing is listening on the socket (yet).
+pub fn not_listening(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        std::io::ErrorKind::ConnectionRefused | std::io::ErrorKind::NotFound
+    )
+}
+
+/// Sends `req` over a connected `stream` and reads the reply. Once this has
+/// started the daemon may have acted on `req`, so failures are not retried.
+pub fn exchange(stream: UnixStream, req: &Request) -> anyhow::Result<Response> {
     let mut writer = BufWriter::new(stream.try_clone()?);
     let mut reader = BufReader::new(stream);
 
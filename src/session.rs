@@ -0,0 +1,135 @@
+use crate::config::Config;
+use crate::subsonic::Song;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// Playback state saved on shutdown so the next daemon start can resume it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Session {
+    pub queue: Vec<Song>,
+    pub index: usize,
+    pub position: f64,
+    pub repeat: bool,
+    pub shuffle: bool,
+}
+
+/// Where playback stood inside an album, kept locally so replaying the album
+/// can continue there even when the server has no bookmark support.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlbumPosition {
+    pub song_id: String,
+    pub position: f64,
+}
+
+pub fn load() -> Result<Option<Session>> {
+    let path = Config::session_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading session {}", path.display()))?;
+    let session = serde_json::from_str(&contents).context("Invalid session file format")?;
+    Ok(Some(session))
+}
+
+pub fn save(session: &Session) -> Result<()> {
+    let path = Config::session_path()?;
+    write_private(&path, &serde_json::to_vec(session)?)
+}
+
+pub fn album_position(album_id: &str) -> Result<Option<AlbumPosition>> {
+    Ok(load_album_positions()?.remove(album_id))
+}
+
+/// Applies several album position changes with one read and one write;
+/// `None` forgets that album's position.
+pub fn update_album_positions(changes: HashMap<String, Option<AlbumPosition>>) -> Result<()> {
+    let mut positions = load_album_positions()?;
+    for (album_id, change) in changes {
+        match change {
+            Some(position) => positions.insert(album_id, position),
+            None => positions.remove(&album_id),
+        };
+    }
+    write_private(&Config::album_positions_path()?, &serde_json::to_vec(&positions)?)
+}
+
+fn load_album_positions() -> Result<HashMap<String, AlbumPosition>> {
+    let path = Config::album_positions_path()?;
+    if !path.exists() {
+        return Ok(HashMap::new());
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading album positions {}", path.display()))?;
+    serde_json::from_str(&contents).context("Invalid album positions file format")
+}
+
+/// The volume each audio device was left at, by mpv device name.
+pub fn device_volume(device: &str) -> Result<Option<f64>> {
+    Ok(load_device_volumes()?.remove(device))
+}
+
+pub fn save_device_volume(device: &str, volume: f64) -> Result<()> {
+    let mut volumes = load_device_volumes()?;
+    volumes.insert(device.to_string(), volume);
+    write_private(&Config::device_volumes_path()?, &serde_json::to_vec(&volumes)?)
+}
+
+fn load_device_volumes() -> Result<BTreeMap<String, f64>> {
+    let path = Config::device_volumes_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading device volumes {}", path.display()))?;
+    serde_json::from_str(&contents).context("Invalid device volumes file format")
+}
+
+/// Counts one more full play of `song_id` and returns the new total.
+pub fn record_completion(song_id: &str) -> Result<u32> {
+    let path = Config::completions_path()?;
+    let mut counts: BTreeMap<String, u32> = if path.exists() {
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed reading completions {}", path.display()))?;
+        serde_json::from_str(&contents).context("Invalid completions file format")?
+    } else {
+        BTreeMap::new()
+    };
+    let count = counts.entry(song_id.to_string()).or_insert(0);
+    *count += 1;
+    let count = *count;
+    write_private(&path, &serde_json::to_vec(&counts)?)?;
+    Ok(count)
+}
+
+/// How often each daemon command was used, when `usage_stats` is on.
+pub fn load_usage_stats() -> Result<BTreeMap<String, u64>> {
+    let path = Config::usage_stats_path()?;
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed reading usage stats {}", path.display()))?;
+    serde_json::from_str(&contents).context("Invalid usage stats file format")
+}
+
+pub fn save_usage_stats(counts: &BTreeMap<String, u64>) -> Result<()> {
+    write_private(&Config::usage_stats_path()?, &serde_json::to_vec(counts)?)
+}
+
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut handle = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    handle.write_all(contents)?;
+    handle.flush()?;
+    Ok(())
+}
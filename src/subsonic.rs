@@ -1,20 +1,73 @@
 use crate::config::Config;
-use anyhow::{anyhow, Context, Result};
 use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
 use rand::Rng;
 use reqwest::blocking::Client;
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use std::fmt;
+use std::io::{BufReader, Read};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Song {
     pub id: String,
     pub title: String,
     pub artist: String,
     pub album: String,
+    pub album_id: Option<String>,
+    pub artist_id: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<u32>,
     pub duration: Option<u32>,
     pub track: Option<u32>,
     pub disc: Option<u32>,
+    pub starred: bool,
+    pub user_rating: Option<u8>,
+    pub play_count: Option<u64>,
+    pub bit_rate: Option<u32>,
+    pub suffix: Option<String>,
+    pub content_type: Option<String>,
+    pub path: Option<String>,
+    pub cover_art: Option<String>,
+    pub track_gain: Option<f64>,
+    pub album_gain: Option<f64>,
+    /// OpenSubsonic `explicitStatus`; `None` when the server does not say.
+    pub explicit: Option<bool>,
+    pub queue_group: Option<u32>,
+}
+
+impl Song {
+    /// A placeholder for media that does not come from the Subsonic server.
+    pub fn external(url: &str) -> Self {
+        Self {
+            id: url.to_string(),
+            title: url.to_string(),
+            artist: "External".to_string(),
+            album: String::new(),
+            album_id: None,
+            artist_id: None,
+            genre: None,
+            year: None,
+            duration: None,
+            track: None,
+            disc: None,
+            starred: false,
+            user_rating: None,
+            play_count: None,
+            bit_rate: None,
+            suffix: None,
+            content_type: None,
+            path: None,
+            cover_art: None,
+            track_gain: None,
+            album_gain: None,
+            explicit: None,
+            queue_group: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -23,6 +76,153 @@ pub struct Item {
     pub name: String,
 }
 
+#[derive(Debug, Clone)]
+pub struct Album {
+    pub id: String,
+    pub name: String,
+    pub artist: String,
+    pub year: Option<u32>,
+    pub created: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AlbumInfo {
+    pub notes: Option<String>,
+    pub musicbrainz_id: Option<String>,
+    pub lastfm_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchResults {
+    pub artists: Vec<SearchEntry>,
+    pub albums: Vec<SearchEntry>,
+    pub songs: Vec<Song>,
+}
+
+/// A `search3` artist or album, with the counts the server reports
+/// alongside it (`albumCount` for artists, `songCount` for albums).
+#[derive(Debug, Clone)]
+pub struct SearchEntry {
+    pub id: String,
+    pub name: String,
+    /// The album's artist; `None` for artists.
+    pub artist: Option<String>,
+    pub album_count: Option<u32>,
+    pub song_count: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ScanStatus {
+    pub scanning: bool,
+    /// Files scanned so far (or in the last scan).
+    pub count: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Bookmark {
+    pub song: Song,
+    pub position_ms: u64,
+    pub comment: String,
+}
+
+/// Why a Subsonic call failed, so callers can branch on the kind of failure
+/// instead of matching message text.
+#[derive(Debug)]
+pub enum SubsonicError {
+    /// Credentials were rejected (Subsonic codes 40/41, HTTP 401/403).
+    Auth { code: Option<u32>, message: String },
+    /// The requested item does not exist (code 70).
+    NotFound { code: Option<u32>, message: String },
+    /// The server could not be reached or did not answer in time.
+    Network(String),
+    /// Any other failure the server reported, with its Subsonic error code.
+    Server { code: Option<u32>, message: String },
+    /// The response (or a URL) could not be understood.
+    Parse(String),
+    /// The server does not implement the endpoint.
+    Unsupported(String),
+}
+
+pub type Result<T> = std::result::Result<T, SubsonicError>;
+
+impl SubsonicError {
+    /// Classifies a `status="failed"` answer by its Subsonic error code.
+    fn from_api(code: Option<u32>, message: String) -> Self {
+        let text = message.to_lowercase();
+        match code {
+            Some(40) | Some(41) => Self::Auth { code, message },
+            Some(70) => Self::NotFound { code, message },
+            _ if ["not supported", "not implemented", "unknown method"]
+                .iter()
+                .any(|marker| text.contains(marker)) =>
+            {
+                Self::Unsupported(message)
+            }
+            _ => Self::Server { code, message },
+        }
+    }
+
+    fn from_http(endpoint: &str, err: &reqwest::Error) -> Self {
+        let message = format!("Request {} failed: {}", endpoint, describe_http_error(err));
+        Self::from_http_status(err.status().map(|status| status.as_u16()), err.is_decode(), message)
+    }
+
+    /// Classifies a failed HTTP exchange by its status, or, without one, by
+    /// whether the body failed to decode.
+    fn from_http_status(status: Option<u16>, is_decode: bool, message: String) -> Self {
+        match status {
+            Some(401) | Some(403) => Self::Auth { code: None, message },
+            Some(404) | Some(501) => Self::Unsupported(message),
+            Some(_) => Self::Server { code: None, message },
+            None if is_decode => Self::Parse(message),
+            None => Self::Network(message),
+        }
+    }
+
+    /// The Subsonic error code, when the server sent one.
+    pub fn code(&self) -> Option<u32> {
+        match self {
+            Self::Auth { code, .. } | Self::NotFound { code, .. } | Self::Server { code, .. } => *code,
+            _ => None,
+        }
+    }
+
+    /// What the user can do about the error, where there is a clear fix.
+    pub fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::Auth { code: Some(41), .. } => Some("the server does not accept token auth"),
+            Self::Auth { .. } => Some("authentication failed, re-run --configure"),
+            Self::Server { code: Some(10), .. } => Some("a required parameter is missing"),
+            Self::Server { code: Some(20 | 30), .. } => {
+                Some("client and server API versions are incompatible; check api_version")
+            }
+            Self::Server { code: Some(50), .. } => Some("this user is not allowed to do that"),
+            Self::NotFound { code: Some(70), .. } => Some("not found on the server"),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for SubsonicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auth { code: Some(code), message }
+            | Self::NotFound { code: Some(code), message }
+            | Self::Server { code: Some(code), message } => {
+                write!(f, "{} (Subsonic error {})", message, code)
+            }
+            Self::Auth { message, .. }
+            | Self::NotFound { message, .. }
+            | Self::Server { message, .. }
+            | Self::Network(message)
+            | Self::Parse(message)
+            | Self::Unsupported(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for SubsonicError {}
+
 #[derive(Clone)]
 pub struct NavidromeClient {
     base_url: String,
@@ -31,28 +231,102 @@ pub struct NavidromeClient {
     api_version: String,
     client_name: String,
     endpoint_suffix: String,
-    http: Client,
+    random_songs_retries: u32,
+    music_folder: Arc<Mutex<Option<Item>>>,
+    extra_params: Vec<(String, String)>,
+    /// `stream` or `download`.
+    playback_endpoint: &'static str,
+    tls_verify: bool,
+    /// Shared by all clones so `reconnect` replaces it everywhere.
+    http: Arc<Mutex<Client>>,
 }
 
+/// Query parameters that carry authentication and response format; config
+/// `extra_params` may not replace them.
+const RESERVED_PARAMS: &[&str] = &["u", "t", "s", "v", "f", "p"];
+
 impl NavidromeClient {
     pub fn new(config: &Config) -> Result<Self> {
-        let mut builder = Client::builder();
-        if !config.tls_verify {
-            builder = builder.danger_accept_invalid_certs(true);
+        let http = build_http(config.tls_verify)?;
+        let mut extra_params = Vec::new();
+        for (key, value) in &config.extra_params {
+            if RESERVED_PARAMS.contains(&key.as_str()) {
+                eprintln!("simplay: ignoring extra_params.{}: it would override authentication", key);
+                continue;
+            }
+            extra_params.push((key.clone(), value.clone()));
+        }
+        let playback_endpoint = if config.download_playback() {
+            "download"
+        } else {
+            "stream"
+        };
+        if !config.playback_endpoint.trim().eq_ignore_ascii_case(playback_endpoint) {
+            eprintln!(
+                "simplay: unknown playback_endpoint \"{}\", using stream",
+                config.playback_endpoint
+            );
+        }
+        if playback_endpoint == "download" {
+            for (key, _) in extra_params.iter().filter(|(key, _)| key == "maxBitRate" || key == "format") {
+                eprintln!("simplay: extra_params.{} has no effect with playback_endpoint = \"download\"", key);
+            }
         }
-        let http = builder.timeout(Duration::from_secs(20)).build()?;
         Ok(Self {
             base_url: config.server_url.clone(),
             username: config.username.clone(),
             password: config.password.clone(),
             api_version: config.api_version.clone(),
-            client_name: config.client_name.clone(),
+            client_name: config.client_id(),
             endpoint_suffix: config.endpoint_suffix.clone(),
-            http,
+            random_songs_retries: config.random_songs_retries,
+            music_folder: Arc::new(Mutex::new(config.music_folder.clone().map(|id| Item {
+                name: id.clone(),
+                id,
+            }))),
+            extra_params,
+            playback_endpoint,
+            tls_verify: config.tls_verify,
+            http: Arc::new(Mutex::new(http)),
         })
     }
 
+    /// Drops pooled connections by replacing the HTTP client, e.g. after the
+    /// machine slept and they went stale.
+    pub fn reconnect(&self) -> Result<()> {
+        let http = build_http(self.tls_verify)?;
+        if let Ok(mut current) = self.http.lock() {
+            *current = http;
+        }
+        Ok(())
+    }
+
+    fn http(&self) -> Client {
+        match self.http.lock() {
+            Ok(http) => http.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
     pub fn request(&self, endpoint: &str, extra_params: &[(&str, String)]) -> Result<Value> {
+        let json: Value = self
+            .send(endpoint, extra_params)?
+            .json()
+            .map_err(|err| SubsonicError::from_http(endpoint, &err))?;
+        let body = json.get("subsonic-response");
+        check_status(
+            body.and_then(|v| v.get("status")).and_then(|v| v.as_str()),
+            body.and_then(|v| v.get("error")),
+        )?;
+        Ok(json)
+    }
+
+    /// Sends a request and returns the response before its body is read.
+    fn send(
+        &self,
+        endpoint: &str,
+        extra_params: &[(&str, String)],
+    ) -> Result<reqwest::blocking::Response> {
         let url = format!(
             "{}/rest/{}.{}",
             self.base_url.trim_end_matches('/'),
@@ -68,76 +342,128 @@ impl NavidromeClient {
             ("c", self.client_name.clone()),
             ("f", "json".to_string()),
         ];
+        self.merge_extra_params(&mut params, extra_params);
         for (k, v) in extra_params {
             params.push((*k, v.clone()));
         }
 
-        let resp = self
-            .http
+        self.http()
             .get(url)
             .query(&params)
             .send()
-            .with_context(|| format!("Failed request {}", endpoint))?
-            .error_for_status()?;
-        let json: Value = resp.json()?;
-        let status = json
-            .get("subsonic-response")
-            .and_then(|v| v.get("status"))
-            .and_then(|v| v.as_str())
-            .unwrap_or("failed");
-        if status != "ok" {
-            let err = json
-                .get("subsonic-response")
-                .and_then(|v| v.get("error"))
-                .and_then(|v| v.get("message"))
-                .and_then(|v| v.as_str())
-                .unwrap_or("Unknown error");
-            return Err(anyhow!(err.to_string()));
-        }
-        Ok(json)
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| SubsonicError::from_http(endpoint, &err))
     }
 
+    /// The URL mpv plays a song from: `stream` (which may transcode) or,
+    /// with `playback_endpoint = "download"`, the original file.
     pub fn stream_url(&self, song_id: &str) -> Result<String> {
         let url = format!(
-            "{}/rest/stream.{}",
+            "{}/rest/{}.{}",
             self.base_url.trim_end_matches('/'),
+            self.playback_endpoint,
             self.endpoint_suffix
         );
-        let mut url = reqwest::Url::parse(&url)?;
+        let mut url = reqwest::Url::parse(&url)
+            .map_err(|err| SubsonicError::Parse(format!("Invalid server_url: {}", err)))?;
         let (token, salt) = self.token_pair();
-        url.query_pairs_mut()
-            .append_pair("u", &self.username)
-            .append_pair("t", &token)
-            .append_pair("s", &salt)
-            .append_pair("v", &self.api_version)
-            .append_pair("c", &self.client_name)
-            .append_pair("id", song_id);
+        let mut params = vec![
+            ("u", self.username.clone()),
+            ("t", token),
+            ("s", salt),
+            ("v", self.api_version.clone()),
+            ("c", self.client_name.clone()),
+        ];
+        let id = [("id", song_id.to_string())];
+        self.merge_extra_params(&mut params, &id);
+        url.query_pairs_mut().extend_pairs(params.iter().chain(id.iter()));
         Ok(url.to_string())
     }
 
+    /// Requests the first `bytes` of a song's stream so the server starts
+    /// transcoding (and caching) it. Returns how many bytes came back.
+    pub fn warm_stream(&self, song_id: &str, bytes: u64) -> Result<u64> {
+        let url = self.stream_url(song_id)?;
+        let resp = self
+            .http()
+            .get(url)
+            .header(reqwest::header::RANGE, format!("bytes=0-{}", bytes.saturating_sub(1)))
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| SubsonicError::from_http("stream", &err))?;
+        // Servers that ignore Range send the whole file; stop after `bytes`.
+        let mut buf = Vec::new();
+        resp.take(bytes)
+            .read_to_end(&mut buf)
+            .map_err(|err| SubsonicError::Network(format!("Request stream failed: {}", err)))?;
+        Ok(buf.len() as u64)
+    }
+
+    /// Applies config `extra_params` to the base `params`: a key already
+    /// present (e.g. `c`) is overridden, others are appended. Keys the
+    /// endpoint sets itself in `endpoint_params` are left to the endpoint.
+    fn merge_extra_params<'a>(
+        &'a self,
+        params: &mut Vec<(&'a str, String)>,
+        endpoint_params: &[(&str, String)],
+    ) {
+        for (key, value) in &self.extra_params {
+            if endpoint_params.iter().any(|(k, _)| k == key) {
+                continue;
+            }
+            match params.iter_mut().find(|(k, _)| k == key) {
+                Some(param) => param.1 = value.clone(),
+                None => params.push((key.as_str(), value.clone())),
+            }
+        }
+    }
+
     pub fn get_random_songs(&self, size: usize) -> Result<Vec<Song>> {
-        let json = self.request("getRandomSongs", &[("size", size.to_string())])?;
-        let songs = json
-            .get("subsonic-response")
-            .and_then(|v| v.get("randomSongs"))
-            .and_then(|v| v.get("song"))
-            .map(parse_song_list)
-            .unwrap_or_default();
+        let fetched = retry_until_songs(self.random_songs_retries + 1, || {
+            let json = self.request(
+                "getRandomSongs",
+                &self.with_folder(vec![("size", size.to_string())]),
+            )?;
+            Ok(json
+                .get("subsonic-response")
+                .and_then(|v| v.get("randomSongs"))
+                .and_then(|v| v.get("song"))
+                .map(parse_song_list)
+                .unwrap_or_default())
+        })?;
+        if let Some(songs) = fetched {
+            return Ok(songs);
+        }
+
+        // Some servers keep answering empty; sample the full library instead.
+        let mut songs = self.all_songs()?;
+        songs.shuffle(&mut rand::thread_rng());
+        songs.truncate(size);
         Ok(songs)
     }
 
     pub fn all_songs(&self) -> Result<Vec<Song>> {
+        let mut songs = Vec::new();
+        for album_id in self.album_ids()? {
+            let mut album_songs = self.album_songs(&album_id)?;
+            songs.append(&mut album_songs);
+        }
+        Ok(songs)
+    }
+
+    /// Ids of every album in the library (or active music folder).
+    pub fn album_ids(&self) -> Result<Vec<String>> {
         let mut offset = 0;
         let page_size = 200;
         let mut album_ids = Vec::new();
         loop {
             let json = self.request(
                 "getAlbumList2",
-                &[
+                &self.with_folder(vec![
                     ("type", "alphabeticalByName".to_string()),
                     ("size", page_size.to_string()),
                     ("offset", offset.to_string()),
-                ],
+                ]),
             )?;
             let albums = json
                 .get("subsonic-response")
@@ -151,35 +477,133 @@ impl NavidromeClient {
             album_ids.extend(albums);
             offset += page_size;
         }
+        Ok(album_ids)
+    }
 
-        let mut songs = Vec::new();
-        for album_id in album_ids {
-            let mut album_songs = self.album_songs(&album_id)?;
-            songs.append(&mut album_songs);
+    pub fn albums_by_year(&self, from_year: u32, to_year: u32) -> Result<Vec<Album>> {
+        let mut offset = 0;
+        let page_size = 200;
+        let mut albums = Vec::new();
+        loop {
+            let json = self.request(
+                "getAlbumList2",
+                &self.with_folder(vec![
+                    ("type", "byYear".to_string()),
+                    ("fromYear", from_year.to_string()),
+                    ("toYear", to_year.to_string()),
+                    ("size", page_size.to_string()),
+                    ("offset", offset.to_string()),
+                ]),
+            )?;
+            let page = json
+                .get("subsonic-response")
+                .and_then(|v| v.get("albumList2"))
+                .and_then(|v| v.get("album"))
+                .map(parse_albums)
+                .unwrap_or_default();
+            if page.is_empty() {
+                break;
+            }
+            let done = page.len() < page_size;
+            albums.extend(page);
+            if done {
+                break;
+            }
+            offset += page_size;
         }
-        Ok(songs)
+        Ok(albums)
     }
 
-    pub fn find_artist(&self, query: &str) -> Result<Option<Item>> {
-        let json = self.request("search3", &[("query", query.to_string())])?;
-        let items = json
+    /// One page of albums, most recently added first.
+    pub fn newest_albums(&self, offset: usize, size: usize) -> Result<Vec<Album>> {
+        let json = self.request(
+            "getAlbumList2",
+            &self.with_folder(vec![
+                ("type", "newest".to_string()),
+                ("size", size.to_string()),
+                ("offset", offset.to_string()),
+            ]),
+        )?;
+        let albums = json
             .get("subsonic-response")
-            .and_then(|v| v.get("searchResult3"))
-            .and_then(|v| v.get("artist"))
-            .map(parse_items)
+            .and_then(|v| v.get("albumList2"))
+            .and_then(|v| v.get("album"))
+            .map(parse_albums)
             .unwrap_or_default();
-        Ok(best_match(query, &items))
+        Ok(albums)
+    }
+
+    /// Albums picked at random by the server.
+    pub fn random_albums(&self, size: usize) -> Result<Vec<Album>> {
+        let json = self.request(
+            "getAlbumList2",
+            &self.with_folder(vec![
+                ("type", "random".to_string()),
+                ("size", size.to_string()),
+            ]),
+        )?;
+        let albums = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("albumList2"))
+            .and_then(|v| v.get("album"))
+            .map(parse_albums)
+            .unwrap_or_default();
+        Ok(albums)
+    }
+
+    pub fn find_artist(&self, query: &str) -> Result<Option<Item>> {
+        Ok(best_match(query, &self.search_artists(query)?))
+    }
+
+    pub fn search_artists(&self, query: &str) -> Result<Vec<Item>> {
+        self.search_items(query, "artist")
     }
 
     pub fn find_album(&self, query: &str) -> Result<Option<Item>> {
-        let json = self.request("search3", &[("query", query.to_string())])?;
+        Ok(best_match(query, &self.search_albums(query)?))
+    }
+
+    pub fn search_albums(&self, query: &str) -> Result<Vec<Item>> {
+        self.search_items(query, "album")
+    }
+
+    fn search_items(&self, query: &str, kind: &str) -> Result<Vec<Item>> {
+        let json = self.request("search3", &self.with_folder(vec![("query", query.to_string())]))?;
         let items = json
             .get("subsonic-response")
             .and_then(|v| v.get("searchResult3"))
-            .and_then(|v| v.get("album"))
+            .and_then(|v| v.get(kind))
             .map(parse_items)
             .unwrap_or_default();
-        Ok(best_match(query, &items))
+        Ok(items)
+    }
+
+    /// Albums and songs matching `query`, up to `count` of each.
+    pub fn search(&self, query: &str, count: usize) -> Result<SearchResults> {
+        let params = self.with_folder(vec![
+            ("query", query.to_string()),
+            ("artistCount", count.to_string()),
+            ("albumCount", count.to_string()),
+            ("songCount", count.to_string()),
+        ]);
+        let json = self.request("search3", &params)?;
+        let result = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("searchResult3"));
+        Ok(SearchResults {
+            artists: result
+                .and_then(|v| v.get("artist"))
+                .map(parse_search_entries)
+                .unwrap_or_default(),
+            albums: result
+                .and_then(|v| v.get("album"))
+                .map(parse_search_entries)
+                .unwrap_or_default(),
+            songs: result
+                .and_then(|v| v.get("song"))
+                .map(parse_song_list)
+                .unwrap_or_default(),
+        })
     }
 
     pub fn list_playlists(&self) -> Result<Vec<Item>> {
@@ -198,13 +622,52 @@ impl NavidromeClient {
         Ok(best_match(query, &items))
     }
 
-    pub fn artist_album_ids(&self, artist_id: &str) -> Result<Vec<String>> {
+    pub fn similar_artists(&self, artist_id: &str) -> Result<Vec<Item>> {
+        let json = self.request("getArtistInfo2", &[("id", artist_id.to_string())])?;
+        let items = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("artistInfo2"))
+            .and_then(|v| v.get("similarArtist"))
+            .map(parse_items)
+            .unwrap_or_default();
+        Ok(items)
+    }
+
+    /// Songs similar to `id` (a song, album or artist), via Last.fm-style
+    /// agents on the server.
+    pub fn similar_songs(&self, id: &str, count: usize) -> Result<Vec<Song>> {
+        let json = self.request(
+            "getSimilarSongs2",
+            &[("id", id.to_string()), ("count", count.to_string())],
+        )?;
+        let songs = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("similarSongs2"))
+            .and_then(|v| v.get("song"))
+            .map(parse_song_list)
+            .unwrap_or_default();
+        Ok(songs)
+    }
+
+    pub fn songs_by_genre(&self, genre: &str, count: usize) -> Result<Vec<Song>> {
+        let params = self.with_folder(vec![("genre", genre.to_string()), ("count", count.to_string())]);
+        let json = self.request("getSongsByGenre", &params)?;
+        let songs = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("songsByGenre"))
+            .and_then(|v| v.get("song"))
+            .map(parse_song_list)
+            .unwrap_or_default();
+        Ok(songs)
+    }
+
+    pub fn artist_albums(&self, artist_id: &str) -> Result<Vec<Album>> {
         let json = self.request("getArtist", &[("id", artist_id.to_string())])?;
         let albums = json
             .get("subsonic-response")
             .and_then(|v| v.get("artist"))
             .and_then(|v| v.get("album"))
-            .map(parse_album_ids)
+            .map(parse_albums)
             .unwrap_or_default();
         Ok(albums)
     }
@@ -220,6 +683,48 @@ impl NavidromeClient {
         Ok(songs)
     }
 
+    pub fn get_song(&self, song_id: &str) -> Result<Song> {
+        let json = self.request("getSong", &[("id", song_id.to_string())])?;
+        json.get("subsonic-response")
+            .and_then(|v| v.get("song"))
+            .and_then(parse_song)
+            .ok_or_else(|| SubsonicError::NotFound {
+                code: None,
+                message: "Song not found".to_string(),
+            })
+    }
+
+    pub fn get_album(&self, album_id: &str) -> Result<Option<Album>> {
+        let json = self.request("getAlbum", &[("id", album_id.to_string())])?;
+        let album = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("album"))
+            .and_then(parse_album);
+        Ok(album)
+    }
+
+    pub fn album_info(&self, album_id: &str) -> Result<AlbumInfo> {
+        let json = self.request("getAlbumInfo2", &[("id", album_id.to_string())])?;
+        let info = match json
+            .get("subsonic-response")
+            .and_then(|v| v.get("albumInfo"))
+        {
+            Some(info) => info,
+            None => return Ok(AlbumInfo::default()),
+        };
+        let field = |key: &str| {
+            info.get(key)
+                .and_then(|v| v.as_str())
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+        Ok(AlbumInfo {
+            notes: field("notes"),
+            musicbrainz_id: field("musicBrainzId"),
+            lastfm_url: field("lastFmUrl"),
+        })
+    }
+
     pub fn playlist_songs(&self, playlist_id: &str) -> Result<Vec<Song>> {
         let json = self.request("getPlaylist", &[("id", playlist_id.to_string())])?;
         let songs = json
@@ -231,6 +736,20 @@ impl NavidromeClient {
         Ok(songs)
     }
 
+    /// Hands a playlist's songs to `each`, `batch` at a time, while the
+    /// `getPlaylist` body is still downloading, so a long playlist can start
+    /// before the rest of it arrives. Stops reading once `each` returns
+    /// `false`.
+    pub fn stream_playlist_songs(
+        &self,
+        playlist_id: &str,
+        batch: usize,
+        mut each: impl FnMut(Vec<Song>) -> bool,
+    ) -> Result<()> {
+        let resp = self.send("getPlaylist", &[("id", playlist_id.to_string())])?;
+        stream_playlist_entries(BufReader::new(resp), batch, &mut each)
+    }
+
     pub fn scrobble_now_playing(&self, song_id: &str) -> Result<()> {
         let _ = self.request(
             "scrobble",
@@ -290,6 +809,24 @@ impl NavidromeClient {
         Ok(())
     }
 
+    /// Creates a public share for `id` (a song, album or playlist) and
+    /// returns its URL.
+    pub fn create_share(&self, id: &str) -> Result<String> {
+        let json = self.request("createShare", &[("id", id.to_string())])?;
+        let share = match json
+            .get("subsonic-response")
+            .and_then(|v| v.get("shares"))
+            .and_then(|v| v.get("share"))
+        {
+            Some(Value::Array(shares)) => shares.first().cloned(),
+            Some(share @ Value::Object(_)) => Some(share.clone()),
+            _ => None,
+        };
+        share
+            .and_then(|share| share.get("url").and_then(|v| v.as_str()).map(|s| s.to_string()))
+            .ok_or_else(|| SubsonicError::Parse("createShare returned no share URL".to_string()))
+    }
+
     pub fn delete_playlist(&self, playlist_id: &str) -> Result<()> {
         let _ = self.request(
             "deletePlaylist",
@@ -299,14 +836,79 @@ impl NavidromeClient {
     }
 
     pub fn starred_songs(&self) -> Result<Vec<Song>> {
-        let json = self.request("getStarred2", &[])?;
-        let songs = json
+        let params = self.with_folder(Vec::new());
+        let (json, key) = match self.request("getStarred2", &params) {
+            Ok(json) => (json, "starred2"),
+            Err(SubsonicError::Unsupported(_)) => (self.request("getStarred", &params)?, "starred"),
+            Err(err) => return Err(err),
+        };
+        Ok(parse_starred_songs(&json, key))
+    }
+
+    pub fn create_bookmark(&self, song_id: &str, position_ms: u64, comment: &str) -> Result<()> {
+        let _ = self.request(
+            "createBookmark",
+            &[
+                ("id", song_id.to_string()),
+                ("position", position_ms.to_string()),
+                ("comment", comment.to_string()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_bookmarks(&self) -> Result<Vec<Bookmark>> {
+        let json = self.request("getBookmarks", &[])?;
+        let bookmarks = match json
             .get("subsonic-response")
-            .and_then(|v| v.get("starred2"))
-            .and_then(|v| v.get("song"))
-            .map(parse_song_list)
+            .and_then(|v| v.get("bookmarks"))
+            .and_then(|v| v.get("bookmark"))
+        {
+            Some(Value::Array(items)) => items.iter().filter_map(parse_bookmark).collect(),
+            Some(value @ Value::Object(_)) => parse_bookmark(value).into_iter().collect(),
+            _ => Vec::new(),
+        };
+        Ok(bookmarks)
+    }
+
+    /// Asks the server to rescan its library; needs an admin user.
+    pub fn start_scan(&self) -> Result<ScanStatus> {
+        let json = self.request("startScan", &[])?;
+        Ok(parse_scan_status(&json))
+    }
+
+    pub fn scan_status(&self) -> Result<ScanStatus> {
+        let json = self.request("getScanStatus", &[])?;
+        Ok(parse_scan_status(&json))
+    }
+
+    pub fn music_folders(&self) -> Result<Vec<Item>> {
+        let json = self.request("getMusicFolders", &[])?;
+        let items = json
+            .get("subsonic-response")
+            .and_then(|v| v.get("musicFolders"))
+            .and_then(|v| v.get("musicFolder"))
+            .map(parse_items)
             .unwrap_or_default();
-        Ok(songs)
+        Ok(items)
+    }
+
+    /// The music folder that library-wide browse and shuffle calls are scoped to.
+    pub fn music_folder(&self) -> Option<Item> {
+        self.music_folder.lock().ok().and_then(|f| f.clone())
+    }
+
+    pub fn set_music_folder(&self, folder: Option<Item>) {
+        if let Ok(mut active) = self.music_folder.lock() {
+            *active = folder;
+        }
+    }
+
+    fn with_folder(&self, mut params: Vec<(&'static str, String)>) -> Vec<(&'static str, String)> {
+        if let Some(folder) = self.music_folder() {
+            params.push(("musicFolderId", folder.id));
+        }
+        params
     }
 
     fn token_pair(&self) -> (String, String) {
@@ -320,6 +922,93 @@ impl NavidromeClient {
     }
 }
 
+/// Turns a reqwest error into a message that says what actually went wrong.
+pub fn describe_http_error(err: &reqwest::Error) -> String {
+    if err.is_timeout() {
+        return "timed out waiting for the server".to_string();
+    }
+    if let Some(status) = err.status() {
+        return format!("server returned HTTP {}", status);
+    }
+    if err.is_decode() {
+        return "server returned an invalid response body".to_string();
+    }
+    if err.is_connect() {
+        let mut source = std::error::Error::source(err);
+        while let Some(cause) = source {
+            if let Some(io_err) = cause.downcast_ref::<std::io::Error>() {
+                if io_err.kind() == std::io::ErrorKind::ConnectionRefused {
+                    return "connection refused (is the server running?)".to_string();
+                }
+            }
+            let text = cause.to_string().to_lowercase();
+            if text.contains("dns") || text.contains("lookup") || text.contains("resolve") {
+                return format!("could not resolve host: {}", cause);
+            }
+            if text.contains("certificate") || text.contains("tls") || text.contains("handshake") {
+                return format!("TLS error (check tls_verify): {}", cause);
+            }
+            source = cause.source();
+        }
+        return format!("could not connect: {}", err);
+    }
+    if err.is_builder() {
+        return format!("invalid request (check server_url): {}", err);
+    }
+    err.to_string()
+}
+
+/// Replaces the auth token and salt in a Subsonic URL so it can be shown.
+pub fn redact_auth(url: &str) -> String {
+    let mut parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    let pairs: Vec<(String, String)> = parsed
+        .query_pairs()
+        .map(|(k, v)| {
+            let value = if matches!(k.as_ref(), "t" | "s" | "p") {
+                "REDACTED".to_string()
+            } else {
+                v.into_owned()
+            };
+            (k.into_owned(), value)
+        })
+        .collect();
+    parsed.query_pairs_mut().clear().extend_pairs(pairs);
+    parsed.to_string()
+}
+
+/// Calls `fetch` up to `attempts` times until it returns songs, logging each
+/// empty answer. `None` when every attempt came back empty; errors end the
+/// retries straight away.
+fn retry_until_songs(
+    attempts: u32,
+    mut fetch: impl FnMut() -> Result<Vec<Song>>,
+) -> Result<Option<Vec<Song>>> {
+    for attempt in 1..=attempts {
+        let songs = fetch()?;
+        if !songs.is_empty() {
+            return Ok(Some(songs));
+        }
+        eprintln!(
+            "simplay: getRandomSongs returned no songs (attempt {}/{})",
+            attempt, attempts
+        );
+    }
+    Ok(None)
+}
+
+/// The songs of a `getStarred2` (`key` = `starred2`) or `getStarred`
+/// (`key` = `starred`) response.
+fn parse_starred_songs(json: &Value, key: &str) -> Vec<Song> {
+    json.get("subsonic-response")
+        .and_then(|v| v.get(key))
+        .and_then(|v| v.get("song"))
+        .map(parse_song_list)
+        .unwrap_or_default()
+}
+
 fn parse_song_list(value: &Value) -> Vec<Song> {
     match value {
         Value::Array(items) => items.iter().filter_map(parse_song).collect(),
@@ -328,6 +1017,158 @@ fn parse_song_list(value: &Value) -> Vec<Song> {
     }
 }
 
+/// The error a response's `status` and `error` fields describe, if any.
+fn check_status(status: Option<&str>, error: Option<&Value>) -> Result<()> {
+    if status == Some("ok") {
+        return Ok(());
+    }
+    let text = |key: &str| {
+        error
+            .and_then(|v| v.get(key))
+            .and_then(|v| v.as_str())
+            .map(|s| s.to_string())
+    };
+    let code = error
+        .and_then(|v| v.get("code"))
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u32);
+    let mut message = text("message").unwrap_or_else(|| "Unknown error".to_string());
+    if let Some(url) = text("helpUrl") {
+        message = format!("{}; see {}", message, url);
+    }
+    Err(SubsonicError::from_api(code, message))
+}
+
+/// Parses a `getPlaylist` body from `reader` one entry at a time, handing
+/// the songs to `each` in batches as they are read.
+fn stream_playlist_entries(
+    reader: impl Read,
+    batch: usize,
+    each: &mut dyn FnMut(Vec<Song>) -> bool,
+) -> Result<()> {
+    let mut sink = PlaylistSink {
+        batch: batch.max(1),
+        songs: Vec::new(),
+        each,
+        status: None,
+        error: None,
+        stopped: false,
+    };
+    let mut json = serde_json::Deserializer::from_reader(reader);
+    let walked = PlaylistLevel { sink: &mut sink, depth: Depth::Root }.deserialize(&mut json);
+    if sink.stopped {
+        return Ok(());
+    }
+    if let Err(err) = walked {
+        let message = format!("Request getPlaylist failed: {}", err);
+        return Err(SubsonicError::from_http_status(None, !err.is_io(), message));
+    }
+    check_status(sink.status.as_deref(), sink.error.as_ref())?;
+    sink.flush();
+    Ok(())
+}
+
+/// What `stream_playlist_entries` keeps while serde walks the body.
+struct PlaylistSink<'a> {
+    batch: usize,
+    songs: Vec<Song>,
+    each: &'a mut dyn FnMut(Vec<Song>) -> bool,
+    status: Option<String>,
+    error: Option<Value>,
+    /// `each` asked to stop; the walk is abandoned with an error.
+    stopped: bool,
+}
+
+impl PlaylistSink<'_> {
+    fn push(&mut self, entry: &Value) -> bool {
+        self.songs.extend(parse_song(entry));
+        self.songs.len() < self.batch || self.flush()
+    }
+
+    fn flush(&mut self) -> bool {
+        if !self.songs.is_empty() && !(self.each)(std::mem::take(&mut self.songs)) {
+            self.stopped = true;
+        }
+        !self.stopped
+    }
+}
+
+/// Where in `{"subsonic-response": {"playlist": {"entry": [...]}}}` a
+/// `PlaylistLevel` is.
+#[derive(Clone, Copy, PartialEq)]
+enum Depth {
+    Root,
+    Response,
+    Playlist,
+    Entries,
+}
+
+struct PlaylistLevel<'s, 'a> {
+    sink: &'s mut PlaylistSink<'a>,
+    depth: Depth,
+}
+
+impl<'de> DeserializeSeed<'de> for PlaylistLevel<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> std::result::Result<(), D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PlaylistLevel<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a getPlaylist response")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> std::result::Result<(), A::Error> {
+        if self.depth == Depth::Entries {
+            // A playlist of one may send its entry as a bare object.
+            let entry = Value::deserialize(de::value::MapAccessDeserializer::new(map))?;
+            if !self.sink.push(&entry) {
+                return Err(de::Error::custom("stopped"));
+            }
+            return Ok(());
+        }
+        while let Some(key) = map.next_key::<String>()? {
+            match (self.depth, key.as_str()) {
+                (Depth::Root, "subsonic-response") => {
+                    map.next_value_seed(PlaylistLevel { sink: &mut *self.sink, depth: Depth::Response })?
+                }
+                (Depth::Response, "playlist") => {
+                    map.next_value_seed(PlaylistLevel { sink: &mut *self.sink, depth: Depth::Playlist })?
+                }
+                (Depth::Playlist, "entry") => {
+                    map.next_value_seed(PlaylistLevel { sink: &mut *self.sink, depth: Depth::Entries })?
+                }
+                (Depth::Response, "status") => self.sink.status = map.next_value()?,
+                (Depth::Response, "error") => self.sink.error = map.next_value()?,
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> std::result::Result<(), A::Error> {
+        if self.depth != Depth::Entries {
+            return Err(de::Error::invalid_type(de::Unexpected::Seq, &self));
+        }
+        while let Some(entry) = seq.next_element::<Value>()? {
+            if !self.sink.push(&entry) {
+                return Err(de::Error::custom("stopped"));
+            }
+        }
+        Ok(())
+    }
+}
+
 fn parse_song(value: &Value) -> Option<Song> {
     let id = value.get("id")?.as_str()?.to_string();
     let title = value
@@ -345,24 +1186,105 @@ fn parse_song(value: &Value) -> Option<Song> {
         .and_then(|v| v.as_str())
         .unwrap_or("Unknown Album")
         .to_string();
+    let album_id = value
+        .get("albumId")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let genre = value
+        .get("genre")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let artist_id = value
+        .get("artistId")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    let year = value.get("year").and_then(|v| v.as_u64()).map(|v| v as u32);
     let duration = value.get("duration").and_then(|v| v.as_u64()).map(|v| v as u32);
     let track = value.get("track").and_then(|v| v.as_u64()).map(|v| v as u32);
     let disc = value
         .get("discNumber")
         .and_then(|v| v.as_u64())
         .map(|v| v as u32);
+    let starred = value.get("starred").is_some();
+    let user_rating = value
+        .get("userRating")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as u8);
+    let play_count = value.get("playCount").and_then(|v| v.as_u64());
+    let bit_rate = value.get("bitRate").and_then(|v| v.as_u64()).map(|v| v as u32);
+    let text = |key: &str| value.get(key).and_then(|v| v.as_str()).map(|s| s.to_string());
+    let suffix = text("suffix");
+    let content_type = text("contentType");
+    let path = text("path");
+    let cover_art = text("coverArt");
+    let replay_gain = value.get("replayGain");
+    let track_gain = replay_gain
+        .and_then(|v| v.get("trackGain"))
+        .and_then(|v| v.as_f64());
+    let album_gain = replay_gain
+        .and_then(|v| v.get("albumGain"))
+        .and_then(|v| v.as_f64());
+    let explicit = match value.get("explicitStatus").and_then(|v| v.as_str()) {
+        Some("explicit") => Some(true),
+        Some("clean") => Some(false),
+        _ => None,
+    };
 
     Some(Song {
         id,
         title,
         artist,
         album,
+        album_id,
+        artist_id,
+        genre,
+        year,
         duration,
         track,
         disc,
+        starred,
+        user_rating,
+        play_count,
+        bit_rate,
+        suffix,
+        content_type,
+        path,
+        cover_art,
+        track_gain,
+        album_gain,
+        explicit,
+        queue_group: None,
     })
 }
 
+fn parse_bookmark(value: &Value) -> Option<Bookmark> {
+    let song = parse_song(value.get("entry")?)?;
+    let position_ms = value.get("position").and_then(|v| v.as_u64()).unwrap_or(0);
+    let comment = value
+        .get("comment")
+        .and_then(|v| v.as_str())
+        .unwrap_or("")
+        .to_string();
+    Some(Bookmark {
+        song,
+        position_ms,
+        comment,
+    })
+}
+
+fn parse_scan_status(json: &Value) -> ScanStatus {
+    let status = json
+        .get("subsonic-response")
+        .and_then(|v| v.get("scanStatus"));
+    ScanStatus {
+        scanning: status
+            .and_then(|v| v.get("scanning"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+        count: status.and_then(|v| v.get("count")).and_then(|v| v.as_u64()),
+    }
+}
+
 fn parse_album_ids(value: &Value) -> Vec<String> {
     match value {
         Value::Array(items) => items
@@ -378,6 +1300,63 @@ fn parse_album_ids(value: &Value) -> Vec<String> {
     }
 }
 
+fn parse_albums(value: &Value) -> Vec<Album> {
+    match value {
+        Value::Array(items) => items.iter().filter_map(parse_album).collect(),
+        Value::Object(_) => parse_album(value).into_iter().collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_album(value: &Value) -> Option<Album> {
+    let id = value.get("id")?.as_str()?.to_string();
+    let name = value
+        .get("name")
+        .or_else(|| value.get("title"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("Unknown Album")
+        .to_string();
+    let artist = value
+        .get("artist")
+        .and_then(|v| v.as_str())
+        .unwrap_or("Unknown Artist")
+        .to_string();
+    let year = value.get("year").and_then(|v| v.as_u64()).map(|v| v as u32);
+    let created = value
+        .get("created")
+        .and_then(|v| v.as_str())
+        .map(|s| s.to_string());
+    Some(Album {
+        id,
+        name,
+        artist,
+        year,
+        created,
+    })
+}
+
+fn parse_search_entries(value: &Value) -> Vec<SearchEntry> {
+    let entries = match value {
+        Value::Array(items) => items.iter().collect(),
+        Value::Object(_) => vec![value],
+        _ => Vec::new(),
+    };
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let item = parse_item(entry)?;
+            let count = |key: &str| entry.get(key).and_then(|v| v.as_u64()).map(|v| v as u32);
+            Some(SearchEntry {
+                id: item.id,
+                name: item.name,
+                artist: entry.get("artist").and_then(|v| v.as_str()).map(|s| s.to_string()),
+                album_count: count("albumCount"),
+                song_count: count("songCount"),
+            })
+        })
+        .collect()
+}
+
 fn parse_items(value: &Value) -> Vec<Item> {
     match value {
         Value::Array(items) => items.iter().filter_map(parse_item).collect(),
@@ -387,7 +1366,12 @@ fn parse_items(value: &Value) -> Vec<Item> {
 }
 
 fn parse_item(value: &Value) -> Option<Item> {
-    let id = value.get("id")?.as_str()?.to_string();
+    let id = value.get("id")?;
+    // Music folder ids are numbers on some servers.
+    let id = id
+        .as_str()
+        .map(|s| s.to_string())
+        .or_else(|| id.as_u64().map(|n| n.to_string()))?;
     let name = value
         .get("name")
         .or_else(|| value.get("title"))
@@ -397,6 +1381,17 @@ fn parse_item(value: &Value) -> Option<Item> {
     Some(Item { id, name })
 }
 
+fn build_http(tls_verify: bool) -> Result<Client> {
+    let mut builder = Client::builder();
+    if !tls_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder
+        .timeout(Duration::from_secs(20))
+        .build()
+        .map_err(|err| SubsonicError::Network(format!("Failed to set up HTTP client: {}", err)))
+}
+
 fn normalize_name(input: &str) -> String {
     input
         .chars()
@@ -417,20 +1412,268 @@ fn match_score(query: &str, candidate: &str) -> i32 {
     }
 }
 
-fn best_match(query: &str, items: &[Item]) -> Option<Item> {
+pub fn best_match(query: &str, items: &[Item]) -> Option<Item> {
+    ranked_matches(query, items)
+        .into_iter()
+        .next()
+        .map(|(item, _)| item)
+}
+
+/// Items matching `query`, best first, with their score (3 exact, 2
+/// substring, 1 prefix). Non-matches are dropped; ties keep server order.
+pub fn ranked_matches(query: &str, items: &[Item]) -> Vec<(Item, i32)> {
     let normalized_query = normalize_name(query);
     if normalized_query.is_empty() {
-        return None;
-    }
-    let mut best: Option<&Item> = None;
-    let mut best_score = 0;
-    for item in items {
-        let normalized = normalize_name(&item.name);
-        let score = match_score(&normalized_query, &normalized);
-        if score > best_score {
-            best_score = score;
-            best = Some(item);
-        }
+        return Vec::new();
+    }
+    let mut ranked: Vec<(Item, i32)> = items
+        .iter()
+        .map(|item| {
+            let score = match_score(&normalized_query, &normalize_name(&item.name));
+            (item.clone(), score)
+        })
+        .filter(|(_, score)| *score > 0)
+        .collect();
+    ranked.sort_by_key(|(_, score)| -score);
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api(code: u32, message: &str) -> SubsonicError {
+        SubsonicError::from_api(Some(code), message.to_string())
+    }
+
+    #[test]
+    fn api_codes_map_to_error_kinds() {
+        assert!(matches!(
+            api(40, "Wrong username or password"),
+            SubsonicError::Auth { code: Some(40), .. }
+        ));
+        assert!(matches!(
+            api(41, "Token auth not supported"),
+            SubsonicError::Auth { code: Some(41), .. }
+        ));
+        assert!(matches!(
+            api(70, "Song not found"),
+            SubsonicError::NotFound { code: Some(70), .. }
+        ));
+        assert!(matches!(api(0, "Method not implemented"), SubsonicError::Unsupported(_)));
+        assert!(matches!(api(0, "Unknown method getStarred2"), SubsonicError::Unsupported(_)));
+        assert!(matches!(
+            api(10, "Required parameter is missing"),
+            SubsonicError::Server { code: Some(10), .. }
+        ));
+        assert!(matches!(api(50, "Not authorized"), SubsonicError::Server { code: Some(50), .. }));
+        assert!(matches!(
+            SubsonicError::from_api(None, "Something broke".to_string()),
+            SubsonicError::Server { code: None, .. }
+        ));
+    }
+
+    #[test]
+    fn api_codes_carry_their_hints() {
+        assert_eq!(api(40, "").hint(), Some("authentication failed, re-run --configure"));
+        assert_eq!(api(41, "").hint(), Some("the server does not accept token auth"));
+        assert_eq!(api(70, "").hint(), Some("not found on the server"));
+        assert_eq!(api(10, "").hint(), Some("a required parameter is missing"));
+        assert_eq!(
+            api(20, "").hint(),
+            Some("client and server API versions are incompatible; check api_version")
+        );
+        assert_eq!(api(30, "").hint(), api(20, "").hint());
+        assert_eq!(api(50, "").hint(), Some("this user is not allowed to do that"));
+        assert_eq!(api(0, "").hint(), None);
+        assert_eq!(api(70, "Song not found").code(), Some(70));
+        assert_eq!(api(70, "Song not found").to_string(), "Song not found (Subsonic error 70)");
+    }
+
+    #[test]
+    fn http_statuses_map_to_error_kinds() {
+        let http = |status: Option<u16>, is_decode: bool| {
+            SubsonicError::from_http_status(status, is_decode, "failed".to_string())
+        };
+        assert!(matches!(http(Some(401), false), SubsonicError::Auth { code: None, .. }));
+        assert!(matches!(http(Some(403), false), SubsonicError::Auth { code: None, .. }));
+        assert!(matches!(http(Some(404), false), SubsonicError::Unsupported(_)));
+        assert!(matches!(http(Some(501), false), SubsonicError::Unsupported(_)));
+        assert!(matches!(http(Some(500), false), SubsonicError::Server { code: None, .. }));
+        assert!(matches!(http(Some(502), true), SubsonicError::Server { .. }));
+        assert!(matches!(http(None, true), SubsonicError::Parse(_)));
+        assert!(matches!(http(None, false), SubsonicError::Network(_)));
+    }
+
+    #[test]
+    fn random_songs_retry_until_the_server_returns_some() {
+        let mut answers = vec![vec![], vec![Song::external("a")]].into_iter();
+        let mut calls = 0;
+        let songs = retry_until_songs(3, || {
+            calls += 1;
+            Ok(answers.next().unwrap_or_default())
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(songs.unwrap()[0].id, "a");
+    }
+
+    #[test]
+    fn starred_songs_parse_from_starred2() {
+        let json = serde_json::json!({
+            "subsonic-response": {
+                "status": "ok",
+                "starred2": {
+                    "artist": [{"id": "ar1", "name": "Artist"}],
+                    "song": [
+                        {"id": "s1", "title": "One", "artist": "Artist", "starred": "2024-01-01T00:00:00Z"},
+                        {"id": "s2", "title": "Two", "artist": "Artist"}
+                    ]
+                }
+            }
+        });
+        let songs = parse_starred_songs(&json, "starred2");
+        let ids: Vec<&str> = songs.iter().map(|song| song.id.as_str()).collect();
+        assert_eq!(ids, ["s1", "s2"]);
+        assert_eq!(songs[0].title, "One");
+    }
+
+    #[test]
+    fn starred_songs_parse_from_starred_with_a_single_song() {
+        // Older servers answer getStarred, and may send one song as an object.
+        let json = serde_json::json!({
+            "subsonic-response": {
+                "status": "ok",
+                "starred": {
+                    "song": {"id": "s1", "title": "One", "artist": "Artist", "isDir": false}
+                }
+            }
+        });
+        let songs = parse_starred_songs(&json, "starred");
+        assert_eq!(songs.len(), 1);
+        assert_eq!(songs[0].id, "s1");
+        assert!(parse_starred_songs(&json, "starred2").is_empty());
+    }
+
+    fn query(url: &str) -> Vec<(String, String)> {
+        reqwest::Url::parse(url)
+            .unwrap()
+            .query_pairs()
+            .map(|(k, v)| (k.into_owned(), v.into_owned()))
+            .collect()
+    }
+
+    fn values<'a>(params: &'a [(String, String)], key: &str) -> Vec<&'a str> {
+        params.iter().filter(|(k, _)| k == key).map(|(_, v)| v.as_str()).collect()
+    }
+
+    #[test]
+    fn extra_params_are_appended_and_override_the_client_name() {
+        let config = Config::from_toml(
+            r#"
+            server_url = "http://music.example"
+            username = "me"
+            [extra_params]
+            c = "myclient"
+            flavour = "fork"
+            "#,
+        );
+        let client = NavidromeClient::new(&config).unwrap();
+        let params = query(&client.stream_url("song1").unwrap());
+        let get = |key: &str| values(&params, key);
+        assert_eq!(get("c"), ["myclient"]);
+        assert_eq!(get("flavour"), ["fork"]);
+        assert_eq!(get("u"), ["me"]);
+        assert_eq!(get("id"), ["song1"]);
+    }
+
+    #[test]
+    fn extra_params_cannot_replace_auth_or_endpoint_params() {
+        let config = Config::from_toml(
+            r#"
+            server_url = "http://music.example"
+            username = "me"
+            [extra_params]
+            u = "someone"
+            t = "token"
+            v = "9.9"
+            id = "other"
+            "#,
+        );
+        let client = NavidromeClient::new(&config).unwrap();
+        let params = query(&client.stream_url("song1").unwrap());
+        let get = |key: &str| values(&params, key);
+        assert_eq!(get("u"), ["me"]);
+        assert_ne!(get("t"), ["token"]);
+        assert_eq!(get("v"), [config.api_version.as_str()]);
+        assert_eq!(get("id"), ["song1"]);
+    }
+
+    #[test]
+    fn random_songs_give_up_after_the_last_empty_attempt() {
+        let mut calls = 0;
+        let songs = retry_until_songs(3, || {
+            calls += 1;
+            Ok(Vec::new())
+        })
+        .unwrap();
+        assert_eq!(calls, 3);
+        assert!(songs.is_none());
+    }
+
+    fn stream(body: &str, batch: usize) -> (Vec<Vec<String>>, Result<()>) {
+        let mut batches = Vec::new();
+        let result = stream_playlist_entries(body.as_bytes(), batch, &mut |songs: Vec<Song>| {
+            batches.push(songs.into_iter().map(|song| song.id).collect());
+            true
+        });
+        (batches, result)
+    }
+
+    #[test]
+    fn playlist_entries_arrive_in_batches() {
+        let body = r#"{"subsonic-response": {"status": "ok", "version": "1.16.1",
+            "playlist": {"id": "p1", "name": "Long", "entry": [
+                {"id": "1", "title": "One"}, {"id": "2", "title": "Two"},
+                {"title": "No id"}, {"id": "3", "title": "Three", "genres": [{"name": "Rock"}]},
+                {"id": "4", "title": "Four"}, {"id": "5", "title": "Five"}
+            ], "songCount": 6}}}"#;
+        let (batches, result) = stream(body, 2);
+        assert!(result.is_ok());
+        assert_eq!(batches, [vec!["1", "2"], vec!["3", "4"], vec!["5"]]);
+    }
+
+    #[test]
+    fn a_single_playlist_entry_may_be_an_object() {
+        let body = r#"{"subsonic-response": {"status": "ok",
+            "playlist": {"id": "p1", "entry": {"id": "1", "title": "Only"}}}}"#;
+        let (batches, result) = stream(body, 50);
+        assert!(result.is_ok());
+        assert_eq!(batches, [vec!["1"]]);
+    }
+
+    #[test]
+    fn a_failed_playlist_response_is_an_api_error() {
+        let body = r#"{"subsonic-response": {"status": "failed",
+            "error": {"code": 70, "message": "Playlist not found"}}}"#;
+        let (batches, result) = stream(body, 50);
+        assert!(batches.is_empty());
+        assert!(matches!(result, Err(SubsonicError::NotFound { code: Some(70), .. })));
+        let (_, result) = stream(r#"{"subsonic-response": {"status": "ok", "playlist": "#, 50);
+        assert!(matches!(result, Err(SubsonicError::Parse(_))));
+    }
+
+    #[test]
+    fn playlist_streaming_stops_when_asked() {
+        let body = r#"{"subsonic-response": {"status": "ok", "playlist": {"entry": [
+            {"id": "1"}, {"id": "2"}, {"id": "3"}, {"id": "4"}
+        ]}}}"#;
+        let mut calls = 0;
+        let result = stream_playlist_entries(body.as_bytes(), 1, &mut |_| {
+            calls += 1;
+            false
+        });
+        assert!(result.is_ok());
+        assert_eq!(calls, 1);
     }
-    best.map(|item| item.clone())
 }
@@ -0,0 +1,31 @@
+#[cfg(feature = "systemd")]
+use sd_notify::NotifyState;
+#[cfg(feature = "systemd")]
+use std::thread;
+use std::time::Duration;
+
+/// Tells systemd the daemon is ready and, when a watchdog is configured,
+/// pings it every half interval for as long as `alive` answers within a
+/// quarter of the interval.
+#[cfg(feature = "systemd")]
+pub fn notify_ready(alive: impl Fn(Duration) -> bool + Send + 'static) {
+    if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready]) {
+        eprintln!("simplay: sd_notify failed: {}", err);
+    }
+
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) && usec > 0 {
+        let interval = Duration::from_micros(usec / 2);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if alive(interval / 2) {
+                let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+            } else {
+                eprintln!("simplay: daemon is not responding, skipping watchdog ping");
+            }
+        });
+    }
+}
+
+#[cfg(not(feature = "systemd"))]
+pub fn notify_ready(_alive: impl Fn(Duration) -> bool + Send + 'static) {}